@@ -0,0 +1,251 @@
+use crate::savestate::Savestate;
+
+// Timer periods in CPU cycles
+const RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+// The delta modulation channel plays 1-bit delta encoded samples straight out of CPU memory.
+// It can't read memory itself, so the bus asks it for `sample_request` every cycle and hands
+// the byte back through `fill_sample_buffer`, stalling the CPU while it does.
+pub struct Dmc {
+    irq_enabled: bool,
+    irq_flag: bool,
+    looping: bool,
+    timer_period: u16,
+    timer: u16,
+
+    // $4012 / $4013 as written, the current sample restarts from these
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+
+    // Output unit
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    output_level: u8,
+}
+
+impl Dmc {
+    pub fn new() -> Self {
+        Dmc {
+            irq_enabled: false,
+            irq_flag: false,
+            looping: false,
+            timer_period: RATE_TABLE[0],
+            timer: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            output_level: 0,
+        }
+    }
+
+    // $4010: IL-- RRRR
+    pub fn write_control(&mut self, data: u8) {
+        self.irq_enabled = data & 0b1000_0000 != 0;
+        if !self.irq_enabled {
+            self.irq_flag = false;
+        }
+        self.looping = data & 0b0100_0000 != 0;
+        self.timer_period = RATE_TABLE[(data & 0b1111) as usize];
+    }
+
+    // $4011: -DDD DDDD
+    pub fn write_output_level(&mut self, data: u8) {
+        self.output_level = data & 0x7F;
+    }
+
+    // $4012: AAAA AAAA, the sample starts at $C000 + A * 64
+    pub fn write_sample_address(&mut self, data: u8) {
+        self.sample_address = 0xC000 | (data as u16) << 6;
+    }
+
+    // $4013: LLLL LLLL, the sample is L * 16 + 1 bytes long
+    pub fn write_sample_length(&mut self, data: u8) {
+        self.sample_length = (data as u16) << 4 | 1;
+    }
+
+    // From $4015. Enabling only restarts the sample if the last one has finished
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    pub fn irq_flag(&self) -> bool {
+        self.irq_flag
+    }
+
+    // The address the memory reader wants fetched, if its buffer has run dry
+    pub fn sample_request(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    pub fn fill_sample_buffer(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+
+        // The address wraps around to $8000 rather than $0000
+        self.current_address = match self.current_address {
+            0xFFFF => 0x8000,
+            addr => addr + 1,
+        };
+
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle, the rate table is in CPU cycles
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+
+        self.timer = self.timer_period - 1;
+
+        // Each bit moves the level up or down by two, unless that would leave 0 - 127
+        if !self.silence {
+            if self.shift_register & 1 == 1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift_register = sample;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // 0 - 127
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+impl Default for Dmc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Dmc {
+    crate::savestate_fields!(
+        irq_enabled,
+        irq_flag,
+        looping,
+        timer_period,
+        timer,
+        sample_address,
+        sample_length,
+        current_address,
+        bytes_remaining,
+        sample_buffer,
+        shift_register,
+        bits_remaining,
+        silence,
+        output_level,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Plays one output cycle's worth of bits at the fastest rate
+    fn play_byte(dmc: &mut Dmc) {
+        for _ in 0..(RATE_TABLE[15] as usize * 8) {
+            dmc.clock_timer();
+        }
+    }
+
+    #[test]
+    fn test_sample_playback() {
+        let mut dmc = Dmc::new();
+        dmc.write_control(0x0F);
+        dmc.write_output_level(64);
+        dmc.write_sample_address(0x01);
+        dmc.write_sample_length(0x00);
+        dmc.set_enabled(true);
+
+        assert_eq!(dmc.sample_request(), Some(0xC040));
+        dmc.fill_sample_buffer(0xFF);
+        assert!(!dmc.active());
+        assert_eq!(dmc.sample_request(), None);
+
+        // The first output cycle is silent, the next plays the fetched byte
+        play_byte(&mut dmc);
+        assert_eq!(dmc.output(), 64);
+        play_byte(&mut dmc);
+        assert_eq!(dmc.output(), 64 + 16);
+    }
+
+    #[test]
+    fn test_loop_and_irq() {
+        let mut dmc = Dmc::new();
+        dmc.write_control(0x80);
+        dmc.write_sample_length(0x01);
+        dmc.set_enabled(true);
+
+        for _ in 0..17 {
+            assert!(!dmc.irq_flag());
+            dmc.fill_sample_buffer(0);
+            dmc.sample_buffer = None;
+        }
+        assert!(dmc.irq_flag());
+        assert!(!dmc.active());
+
+        // Looping samples restart instead of raising the IRQ
+        dmc.write_control(0x40);
+        assert!(!dmc.irq_flag());
+        dmc.set_enabled(true);
+        for _ in 0..17 {
+            dmc.fill_sample_buffer(0);
+            dmc.sample_buffer = None;
+        }
+        assert!(!dmc.irq_flag());
+        assert_eq!(dmc.sample_request(), Some(0xC000));
+    }
+}
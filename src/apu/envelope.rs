@@ -0,0 +1,78 @@
+use crate::savestate::Savestate;
+
+// Volume envelope shared by the pulse and noise channels. Either a constant volume or a sawtooth
+// that decays from 15 to 0, optionally looping.
+pub struct Envelope {
+    start: bool,
+    looping: bool,
+    constant_volume: bool,
+    // Constant volume, or the envelope's divider period
+    volume: u8,
+    divider: u8,
+    decay_level: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            start: false,
+            looping: false,
+            constant_volume: false,
+            volume: 0,
+            divider: 0,
+            decay_level: 0,
+        }
+    }
+
+    // --LC VVVV, the loop flag doubles as the length counter halt
+    pub fn write_control(&mut self, data: u8) {
+        self.looping = data & 0b0010_0000 != 0;
+        self.constant_volume = data & 0b0001_0000 != 0;
+        self.volume = data & 0b0000_1111;
+    }
+
+    // Writing the channel's length register restarts the envelope on the next quarter frame
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    // Clocked every quarter frame by the frame counter
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay_level = 15;
+            self.divider = self.volume;
+            return;
+        }
+
+        if self.divider > 0 {
+            self.divider -= 1;
+            return;
+        }
+
+        self.divider = self.volume;
+        if self.decay_level > 0 {
+            self.decay_level -= 1;
+        } else if self.looping {
+            self.decay_level = 15;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay_level
+        }
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Envelope {
+    crate::savestate_fields!(start, looping, constant_volume, volume, divider, decay_level);
+}
@@ -0,0 +1,50 @@
+use core::f32::consts::PI;
+
+// First order RC filters, the same shape as the ones between the 2A03 and the console's audio
+// out. Both run at the output sample rate.
+fn rc(cutoff: f32) -> f32 {
+    1.0 / (2.0 * PI * cutoff)
+}
+
+pub struct HighPass {
+    alpha: f32,
+    last_input: f32,
+    last_output: f32,
+}
+
+impl HighPass {
+    pub fn new(cutoff: f32, sample_rate: u32) -> Self {
+        let dt = 1.0 / sample_rate as f32;
+        HighPass {
+            alpha: rc(cutoff) / (rc(cutoff) + dt),
+            last_input: 0.0,
+            last_output: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        self.last_output = self.alpha * (self.last_output + input - self.last_input);
+        self.last_input = input;
+        self.last_output
+    }
+}
+
+pub struct LowPass {
+    alpha: f32,
+    last_output: f32,
+}
+
+impl LowPass {
+    pub fn new(cutoff: f32, sample_rate: u32) -> Self {
+        let dt = 1.0 / sample_rate as f32;
+        LowPass {
+            alpha: dt / (rc(cutoff) + dt),
+            last_output: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        self.last_output += self.alpha * (input - self.last_output);
+        self.last_output
+    }
+}
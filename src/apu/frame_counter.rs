@@ -0,0 +1,194 @@
+use crate::savestate::Savestate;
+
+// What the frame counter wants clocked on this CPU cycle. A half frame clock also clocks
+// everything a quarter frame does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameClock {
+    None,
+    Quarter,
+    Half,
+}
+
+// The frame sequencer driven by $4017. In 4-step mode it also raises the frame IRQ at the end of
+// every sequence. Step timings are in CPU cycles since the sequence started (NTSC).
+pub struct FrameCounter {
+    five_step: bool,
+    irq_inhibit: bool,
+    irq_flag: bool,
+    cycle: u32,
+    // Writes take effect 3 or 4 CPU cycles later: (cycles left, value written)
+    pending_write: Option<(u8, u8)>,
+    last_write: u8,
+}
+
+impl FrameCounter {
+    pub fn new() -> Self {
+        FrameCounter {
+            five_step: false,
+            irq_inhibit: false,
+            irq_flag: false,
+            cycle: 0,
+            pending_write: None,
+            last_write: 0,
+        }
+    }
+
+    // $4017: MI-- ----. `odd_cycle` is whether the write lands between APU cycles, which
+    // delays the sequencer reset by an extra CPU cycle
+    pub fn write(&mut self, data: u8, odd_cycle: bool) {
+        self.last_write = data;
+
+        // Inhibiting the IRQ also clears the flag, straight away
+        self.irq_inhibit = data & 0b0100_0000 != 0;
+        if self.irq_inhibit {
+            self.irq_flag = false;
+        }
+
+        self.pending_write = Some((if odd_cycle { 4 } else { 3 }, data));
+    }
+
+    // The CPU's reset line rewrites the last value written to $4017
+    pub fn reset(&mut self) {
+        self.irq_flag = false;
+        self.write(self.last_write, false);
+    }
+
+    pub fn irq_flag(&self) -> bool {
+        self.irq_flag
+    }
+
+    // Reading $4015 acknowledges the frame IRQ
+    pub fn acknowledge_irq(&mut self) {
+        self.irq_flag = false;
+    }
+
+    fn raise_irq(&mut self) {
+        if !self.irq_inhibit {
+            self.irq_flag = true;
+        }
+    }
+
+    pub fn tick(&mut self) -> FrameClock {
+        if let Some((delay, data)) = self.pending_write {
+            if delay > 1 {
+                self.pending_write = Some((delay - 1, data));
+            } else {
+                self.pending_write = None;
+                self.five_step = data & 0b1000_0000 != 0;
+                self.cycle = 0;
+
+                // Switching to 5-step mode clocks everything immediately
+                if self.five_step {
+                    return FrameClock::Half;
+                }
+                return FrameClock::None;
+            }
+        }
+
+        self.cycle += 1;
+        match (self.five_step, self.cycle) {
+            (_, 7457) => FrameClock::Quarter,
+            (_, 14913) => FrameClock::Half,
+            (_, 22371) => FrameClock::Quarter,
+            (false, 29828) => {
+                self.raise_irq();
+                FrameClock::None
+            }
+            (false, 29829) => {
+                self.raise_irq();
+                FrameClock::Half
+            }
+            (false, 29830) => {
+                self.raise_irq();
+                self.cycle = 0;
+                FrameClock::None
+            }
+            (true, 37281) => FrameClock::Half,
+            (true, 37282) => {
+                self.cycle = 0;
+                FrameClock::None
+            }
+            _ => FrameClock::None,
+        }
+    }
+}
+
+impl Default for FrameCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for FrameCounter {
+    crate::savestate_fields!(five_step, irq_inhibit, irq_flag, cycle, pending_write, last_write);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Runs the counter and returns the cycles (counted from the first tick) each clock landed on
+    fn clocks(counter: &mut FrameCounter, cycles: u32) -> Vec<(u32, FrameClock)> {
+        (1..=cycles)
+            .filter_map(|cycle| match counter.tick() {
+                FrameClock::None => None,
+                clock => Some((cycle, clock)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_four_step_sequence() {
+        let mut counter = FrameCounter::new();
+        counter.write(0x00, false);
+
+        // 3 cycles of write delay, then the sequence starts counting
+        let clocks = clocks(&mut counter, 3 + 29830);
+        assert_eq!(
+            clocks,
+            vec![
+                (3 + 7457, FrameClock::Quarter),
+                (3 + 14913, FrameClock::Half),
+                (3 + 22371, FrameClock::Quarter),
+                (3 + 29829, FrameClock::Half),
+            ]
+        );
+        assert!(counter.irq_flag());
+
+        counter.acknowledge_irq();
+        assert!(!counter.irq_flag());
+    }
+
+    #[test]
+    fn test_five_step_sequence() {
+        let mut counter = FrameCounter::new();
+        counter.write(0x80, true);
+
+        let clocks = clocks(&mut counter, 4 + 37282);
+        assert_eq!(
+            clocks,
+            vec![
+                // Writing 5-step mode clocks straight away
+                (4, FrameClock::Half),
+                (4 + 7457, FrameClock::Quarter),
+                (4 + 14913, FrameClock::Half),
+                (4 + 22371, FrameClock::Quarter),
+                (4 + 37281, FrameClock::Half),
+            ]
+        );
+        assert!(!counter.irq_flag());
+    }
+
+    #[test]
+    fn test_irq_inhibit() {
+        let mut counter = FrameCounter::new();
+        counter.write(0x00, false);
+        clocks(&mut counter, 3 + 29830);
+        assert!(counter.irq_flag());
+
+        counter.write(0x40, false);
+        assert!(!counter.irq_flag());
+        clocks(&mut counter, 3 + 29830);
+        assert!(!counter.irq_flag());
+    }
+}
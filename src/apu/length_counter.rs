@@ -0,0 +1,64 @@
+use crate::savestate::Savestate;
+
+// Number of half frames a note lasts, indexed by the top five bits of the length register
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Silences a channel once its note has run out. Every channel but the DMC has one.
+pub struct LengthCounter {
+    enabled: bool,
+    halted: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    pub fn new() -> Self {
+        LengthCounter {
+            enabled: false,
+            halted: false,
+            counter: 0,
+        }
+    }
+
+    // From $4015. Disabling the channel silences it straight away
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn set_halted(&mut self, halted: bool) {
+        self.halted = halted;
+    }
+
+    // Takes the whole length register, the index lives in the top five bits
+    pub fn load(&mut self, data: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(data >> 3) as usize];
+        }
+    }
+
+    // Clocked every half frame by the frame counter
+    pub fn clock(&mut self) {
+        if !self.halted && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+impl Default for LengthCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for LengthCounter {
+    crate::savestate_fields!(enabled, halted, counter);
+}
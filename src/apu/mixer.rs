@@ -0,0 +1,85 @@
+use super::ChannelOutputs;
+
+// The 2A03 mixes its channels through two resistor networks, so the output isn't linear in the
+// channel levels. These approximations are from the NESdev wiki and give 0.0 - ~1.0.
+pub fn pulse_level(pulse: u8) -> f32 {
+    if pulse == 0 {
+        return 0.0;
+    }
+    95.52 / (8128.0 / pulse as f32 + 100.0)
+}
+
+pub fn tnd_level(tnd: u16) -> f32 {
+    if tnd == 0 {
+        return 0.0;
+    }
+    163.67 / (24329.0 / tnd as f32 + 100.0)
+}
+
+// Lookup tables for both halves of the mix, indexed by the summed channel levels
+pub struct Mixer {
+    pulse_table: [f32; 31],
+    tnd_table: [f32; 203],
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        let mut pulse_table = [0.0; 31];
+        for (i, level) in pulse_table.iter_mut().enumerate() {
+            *level = pulse_level(i as u8);
+        }
+
+        let mut tnd_table = [0.0; 203];
+        for (i, level) in tnd_table.iter_mut().enumerate() {
+            *level = tnd_level(i as u16);
+        }
+
+        Mixer {
+            pulse_table,
+            tnd_table,
+        }
+    }
+
+    pub fn mix(&self, outputs: &ChannelOutputs) -> f32 {
+        let pulse = (outputs.pulse1 + outputs.pulse2) as usize;
+        let tnd = 3 * outputs.triangle as usize + 2 * outputs.noise as usize + outputs.dmc as usize;
+        self.pulse_table[pulse] + self.tnd_table[tnd] + outputs.expansion
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mix_is_non_linear() {
+        let mixer = Mixer::new();
+        let one = mixer.mix(&ChannelOutputs {
+            pulse1: 15,
+            ..Default::default()
+        });
+        let both = mixer.mix(&ChannelOutputs {
+            pulse1: 15,
+            pulse2: 15,
+            ..Default::default()
+        });
+        assert!((both - 0.2585).abs() < 0.001);
+        assert!(both < 2.0 * one);
+
+        let loudest = mixer.mix(&ChannelOutputs {
+            pulse1: 15,
+            pulse2: 15,
+            triangle: 15,
+            noise: 15,
+            dmc: 127,
+            ..Default::default()
+        });
+        assert!(loudest < 1.0);
+    }
+}
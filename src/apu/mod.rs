@@ -0,0 +1,384 @@
+pub mod dmc;
+pub mod envelope;
+pub mod filter;
+pub mod frame_counter;
+pub mod length_counter;
+pub mod mixer;
+pub mod noise;
+pub mod pipeline;
+pub mod pulse;
+pub mod resampler;
+pub mod triangle;
+
+use dmc::Dmc;
+use frame_counter::{FrameClock, FrameCounter};
+use noise::Noise;
+use pipeline::AudioPipeline;
+use pulse::{Pulse, Sweep};
+use triangle::Triangle;
+use crate::savestate::Savestate;
+use alloc::vec::Vec;
+
+// Each channel's current output level, 0 - 15 apart from the DMC's 0 - 127. Expansion audio
+// from the cartridge is already in the mixer's units, see `Mapper::audio_output`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChannelOutputs {
+    pub pulse1: u8,
+    pub pulse2: u8,
+    pub triangle: u8,
+    pub noise: u8,
+    pub dmc: u8,
+    pub expansion: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    Expansion,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 6] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::Dmc,
+        Channel::Expansion,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+            Channel::Expansion => "expansion",
+        }
+    }
+}
+
+impl ChannelOutputs {
+    // Just the one channel, as it would sound with the others silent
+    pub fn isolate(&self, channel: Channel) -> ChannelOutputs {
+        let mut outputs = ChannelOutputs::default();
+        match channel {
+            Channel::Pulse1 => outputs.pulse1 = self.pulse1,
+            Channel::Pulse2 => outputs.pulse2 = self.pulse2,
+            Channel::Triangle => outputs.triangle = self.triangle,
+            Channel::Noise => outputs.noise = self.noise,
+            Channel::Dmc => outputs.dmc = self.dmc,
+            Channel::Expansion => outputs.expansion = self.expansion,
+        }
+        outputs
+    }
+}
+
+// The audio half of the 2A03, mapped at $4000 - $4017
+pub struct Apu {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    frame_counter: FrameCounter,
+    // The cartridge's sound chip, if it has one
+    expansion: f32,
+    // Only set up when someone wants to listen
+    pipeline: Option<AudioPipeline>,
+    // One per channel in `Channel::ALL` order while they are being captured separately
+    channel_pipelines: Vec<AudioPipeline>,
+
+    // The pulse channels' timers run at half the CPU clock
+    odd_cycle: bool,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Apu {
+            pulse1: Pulse::new(Sweep::OnesComplement),
+            pulse2: Pulse::new(Sweep::TwosComplement),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            frame_counter: FrameCounter::new(),
+            expansion: 0.0,
+            pipeline: None,
+            channel_pipelines: Vec::new(),
+            odd_cycle: false,
+        }
+    }
+
+    // Starts producing samples at `sample_rate`, read them out each frame through `audio`
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.pipeline = Some(AudioPipeline::new(sample_rate));
+    }
+
+    pub fn audio(&mut self) -> Option<&mut AudioPipeline> {
+        self.pipeline.as_mut()
+    }
+
+    // Also produces each channel on its own, read them out through `channel_audio`
+    pub fn capture_channels(&mut self, sample_rate: u32) {
+        self.channel_pipelines = Channel::ALL
+            .iter()
+            .map(|_| AudioPipeline::new(sample_rate))
+            .collect();
+    }
+
+    pub fn stop_channel_capture(&mut self) {
+        self.channel_pipelines.clear();
+    }
+
+    pub fn channel_audio(&mut self) -> &mut [AudioPipeline] {
+        &mut self.channel_pipelines
+    }
+
+    // The bus passes on the cartridge's expansion audio every cycle
+    pub fn set_expansion_output(&mut self, level: f32) {
+        self.expansion = level;
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000 => self.pulse1.write_control(data),
+            0x4001 => self.pulse1.write_sweep(data),
+            0x4002 => self.pulse1.write_timer_low(data),
+            0x4003 => self.pulse1.write_length(data),
+
+            0x4004 => self.pulse2.write_control(data),
+            0x4005 => self.pulse2.write_sweep(data),
+            0x4006 => self.pulse2.write_timer_low(data),
+            0x4007 => self.pulse2.write_length(data),
+
+            0x4008 => self.triangle.write_linear_counter(data),
+            0x400A => self.triangle.write_timer_low(data),
+            0x400B => self.triangle.write_length(data),
+
+            0x400C => self.noise.write_control(data),
+            0x400E => self.noise.write_period(data),
+            0x400F => self.noise.write_length(data),
+
+            0x4010 => self.dmc.write_control(data),
+            0x4011 => self.dmc.write_output_level(data),
+            0x4012 => self.dmc.write_sample_address(data),
+            0x4013 => self.dmc.write_sample_length(data),
+
+            // ---D NT21, enable channels
+            0x4015 => {
+                self.pulse1.length_counter.set_enabled(data & 0b0001 != 0);
+                self.pulse2.length_counter.set_enabled(data & 0b0010 != 0);
+                self.triangle.length_counter.set_enabled(data & 0b0100 != 0);
+                self.noise.length_counter.set_enabled(data & 0b1000 != 0);
+                self.dmc.set_enabled(data & 0b1_0000 != 0);
+            }
+
+            0x4017 => self.frame_counter.write(data, self.odd_cycle),
+            _ => {}
+        }
+    }
+
+    // Silences every channel and restarts the frame counter with the last $4017 write
+    pub fn reset(&mut self) {
+        self.write_register(0x4015, 0);
+        self.frame_counter.reset();
+    }
+
+    // $4015: IF-D NT21, the IRQ flags and which channels still have length left
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        if self.dmc.irq_flag() {
+            status |= 0b1000_0000;
+        }
+        if self.frame_counter.irq_flag() {
+            status |= 0b0100_0000;
+        }
+        if self.pulse1.length_counter.active() {
+            status |= 0b0001;
+        }
+        if self.pulse2.length_counter.active() {
+            status |= 0b0010;
+        }
+        if self.triangle.length_counter.active() {
+            status |= 0b0100;
+        }
+        if self.noise.length_counter.active() {
+            status |= 0b1000;
+        }
+        if self.dmc.active() {
+            status |= 0b1_0000;
+        }
+
+        // Reading acknowledges the frame IRQ
+        self.frame_counter.acknowledge_irq();
+        status
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.frame_counter.irq_flag() || self.dmc.irq_flag()
+    }
+
+    // Where the DMC wants its next sample byte read from, see `Dmc::sample_request`
+    pub fn dmc_request(&self) -> Option<u16> {
+        self.dmc.sample_request()
+    }
+
+    pub fn dmc_fill(&mut self, data: u8) {
+        self.dmc.fill_sample_buffer(data);
+    }
+
+    // Advances the APU by one CPU cycle
+    pub fn tick(&mut self) {
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+
+        match self.frame_counter.tick() {
+            FrameClock::Quarter => self.clock_quarter_frame(),
+            FrameClock::Half => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            FrameClock::None => {}
+        }
+
+        if self.pipeline.is_some() || !self.channel_pipelines.is_empty() {
+            let outputs = self.outputs();
+            if let Some(pipeline) = self.pipeline.as_mut() {
+                pipeline.clock(&outputs);
+            }
+            for (pipeline, channel) in self.channel_pipelines.iter_mut().zip(Channel::ALL) {
+                pipeline.clock(&outputs.isolate(channel));
+            }
+        }
+    }
+
+    // Envelopes and the triangle's linear counter
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear_counter();
+    }
+
+    // Length counters and sweeps
+    fn clock_half_frame(&mut self) {
+        self.pulse1.length_counter.clock();
+        self.pulse2.length_counter.clock();
+        self.triangle.length_counter.clock();
+        self.noise.length_counter.clock();
+
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    pub fn outputs(&self) -> ChannelOutputs {
+        ChannelOutputs {
+            pulse1: self.pulse1.output(),
+            pulse2: self.pulse2.output(),
+            triangle: self.triangle.output(),
+            noise: self.noise.output(),
+            dmc: self.dmc.output(),
+            expansion: self.expansion,
+        }
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The output pipelines belong to whoever is listening rather than the console, so they are kept
+impl Savestate for Apu {
+    crate::savestate_fields!(
+        pulse1,
+        pulse2,
+        triangle,
+        noise,
+        dmc,
+        frame_counter,
+        expansion,
+        odd_cycle,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_status_reports_length_counters() {
+        let mut apu = Apu::new();
+        // Loading a length while the channel is disabled does nothing
+        apu.write_register(0x4003, 0b0000_1000);
+        assert_eq!(apu.read_status(), 0);
+
+        apu.write_register(0x4015, 0b1111);
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.write_register(0x400B, 0b0000_1000);
+        apu.write_register(0x400F, 0b0000_1000);
+        assert_eq!(apu.read_status(), 0b1101);
+
+        // Disabling clears the counter
+        apu.write_register(0x4015, 0b1100);
+        assert_eq!(apu.read_status(), 0b1100);
+    }
+
+    #[test]
+    fn test_length_counter_runs_out() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0b0001);
+        // Index 1 is 254 half frames, index 3 is 2
+        apu.write_register(0x4003, 0b0001_1000);
+
+        apu.clock_half_frame();
+        assert_eq!(apu.read_status(), 0b0001);
+        apu.clock_half_frame();
+        assert_eq!(apu.read_status(), 0);
+    }
+
+    #[test]
+    fn test_frame_irq_through_status() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4017, 0x00);
+        for _ in 0..(4 + 29830) {
+            apu.tick();
+        }
+        assert!(apu.irq_pending());
+        assert_eq!(apu.read_status() & 0b0100_0000, 0b0100_0000);
+        assert!(!apu.irq_pending());
+        assert_eq!(apu.read_status() & 0b0100_0000, 0);
+    }
+
+    #[test]
+    fn test_pulse_output_follows_envelope() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0b0001);
+        // 50% duty, constant volume 9
+        apu.write_register(0x4000, 0b1001_1001);
+        apu.write_register(0x4002, 0x10);
+        apu.write_register(0x4003, 0b0000_1000);
+
+        let mut levels = Vec::new();
+        for _ in 0..(0x11 * 2 * 8) {
+            apu.tick();
+            levels.push(apu.outputs().pulse1);
+        }
+        assert!(levels.contains(&9));
+        assert!(levels.contains(&0));
+        assert!(levels.iter().all(|&level| level == 0 || level == 9));
+    }
+}
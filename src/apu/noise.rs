@@ -0,0 +1,119 @@
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::savestate::Savestate;
+
+// Timer periods in CPU cycles
+const PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+pub struct Noise {
+    pub envelope: Envelope,
+    pub length_counter: LengthCounter,
+
+    // Short mode feeds back from bit 6 instead of bit 1, giving a 93 step metallic tone
+    short_mode: bool,
+    timer_period: u16,
+    timer: u16,
+    shift_register: u16,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Noise {
+            envelope: Envelope::new(),
+            length_counter: LengthCounter::new(),
+            short_mode: false,
+            timer_period: PERIOD_TABLE[0],
+            timer: 0,
+            // Loaded with 1 on power up
+            shift_register: 1,
+        }
+    }
+
+    // $400C: --LC VVVV
+    pub fn write_control(&mut self, data: u8) {
+        self.length_counter.set_halted(data & 0b0010_0000 != 0);
+        self.envelope.write_control(data);
+    }
+
+    // $400E: M--- PPPP
+    pub fn write_period(&mut self, data: u8) {
+        self.short_mode = data & 0b1000_0000 != 0;
+        self.timer_period = PERIOD_TABLE[(data & 0b1111) as usize];
+    }
+
+    // $400F: LLLL L---
+    pub fn write_length(&mut self, data: u8) {
+        self.length_counter.load(data);
+        self.envelope.restart();
+    }
+
+    // Clocked every CPU cycle, the period table is in CPU cycles
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+
+        self.timer = self.timer_period - 1;
+
+        let tap = if self.short_mode { 6 } else { 1 };
+        let feedback = (self.shift_register & 1) ^ ((self.shift_register >> tap) & 1);
+        self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length_counter.active() || self.shift_register & 1 == 1 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Noise {
+    crate::savestate_fields!(
+        envelope,
+        length_counter,
+        short_mode,
+        timer_period,
+        timer,
+        shift_register,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Number of shifts before the register returns to where it started
+    fn lfsr_period(short_mode: bool) -> usize {
+        let mut noise = Noise::new();
+        noise.write_period(if short_mode { 0x80 } else { 0x00 });
+
+        let start = noise.shift_register;
+        let mut shifts = 0;
+        loop {
+            for _ in 0..PERIOD_TABLE[0] {
+                noise.clock_timer();
+            }
+            shifts += 1;
+            if noise.shift_register == start {
+                return shifts;
+            }
+        }
+    }
+
+    #[test]
+    fn test_lfsr_modes() {
+        assert_eq!(lfsr_period(false), 32767);
+        assert_eq!(lfsr_period(true), 93);
+    }
+}
@@ -0,0 +1,122 @@
+use super::filter::{HighPass, LowPass};
+use super::mixer::Mixer;
+use super::resampler::Resampler;
+use super::ChannelOutputs;
+use alloc::vec::Vec;
+
+// NTSC CPU clock, which is also the rate the APU's outputs change at
+pub const CPU_CLOCK_RATE: f64 = 1_789_773.0;
+
+// Turns the APU's per cycle channel levels into samples at the host's rate: the non-linear
+// mix, band-limited resampling, then the console's own output filters.
+pub struct AudioPipeline {
+    sample_rate: u32,
+    mixer: Mixer,
+    resampler: Resampler,
+    high_pass_90: HighPass,
+    high_pass_440: HighPass,
+    low_pass_14k: LowPass,
+
+    last_level: f32,
+    raw: Vec<f32>,
+    samples: Vec<f32>,
+    samples_i16: Vec<i16>,
+}
+
+impl AudioPipeline {
+    pub fn new(sample_rate: u32) -> Self {
+        AudioPipeline {
+            sample_rate,
+            mixer: Mixer::new(),
+            resampler: Resampler::new(sample_rate as f64 / CPU_CLOCK_RATE),
+            high_pass_90: HighPass::new(90.0, sample_rate),
+            high_pass_440: HighPass::new(440.0, sample_rate),
+            low_pass_14k: LowPass::new(14_000.0, sample_rate),
+            last_level: 0.0,
+            raw: Vec::new(),
+            samples: Vec::new(),
+            samples_i16: Vec::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Nudges the effective output rate, e.g. 1.005 makes 0.5% more samples per frame
+    pub fn set_rate_adjust(&mut self, adjust: f64) {
+        self.resampler
+            .set_ratio(self.sample_rate as f64 * adjust / CPU_CLOCK_RATE);
+    }
+
+    // One CPU cycle's worth of output
+    pub fn clock(&mut self, outputs: &ChannelOutputs) {
+        let level = self.mixer.mix(outputs);
+        if level != self.last_level {
+            self.resampler.add_delta(level - self.last_level);
+            self.last_level = level;
+        }
+        self.resampler.clock();
+    }
+
+    // The samples produced since the last call, in -1.0 - 1.0
+    pub fn end_frame(&mut self) -> &[f32] {
+        self.raw.clear();
+        self.resampler.read(&mut self.raw);
+
+        self.samples.clear();
+        for &sample in &self.raw {
+            let sample = self.high_pass_90.process(sample);
+            let sample = self.high_pass_440.process(sample);
+            self.samples.push(self.low_pass_14k.process(sample));
+        }
+        &self.samples
+    }
+
+    // As `end_frame`, scaled to full range 16 bit
+    pub fn end_frame_i16(&mut self) -> &[i16] {
+        self.end_frame();
+        self.samples_i16.clear();
+        for &sample in &self.samples {
+            self.samples_i16
+                .push((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16);
+        }
+        &self.samples_i16
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_samples_per_frame() {
+        let mut pipeline = AudioPipeline::new(44_100);
+        let outputs = ChannelOutputs::default();
+
+        // 60 frames of CPU cycles make a second of audio
+        let mut total = 0;
+        for _ in 0..60 {
+            for _ in 0..(CPU_CLOCK_RATE / 60.0) as usize {
+                pipeline.clock(&outputs);
+            }
+            total += pipeline.end_frame().len();
+        }
+        assert!((44_090..=44_100).contains(&total));
+    }
+
+    #[test]
+    fn test_dc_is_filtered_out() {
+        let mut pipeline = AudioPipeline::new(48_000);
+        let outputs = ChannelOutputs {
+            pulse1: 15,
+            ..Default::default()
+        };
+        for _ in 0..(CPU_CLOCK_RATE / 2.0) as usize {
+            pipeline.clock(&outputs);
+        }
+        let samples = pipeline.end_frame_i16();
+        assert!(samples.iter().any(|&sample| sample > 1000));
+        assert!(samples[samples.len() - 1].abs() < 10);
+    }
+}
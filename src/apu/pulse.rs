@@ -0,0 +1,201 @@
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::savestate::Savestate;
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+    [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+    [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
+];
+
+// The two pulse channels are identical apart from how their sweep units negate. Pulse 1 adds
+// the one's complement of the change (subtracting one more), pulse 2 the two's complement.
+// The MMC5's copies of the pulse channel have no sweep unit at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sweep {
+    OnesComplement,
+    TwosComplement,
+    None,
+}
+
+pub struct Pulse {
+    negate_mode: Sweep,
+    pub envelope: Envelope,
+    pub length_counter: LengthCounter,
+
+    duty: u8,
+    sequence_step: u8,
+    timer_period: u16,
+    timer: u16,
+
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    pub fn new(negate_mode: Sweep) -> Self {
+        Pulse {
+            negate_mode,
+            envelope: Envelope::new(),
+            length_counter: LengthCounter::new(),
+            duty: 0,
+            sequence_step: 0,
+            timer_period: 0,
+            timer: 0,
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+
+    // $4000 / $4004: DDLC VVVV
+    pub fn write_control(&mut self, data: u8) {
+        self.duty = data >> 6;
+        self.length_counter.set_halted(data & 0b0010_0000 != 0);
+        self.envelope.write_control(data);
+    }
+
+    // $4001 / $4005: EPPP NSSS
+    pub fn write_sweep(&mut self, data: u8) {
+        self.sweep_enabled = data & 0b1000_0000 != 0;
+        self.sweep_period = (data >> 4) & 0b111;
+        self.sweep_negate = data & 0b0000_1000 != 0;
+        self.sweep_shift = data & 0b111;
+        self.sweep_reload = true;
+    }
+
+    // $4002 / $4006
+    pub fn write_timer_low(&mut self, data: u8) {
+        self.timer_period = (self.timer_period & 0x700) | data as u16;
+    }
+
+    // $4003 / $4007: LLLL LHHH
+    pub fn write_length(&mut self, data: u8) {
+        self.timer_period = (self.timer_period & 0x0FF) | ((data & 0b111) as u16) << 8;
+        self.length_counter.load(data);
+        self.envelope.restart();
+        self.sequence_step = 0;
+    }
+
+    // The period the sweep unit is heading towards, worked out continuously
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if !self.sweep_negate {
+            return self.timer_period + change;
+        }
+
+        match self.negate_mode {
+            Sweep::OnesComplement => self.timer_period.saturating_sub(change + 1),
+            Sweep::TwosComplement => self.timer_period.saturating_sub(change),
+            Sweep::None => self.timer_period,
+        }
+    }
+
+    // The sweep unit silences the channel for periods too short or targets too long,
+    // even when it isn't enabled
+    fn sweep_muted(&self) -> bool {
+        if self.negate_mode == Sweep::None {
+            return false;
+        }
+        self.timer_period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    // Clocked every APU cycle, which is every other CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_step = (self.sequence_step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Clocked every half frame by the frame counter
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.sweep_muted() {
+            self.timer_period = self.sweep_target();
+        }
+
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length_counter.active()
+            || self.sweep_muted()
+            || DUTY_TABLE[self.duty as usize][self.sequence_step as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl Savestate for Pulse {
+    crate::savestate_fields!(
+        envelope,
+        length_counter,
+        duty,
+        sequence_step,
+        timer_period,
+        timer,
+        sweep_enabled,
+        sweep_period,
+        sweep_negate,
+        sweep_shift,
+        sweep_divider,
+        sweep_reload,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pulse(negate_mode: Sweep) -> Pulse {
+        let mut pulse = Pulse::new(negate_mode);
+        pulse.length_counter.set_enabled(true);
+        // Period 0x100, negated sweep with a shift of 1 and period 0
+        pulse.write_timer_low(0x00);
+        pulse.write_length(0x01);
+        pulse.write_sweep(0b1000_1001);
+        pulse
+    }
+
+    #[test]
+    fn test_sweep_negate_differs_between_channels() {
+        let mut pulse1 = pulse(Sweep::OnesComplement);
+        let mut pulse2 = pulse(Sweep::TwosComplement);
+
+        pulse1.clock_sweep();
+        pulse2.clock_sweep();
+        assert_eq!(pulse1.timer_period, 0x100 - 0x80 - 1);
+        assert_eq!(pulse2.timer_period, 0x100 - 0x80);
+    }
+
+    #[test]
+    fn test_sweep_mutes_low_periods() {
+        let mut pulse = pulse(Sweep::TwosComplement);
+        pulse.write_control(0b1011_1111);
+        pulse.write_timer_low(0x07);
+        pulse.write_length(0x00);
+
+        for _ in 0..16 {
+            pulse.clock_timer();
+            assert_eq!(pulse.output(), 0);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use crate::math;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::f64::consts::PI;
+
+// Kernel taps per step, half either side of it
+const WIDTH: usize = 16;
+// Sub-sample positions the kernel is precomputed for
+const PHASES: usize = 32;
+// Passband edge as a fraction of the output rate, leaving room for the kernel's roll off
+const CUTOFF: f64 = 0.45;
+
+// Band-limited step synthesis, the same idea as blargg's blip_buf. The APU's output only ever
+// changes in steps, so rather than filtering 1.79 million input samples a second we add a
+// band-limited impulse for each change into a buffer of deltas at the output rate, then
+// integrate the deltas back into samples when they are read out.
+pub struct Resampler {
+    kernel: Vec<[f32; WIDTH]>,
+    // Output samples per input clock
+    ratio: f64,
+    // Where the next input clock lands, in output samples from the start of `deltas`
+    time: f64,
+    deltas: Vec<f32>,
+    level: f32,
+}
+
+impl Resampler {
+    pub fn new(ratio: f64) -> Self {
+        Resampler {
+            kernel: build_kernel(),
+            ratio,
+            time: 0.0,
+            deltas: vec![0.0; WIDTH],
+            level: 0.0,
+        }
+    }
+
+    pub fn set_ratio(&mut self, ratio: f64) {
+        self.ratio = ratio;
+    }
+
+    // The input stepped by `delta` at the current clock
+    pub fn add_delta(&mut self, delta: f32) {
+        let start = self.time as usize;
+        let phase = ((self.time - start as f64) * PHASES as f64) as usize;
+
+        if self.deltas.len() < start + WIDTH {
+            self.deltas.resize(start + WIDTH, 0.0);
+        }
+        for (slot, tap) in self.deltas[start..].iter_mut().zip(&self.kernel[phase]) {
+            *slot += delta * tap;
+        }
+    }
+
+    pub fn clock(&mut self) {
+        self.time += self.ratio;
+    }
+
+    // Moves every sample that no future step can change into `out`
+    pub fn read(&mut self, out: &mut Vec<f32>) {
+        let ready = self.time as usize;
+        if self.deltas.len() < ready + WIDTH {
+            self.deltas.resize(ready + WIDTH, 0.0);
+        }
+
+        for delta in self.deltas.drain(..ready) {
+            self.level += delta;
+            out.push(self.level);
+        }
+        self.time -= ready as f64;
+    }
+}
+
+// A Blackman windowed sinc for each phase, normalised so a step always settles at its full size
+fn build_kernel() -> Vec<[f32; WIDTH]> {
+    let half = (WIDTH / 2) as f64;
+
+    (0..PHASES)
+        .map(|phase| {
+            let offset = phase as f64 / PHASES as f64;
+            let mut taps = [0.0; WIDTH];
+            for (i, tap) in taps.iter_mut().enumerate() {
+                let x = i as f64 + 1.0 - half - offset;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    math::sin(2.0 * PI * CUTOFF * x) / (2.0 * PI * CUTOFF * x)
+                };
+                let n = (x + half) / (2.0 * half);
+                let window = 0.42 - 0.5 * math::cos(2.0 * PI * n) + 0.08 * math::cos(4.0 * PI * n);
+                *tap = (sinc * window) as f32;
+            }
+
+            let sum: f32 = taps.iter().sum();
+            taps.iter_mut().for_each(|tap| *tap /= sum);
+            taps
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_step_settles() {
+        let mut resampler = Resampler::new(0.25);
+        resampler.add_delta(1.0);
+        for _ in 0..400 {
+            resampler.clock();
+        }
+
+        let mut out = Vec::new();
+        resampler.read(&mut out);
+        assert_eq!(out.len(), 100);
+        assert!((out[99] - 1.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn test_high_frequencies_are_removed() {
+        // A square wave well above the output's Nyquist frequency should all but vanish
+        let mut resampler = Resampler::new(0.1);
+        let mut high = false;
+        for clock in 0..10_000 {
+            if clock % 3 == 0 {
+                high = !high;
+                resampler.add_delta(if high { 1.0 } else { -1.0 });
+            }
+            resampler.clock();
+        }
+
+        let mut out = Vec::new();
+        resampler.read(&mut out);
+        let settled = &out[WIDTH..];
+        assert!(settled.iter().all(|&sample| (sample - 0.5).abs() < 0.1));
+    }
+}
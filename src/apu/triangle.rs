@@ -0,0 +1,105 @@
+use super::length_counter::LengthCounter;
+use crate::savestate::Savestate;
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+pub struct Triangle {
+    pub length_counter: LengthCounter,
+
+    timer_period: u16,
+    timer: u16,
+    sequence_step: u8,
+
+    // Also halts the length counter
+    control: bool,
+    linear_counter_period: u8,
+    linear_counter: u8,
+    linear_counter_reload: bool,
+}
+
+impl Triangle {
+    pub fn new() -> Self {
+        Triangle {
+            length_counter: LengthCounter::new(),
+            timer_period: 0,
+            timer: 0,
+            sequence_step: 0,
+            control: false,
+            linear_counter_period: 0,
+            linear_counter: 0,
+            linear_counter_reload: false,
+        }
+    }
+
+    // $4008: CRRR RRRR
+    pub fn write_linear_counter(&mut self, data: u8) {
+        self.control = data & 0b1000_0000 != 0;
+        self.length_counter.set_halted(self.control);
+        self.linear_counter_period = data & 0b0111_1111;
+    }
+
+    // $400A
+    pub fn write_timer_low(&mut self, data: u8) {
+        self.timer_period = (self.timer_period & 0x700) | data as u16;
+    }
+
+    // $400B: LLLL LHHH
+    pub fn write_length(&mut self, data: u8) {
+        self.timer_period = (self.timer_period & 0x0FF) | ((data & 0b111) as u16) << 8;
+        self.length_counter.load(data);
+        self.linear_counter_reload = true;
+    }
+
+    // Unlike the other channels, clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+
+        self.timer = self.timer_period;
+        // The sequencer only moves while both counters are running, otherwise it holds its level
+        if self.linear_counter > 0 && self.length_counter.active() {
+            self.sequence_step = (self.sequence_step + 1) % 32;
+        }
+    }
+
+    // Clocked every quarter frame by the frame counter
+    pub fn clock_linear_counter(&mut self) {
+        if self.linear_counter_reload {
+            self.linear_counter = self.linear_counter_period;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        if !self.control {
+            self.linear_counter_reload = false;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.sequence_step as usize]
+    }
+}
+
+impl Default for Triangle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Triangle {
+    crate::savestate_fields!(
+        length_counter,
+        timer_period,
+        timer,
+        sequence_step,
+        control,
+        linear_counter_period,
+        linear_counter,
+        linear_counter_reload,
+    );
+}
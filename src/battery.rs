@@ -0,0 +1,114 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// How often battery backed RAM is written out while the game is running
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+// Keeps a cartridge's battery backed PRG RAM in a .sav file, next to the ROM unless a save
+// directory is given.
+pub struct BatterySave {
+    path: PathBuf,
+    // What is on disk, so we only write when the game has actually saved something
+    saved: Vec<u8>,
+    last_flush: Instant,
+}
+
+impl BatterySave {
+    pub fn new(rom_path: &Path, save_dir: Option<&Path>) -> Self {
+        let file_name = rom_path.with_extension("sav");
+        let path = match (save_dir, file_name.file_name()) {
+            (Some(dir), Some(name)) => dir.join(name),
+            _ => file_name,
+        };
+
+        BatterySave {
+            path,
+            saved: Vec::new(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Fills the RAM from the save file. Having no save file yet is fine
+    pub fn load(&mut self, ram: &mut [u8]) -> io::Result<()> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        // A save from a differently sized RAM still loads as much as fits
+        let len = data.len().min(ram.len());
+        ram[..len].copy_from_slice(&data[..len]);
+        self.saved = ram.to_vec();
+        Ok(())
+    }
+
+    pub fn flush(&mut self, ram: &[u8]) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        if self.saved == ram {
+            return Ok(());
+        }
+
+        if let Some(dir) = self.path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+
+        // Write to a temporary file first so a crash mid write can't destroy the old save
+        let tmp = self.path.with_extension("sav.tmp");
+        fs::write(&tmp, ram)?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.saved = ram.to_vec();
+        Ok(())
+    }
+
+    // Called every frame, only touches the disk every FLUSH_INTERVAL
+    pub fn flush_periodically(&mut self, ram: &[u8]) -> io::Result<()> {
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush(ram)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_save_path() {
+        let save = BatterySave::new(Path::new("roms/zelda.nes"), None);
+        assert_eq!(save.path(), Path::new("roms/zelda.sav"));
+
+        let save = BatterySave::new(Path::new("roms/zelda.nes"), Some(Path::new("saves")));
+        assert_eq!(save.path(), Path::new("saves/zelda.sav"));
+    }
+
+    #[test]
+    fn test_flush_and_load() {
+        let dir = std::env::temp_dir().join(format!("nes-battery-test-{}", std::process::id()));
+        let rom_path = Path::new("zelda.nes");
+
+        let mut ram = vec![0; 0x2000];
+        let mut save = BatterySave::new(rom_path, Some(&dir));
+        save.load(&mut ram).unwrap();
+        assert!(ram.iter().all(|&b| b == 0));
+
+        ram[0x10] = 0x42;
+        save.flush(&ram).unwrap();
+
+        let mut loaded = vec![0; 0x2000];
+        BatterySave::new(rom_path, Some(&dir)).load(&mut loaded).unwrap();
+        assert_eq!(loaded[0x10], 0x42);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+use nes::cartridge::Rom;
+use nes::input::{InputState, Peripherals};
+use nes::movie::{self, Movie, MovieSession};
+use nes::nes::SNAKE_GAME;
+use nes::savestate;
+use nes::Nes;
+use std::path::PathBuf;
+
+const DEFAULT_FRAMES: u64 = 600;
+
+#[derive(Default)]
+struct Args {
+    rom_path: Option<PathBuf>,
+    frames: Option<u64>,
+    play_movie: Option<PathBuf>,
+    input: Option<Peripherals>,
+    screenshot: Option<PathBuf>,
+}
+
+const USAGE: &str = "\
+Usage: nes-headless [rom.nes] [--frames <n>] [--play-movie <file.fm2>] [--input <device>]
+                    [--screenshot <file.png|.ppm>]";
+
+// Runs the game, or the snake game without a ROM, for 600 frames or --frames, with only the
+// movie's input if there is one. Then prints hashes for regression tests: the machine's state
+// (CPU registers and RAM, the APU, the cartridge's banks and RAM, the controllers and the snake
+// game's randomness) and all the sound made. Needs no window or sound device.
+// Only the snake game has a picture: its last frame is hashed too, and --screenshot writes it
+// out. Cartridges have no picture until there's a PPU, so they get neither.
+fn parse_args() -> Args {
+    let mut parsed = Args::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => parsed.frames = args.next().and_then(|frames| frames.parse().ok()),
+            "--play-movie" => parsed.play_movie = args.next().map(PathBuf::from),
+            "--input" => match args.next().as_deref().map(|name| (name, Peripherals::from_name(name))) {
+                Some((_, Some(peripherals))) => parsed.input = Some(peripherals),
+                Some((name, None)) => eprintln!("Unknown input device {}, using the default", name),
+                None => {}
+            },
+            "--screenshot" => parsed.screenshot = args.next().map(PathBuf::from),
+            _ if arg.starts_with('-') => {
+                eprintln!("Unknown option {}\n{}", arg, USAGE);
+                std::process::exit(1);
+            }
+            _ => parsed.rom_path = Some(PathBuf::from(arg)),
+        }
+    }
+
+    parsed
+}
+
+fn run(args: &Args) -> Result<(), String> {
+    if args.rom_path.is_some() && args.screenshot.is_some() {
+        return Err("Cartridges have no picture until there's a PPU, --screenshot only works for the snake game".to_string());
+    }
+    let movie = match &args.play_movie {
+        Some(path) => Some(Movie::load(path)?),
+        None => None,
+    };
+    let (mut nes, rom_checksum) = match &args.rom_path {
+        Some(path) => {
+            let raw = std::fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+            let rom = Rom::new(&raw)?;
+            let checksum = rom.md5();
+            (Nes::new(rom)?, checksum)
+        }
+        // Without a movie the snake game gets the same apples every run
+        None => (Nes::snake(movie.as_ref().map_or(1, |movie| movie.rng_seed)), movie::md5(&SNAKE_GAME)),
+    };
+    if let Some(peripherals) = args.input {
+        nes.cpu().bus.controllers().connect(peripherals);
+    }
+    if nes.cpu().bus.controllers().peripherals() == Peripherals::Zapper {
+        eprintln!("Only the Zapper's trigger works until there's a PPU, it never senses light so it can't hit anything");
+    }
+    let mut session = match (movie, &args.play_movie) {
+        (Some(movie), Some(path)) => {
+            if movie.rom_checksum != rom_checksum {
+                eprintln!("{} was recorded with a different ROM ({})", path.display(), movie.rom_filename);
+            }
+            Some(MovieSession::start_playback(nes.cpu(), movie, path)?)
+        }
+        _ => None,
+    };
+
+    // Each frame's sound is hashed along with the hash so far
+    let mut audio_hash = 0u32;
+    let idle = InputState::default();
+    for _ in 0..args.frames.unwrap_or(DEFAULT_FRAMES) {
+        let input = session
+            .as_mut()
+            .and_then(|session| nes.movie_input(session, &idle))
+            .unwrap_or(idle);
+        let (_, audio) = nes.run_frame(&input);
+        let bytes: Vec<u8> = audio.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        audio_hash = savestate::crc32(&[&audio_hash.to_le_bytes(), &bytes]);
+    }
+
+    let state_hash = savestate::crc32(&[&nes.cpu().save_state()]);
+    let frame = nes.frame();
+    if nes.is_snake() {
+        println!("Frame CRC32 {:08X}", frame.hash());
+    }
+    println!("State CRC32 {:08X}", state_hash);
+    println!("Audio CRC32 {:08X}", audio_hash);
+
+    if let Some(path) = &args.screenshot {
+        let image = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("png") => frame.to_png(),
+            _ => frame.to_ppm(),
+        };
+        std::fs::write(path, image).map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run(&parse_args()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
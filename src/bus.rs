@@ -0,0 +1,389 @@
+use crate::apu::Apu;
+use crate::cartridge::Rom;
+use crate::cpu::memory::Memory;
+use crate::input::{ControllerPorts, Peripherals};
+use crate::mapper::{self, Mapper};
+use crate::movie::MovieRng;
+use crate::nametables::Nametables;
+use crate::savestate::Savestate;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+// CPU cycles an OAM DMA holds the CPU for, plus one more if it starts on an odd cycle
+const OAM_DMA_CYCLES: u32 = 513;
+
+// Everything the CPU can address. Without a cartridge inserted the whole 64 KiB is plain RAM,
+// which is what the snake game and the CPU tests run against.
+pub struct Bus {
+    memory: [u8; 0x10000],
+    mapper: Option<Box<dyn Mapper>>,
+    battery: bool,
+    // CRC32 of the cartridge's ROM, which save states have to match
+    rom_hash: u32,
+    nametables: Nametables,
+    apu: Apu,
+    controllers: ControllerPorts,
+    // The snake game's source of random numbers. It lives here so save states carry on with it
+    snake_rng: Option<MovieRng>,
+
+    // CPU cycles since power on, including the ones spent stalled by DMA
+    cycles: u64,
+    // Stall cycles owed to an OAM DMA started by the last instruction
+    oam_dma_stall: u32,
+    // The controller port read by the last instruction. A DMC fetch landing on that read
+    // makes the CPU read the port again, which clocks the controller's shift register
+    controller_read: Option<u16>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Bus {
+            memory: [0; 0x10000],
+            mapper: None,
+            battery: false,
+            rom_hash: 0,
+            nametables: Nametables::default(),
+            apu: Apu::new(),
+            controllers: ControllerPorts::new(),
+            snake_rng: None,
+            cycles: 0,
+            oam_dma_stall: 0,
+            controller_read: None,
+        }
+    }
+
+    // Nothing changes if the cartridge can't be inserted
+    pub fn insert_cartridge(&mut self, mut rom: Rom) -> Result<(), String> {
+        let battery = rom.battery;
+        let rom_hash = rom.crc32();
+        let nametables = Nametables::new(rom.screen_mirroring);
+        let peripherals = Peripherals::from_nes2(rom.expansion_device);
+        let trainer = rom.trainer.take();
+        let mut mapper = mapper::from_rom(rom)?;
+
+        // Trainers were loaded into $7000 - $71FF by copiers before the game started
+        if let Some(trainer) = trainer {
+            match mapper.prg_ram() {
+                Some(ram) if ram.len() >= 0x1000 + trainer.len() => {
+                    ram[0x1000..(0x1000 + trainer.len())].copy_from_slice(&trainer);
+                }
+                _ => return Err("Cartridge has a trainer but no PRG RAM to hold it".to_string()),
+            }
+        }
+
+        self.battery = battery;
+        self.rom_hash = rom_hash;
+        self.nametables = nametables;
+        // The frontend can still plug in something else afterwards
+        if let Some(peripherals) = peripherals {
+            self.controllers.connect(peripherals);
+        }
+        self.mapper = Some(mapper);
+        Ok(())
+    }
+
+    // For hardware that isn't an iNES cartridge, like an NSF player
+    pub fn insert_mapper(&mut self, mapper: Box<dyn Mapper>) {
+        self.battery = false;
+        self.rom_hash = 0;
+        self.mapper = Some(mapper);
+    }
+
+    // PRG RAM that should outlive the power being switched off, if the cartridge has a battery
+    pub fn battery_ram(&mut self) -> Option<&mut [u8]> {
+        if !self.battery {
+            return None;
+        }
+        self.mapper.as_mut().and_then(|mapper| mapper.prg_ram())
+    }
+
+    pub fn mapper(&mut self) -> Option<&mut Box<dyn Mapper>> {
+        self.mapper.as_mut()
+    }
+
+    // PPU side access to $0000 - $1FFF. Nothing calls these until there's a PPU
+    pub fn pattern_read(&mut self, addr: u16) -> u8 {
+        self.nametables.pattern_read(self.mapper.as_mut(), addr)
+    }
+
+    pub fn pattern_write(&mut self, addr: u16, data: u8) {
+        self.nametables.pattern_write(self.mapper.as_mut(), addr, data);
+    }
+
+    // PPU side access to $2000 - $3EFF
+    pub fn nametable_read(&mut self, addr: u16) -> u8 {
+        self.nametables.read(self.mapper.as_mut(), addr)
+    }
+
+    pub fn nametable_write(&mut self, addr: u16, data: u8) {
+        self.nametables.write(self.mapper.as_mut(), addr, data);
+    }
+
+    pub fn set_snake_rng(&mut self, rng: Option<MovieRng>) {
+        self.snake_rng = rng;
+    }
+
+    pub fn snake_rng(&self) -> Option<&MovieRng> {
+        self.snake_rng.as_ref()
+    }
+
+    pub fn snake_rng_mut(&mut self) -> Option<&mut MovieRng> {
+        self.snake_rng.as_mut()
+    }
+
+    pub fn apu(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    pub fn rom_hash(&self) -> u32 {
+        self.rom_hash
+    }
+
+    pub fn controllers(&mut self) -> &mut ControllerPorts {
+        &mut self.controllers
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Runs everything else on the bus for the cycles the last instruction took, plus any
+    // cycles the CPU spends halted for DMA along the way
+    pub fn tick(&mut self, cycles: u8) {
+        let mut remaining = cycles as u32 + self.oam_dma_stall;
+        let mut oam_dma = self.oam_dma_stall;
+        self.oam_dma_stall = 0;
+
+        while remaining > 0 {
+            remaining -= 1;
+            self.clock();
+
+            if let Some(addr) = self.apu.dmc_request() {
+                // The DMC steals 4 cycles normally, but only 2 when it slots into an OAM DMA
+                let in_oam_dma = remaining < oam_dma;
+                let stall = if in_oam_dma { 2 } else { 4 };
+
+                // The halted CPU repeats its read, so a controller being read gets clocked again
+                if let Some(port) = self.controller_read.take() {
+                    self.mem_read(port);
+                }
+
+                let data = self.mem_read(addr);
+                self.apu.dmc_fill(data);
+                remaining += stall;
+                if in_oam_dma {
+                    oam_dma += stall;
+                }
+            }
+        }
+
+        self.controller_read = None;
+    }
+
+    fn clock(&mut self) {
+        self.cycles += 1;
+        if let Some(mapper) = self.mapper.as_mut() {
+            mapper.cpu_clock();
+            self.apu.set_expansion_output(mapper.audio_output());
+        }
+        self.apu.tick();
+    }
+
+    // $4014: copies a page of CPU memory to the PPU's OAM through $2004
+    fn oam_dma(&mut self, page: u8) {
+        let base = (page as u16) << 8;
+        for offset in 0..256 {
+            let data = self.mem_read(base + offset);
+            self.mem_write(0x2004, data);
+        }
+        self.oam_dma_stall = OAM_DMA_CYCLES + (self.cycles & 1) as u32;
+    }
+
+    pub fn reset(&mut self) {
+        self.apu.reset();
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.apu.irq_pending() || self.mapper.as_ref().is_some_and(|mapper| mapper.irq_pending())
+    }
+}
+
+impl Savestate for Bus {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.memory.save(out);
+        // The cartridge itself has to be inserted already, only its board's state is saved
+        self.mapper.is_some().save(out);
+        if let Some(mapper) = self.mapper.as_ref() {
+            mapper.save(out);
+        }
+        self.nametables.save(out);
+        self.apu.save(out);
+        self.controllers.save(out);
+        self.snake_rng.is_some().save(out);
+        if let Some(rng) = self.snake_rng.as_ref() {
+            rng.save(out);
+        }
+        self.cycles.save(out);
+        self.oam_dma_stall.save(out);
+        self.controller_read.save(out);
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        self.memory.load(data)?;
+        let mut has_mapper = false;
+        has_mapper.load(data)?;
+        match (has_mapper, self.mapper.as_mut()) {
+            (true, Some(mapper)) => mapper.load(data)?,
+            (false, None) => {}
+            (true, None) => return Err("Save state needs a cartridge inserted".to_string()),
+            (false, Some(_)) => return Err("Save state was made without a cartridge".to_string()),
+        }
+        self.nametables.load(data)?;
+        self.apu.load(data)?;
+        self.controllers.load(data)?;
+        let mut has_snake_rng = false;
+        has_snake_rng.load(data)?;
+        match (has_snake_rng, self.snake_rng.as_mut()) {
+            (true, Some(rng)) => rng.load(data)?,
+            (false, None) => {}
+            (true, None) => return Err("Save state is from the snake game".to_string()),
+            (false, Some(_)) => return Err("Save state isn't from the snake game".to_string()),
+        }
+        self.cycles.load(data)?;
+        self.oam_dma_stall.load(data)?;
+        self.controller_read.load(data)
+    }
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for Bus {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        match (addr, self.mapper.as_mut()) {
+            (0x4015, _) => self.apu.read_status(),
+            (0x4016 | 0x4017, _) => {
+                self.controller_read = Some(addr);
+                self.controllers.read((addr - 0x4016) as usize)
+            }
+            (0x4020..=0xFFFF, Some(mapper)) => mapper.cpu_read(addr),
+            _ => self.memory[addr as usize],
+        }
+    }
+
+    fn mem_read_u16(&mut self, pos: u16) -> u16 {
+        let lo = self.mem_read(pos);
+        let hi = self.mem_read(pos.wrapping_add(1));
+        u16::from_le_bytes([lo, hi])
+    }
+
+    fn mem_write(&mut self, addr: u16, data: u8) {
+        match (addr, self.mapper.as_mut()) {
+            (0x2000..=0x3FFF, Some(mapper)) => {
+                mapper.ppu_register_write(addr, data);
+                self.memory[addr as usize] = data;
+            }
+            (0x4000..=0x4013 | 0x4015 | 0x4017, _) => self.apu.write_register(addr, data),
+            (0x4014, _) => self.oam_dma(data),
+            (0x4016, _) => self.controllers.write(data),
+            (0x4020..=0xFFFF, Some(mapper)) => mapper.cpu_write(addr, data),
+            _ => self.memory[addr as usize] = data,
+        }
+    }
+
+    fn mem_write_u16(&mut self, pos: u16, data: u16) {
+        let [lo, hi] = data.to_le_bytes();
+        self.mem_write(pos, lo);
+        self.mem_write(pos.wrapping_add(1), hi);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::input::{Buttons, InputState};
+
+    #[test]
+    fn test_trainer_mapped_at_7000() {
+        let mut rom = test_rom(0, 1, 0);
+        rom.trainer = Some((0..=255).cycle().take(512).collect());
+
+        let mut bus = Bus::new();
+        bus.insert_cartridge(rom).unwrap();
+        assert_eq!(bus.mem_read(0x7000), 0);
+        assert_eq!(bus.mem_read(0x7005), 5);
+        assert_eq!(bus.mem_read(0x71FF), 0xFF);
+
+        // A trainer with nowhere to go leaves the cartridge that was in before
+        let mut rom = test_rom(0, 1, 0);
+        rom.trainer = Some(vec![0; 0x2000]);
+        rom.expansion_device = 0x08;
+        assert!(bus.insert_cartridge(rom).is_err());
+        assert_eq!(bus.mem_read(0x7005), 5);
+        assert_eq!(bus.controllers().peripherals(), Peripherals::Joypads);
+    }
+
+    #[test]
+    fn test_dmc_fetch_stalls_cpu() {
+        let mut rom = test_rom(0, 2, 1);
+        rom.prg_rom[0x4040] = 0xAA;
+        let mut bus = Bus::new();
+        bus.insert_cartridge(rom).unwrap();
+
+        // A one byte sample at $C040
+        bus.mem_write(0x4012, 0x01);
+        bus.mem_write(0x4013, 0x00);
+        bus.mem_write(0x4015, 0b1_0000);
+        assert_eq!(bus.mem_read(0x4015) & 0b1_0000, 0b1_0000);
+
+        bus.tick(2);
+        assert_eq!(bus.cycles(), 2 + 4);
+        assert_eq!(bus.mem_read(0x4015) & 0b1_0000, 0);
+    }
+
+    #[test]
+    fn test_oam_dma_stall() {
+        let mut bus = Bus::new();
+        bus.mem_write(0x4014, 0x02);
+        bus.tick(4);
+        assert_eq!(bus.cycles(), 4 + OAM_DMA_CYCLES as u64);
+
+        // Starting on an odd cycle costs one more
+        bus.mem_write(0x4014, 0x02);
+        bus.tick(4);
+        assert_eq!(bus.cycles(), 2 * (4 + OAM_DMA_CYCLES as u64) + 1);
+    }
+
+    #[test]
+    fn test_controller_ports() {
+        let mut bus = Bus::new();
+        let input = InputState {
+            buttons: [Buttons::B, Buttons::A, Buttons::empty(), Buttons::empty()],
+            ..Default::default()
+        };
+        bus.controllers().update(&input);
+
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        assert_eq!(bus.mem_read(0x4016), 0x40);
+        assert_eq!(bus.mem_read(0x4016), 0x41);
+        // Each port shifts on its own
+        assert_eq!(bus.mem_read(0x4017), 0x41);
+        assert_eq!(bus.mem_read(0x4017), 0x40);
+    }
+
+    #[test]
+    fn test_chr_ram_is_writable() {
+        let mut bus = Bus::new();
+        bus.insert_cartridge(test_rom(0, 1, 0)).unwrap();
+
+        let mapper = bus.mapper().unwrap();
+        mapper.ppu_write(0x1234, 0x42);
+        assert_eq!(mapper.ppu_read(0x1234), 0x42);
+    }
+}
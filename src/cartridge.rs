@@ -0,0 +1,337 @@
+use crate::movie;
+use crate::savestate::{self, Savestate};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+// iNES header magic: "NES" followed by MS-DOS end of file
+const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_ROM_PAGE_SIZE: usize = 16384;
+const CHR_ROM_PAGE_SIZE: usize = 8192;
+// Boards without CHR ROM have 8 KiB of CHR RAM unless a NES 2.0 header says otherwise
+const DEFAULT_CHR_RAM_SIZE: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    Vertical,
+    Horizontal,
+    FourScreen,
+    // Every nametable shows the same page of VRAM. Only mapper controlled, never from the header
+    SingleScreenLower,
+    SingleScreenUpper,
+}
+
+impl Savestate for Mirroring {
+    fn save(&self, out: &mut Vec<u8>) {
+        let mode: u8 = match self {
+            Mirroring::Vertical => 0,
+            Mirroring::Horizontal => 1,
+            Mirroring::FourScreen => 2,
+            Mirroring::SingleScreenLower => 3,
+            Mirroring::SingleScreenUpper => 4,
+        };
+        mode.save(out);
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        let mut mode = 0u8;
+        mode.load(data)?;
+        *self = match mode {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::FourScreen,
+            3 => Mirroring::SingleScreenLower,
+            4 => Mirroring::SingleScreenUpper,
+            _ => return Err(format!("Save state has an unknown mirroring mode {}", mode)),
+        };
+        Ok(())
+    }
+}
+
+impl Mirroring {
+    // Maps a PPU address in the nametable space ($2000 - $3EFF) to an offset into nametable
+    // memory. There are four logical nametables but the console only has room for two:
+    // Vertical:   [A B]    Horizontal: [A A]    Single screen: [A A] or [B B]
+    //             [A B]                [B B]                   [A A]    [B B]
+    // Four screen boards add another 2 KiB on the cartridge for C and D, at 0x800 - 0xFFF.
+    pub fn vram_offset(&self, addr: u16) -> usize {
+        let addr = (addr - 0x2000) & 0x0FFF;
+        let table = addr / 0x400;
+        let offset = (addr & 0x3FF) as usize;
+
+        let page = match (self, table) {
+            (Mirroring::Vertical, 0) | (Mirroring::Vertical, 2) => 0,
+            (Mirroring::Vertical, _) => 1,
+            (Mirroring::Horizontal, 0) | (Mirroring::Horizontal, 1) => 0,
+            (Mirroring::Horizontal, _) => 1,
+            (Mirroring::FourScreen, table) => table as usize,
+            (Mirroring::SingleScreenLower, _) => 0,
+            (Mirroring::SingleScreenUpper, _) => 1,
+        };
+
+        page * 0x400 + offset
+    }
+}
+
+pub struct Rom {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    // Zero when the board has CHR ROM
+    pub chr_ram_size: usize,
+    // 512 bytes the loader copies to $7000 - $71FF before the game starts
+    pub trainer: Option<Vec<u8>>,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub screen_mirroring: Mirroring,
+    pub battery: bool,
+    // The input device the game expects, from byte 15 of a NES 2.0 header. 0 when not given
+    pub expansion_device: u8,
+}
+
+impl Rom {
+    // Identifies the game, the header isn't included since it's so often fixed up
+    pub fn crc32(&self) -> u32 {
+        savestate::crc32(&[&self.prg_rom, &self.chr_rom])
+    }
+
+    // The same for movies, which go by MD5
+    pub fn md5(&self) -> [u8; 16] {
+        movie::md5(&[&self.prg_rom[..], &self.chr_rom[..]].concat())
+    }
+
+    pub fn new(raw: &[u8]) -> Result<Rom, String> {
+        if raw.len() < HEADER_SIZE || raw[0..4] != NES_TAG {
+            return Err("File is not in iNES file format".to_string());
+        }
+
+        // Byte 7 bits 2-3 are 0b10 for NES 2.0 headers. Those extend the mapper number to 12 bits
+        // and add a submapper, which some boards (eg. the Konami VRCs) need to pick their wiring
+        let nes2 = raw[7] & 0b0000_1100 == 0b0000_1000;
+        if !nes2 && raw[7] & 0b0000_1100 != 0 {
+            return Err("iNES versions other than 1.0 and 2.0 are not supported".to_string());
+        }
+
+        let mut mapper = ((raw[7] & 0b1111_0000) | (raw[6] >> 4)) as u16;
+        let mut submapper = 0;
+        if nes2 {
+            mapper |= ((raw[8] & 0b0000_1111) as u16) << 8;
+            submapper = raw[8] >> 4;
+        }
+
+        let four_screen = raw[6] & 0b1000 != 0;
+        let vertical_mirroring = raw[6] & 0b1 != 0;
+        let screen_mirroring = match (four_screen, vertical_mirroring) {
+            (true, _) => Mirroring::FourScreen,
+            (false, true) => Mirroring::Vertical,
+            (false, false) => Mirroring::Horizontal,
+        };
+
+        let battery = raw[6] & 0b10 != 0;
+        let expansion_device = if nes2 { raw[15] & 0b0011_1111 } else { 0 };
+
+        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
+        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        // Every board banks PRG ROM by its size, there is nothing to run without any
+        if prg_rom_size == 0 {
+            return Err("Header gives no PRG ROM".to_string());
+        }
+
+        // NES 2.0 gives CHR RAM sizes as shift counts, 64 << n bytes. Byte 11's low nibble is
+        // volatile RAM and the high nibble battery backed RAM, we don't tell them apart
+        let chr_ram_size = match (chr_rom_size, nes2) {
+            (0, true) => [raw[11] & 0x0F, raw[11] >> 4]
+                .iter()
+                .filter(|&&shift| shift != 0)
+                .map(|&shift| 64 << shift)
+                .sum(),
+            (0, false) => DEFAULT_CHR_RAM_SIZE,
+            _ => 0,
+        };
+
+        // A 512 byte trainer may sit between the header and PRG ROM
+        let has_trainer = raw[6] & 0b100 != 0;
+        let prg_rom_start = HEADER_SIZE + if has_trainer { TRAINER_SIZE } else { 0 };
+        let chr_rom_start = prg_rom_start + prg_rom_size;
+
+        if raw.len() < chr_rom_start + chr_rom_size {
+            return Err("File is shorter than the sizes given in its header".to_string());
+        }
+
+        let trainer = if has_trainer {
+            Some(raw[HEADER_SIZE..(HEADER_SIZE + TRAINER_SIZE)].to_vec())
+        } else {
+            None
+        };
+
+        Ok(Rom {
+            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
+            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+            chr_ram_size,
+            trainer,
+            mapper,
+            submapper,
+            screen_mirroring,
+            battery,
+            expansion_device,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    pub struct TestRom {
+        pub header: Vec<u8>,
+        pub trainer: Option<Vec<u8>>,
+        pub prg_rom: Vec<u8>,
+        pub chr_rom: Vec<u8>,
+    }
+
+    pub fn create_rom(rom: TestRom) -> Vec<u8> {
+        let mut result = Vec::with_capacity(
+            rom.header.len()
+                + rom.trainer.as_ref().map_or(0, |t| t.len())
+                + rom.prg_rom.len()
+                + rom.chr_rom.len(),
+        );
+
+        result.extend(&rom.header);
+        if let Some(t) = rom.trainer {
+            result.extend(t);
+        }
+        result.extend(&rom.prg_rom);
+        result.extend(&rom.chr_rom);
+
+        result
+    }
+
+    // Builds an iNES 1.0 image for the given mapper with zeroed PRG and CHR ROM
+    pub fn test_rom(mapper: u8, prg_pages: u8, chr_pages: u8) -> Rom {
+        let raw = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, prg_pages, chr_pages, (mapper << 4) | 0b01, mapper & 0xF0, 00, 00,
+                00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![0; prg_pages as usize * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![0; chr_pages as usize * CHR_ROM_PAGE_SIZE],
+        });
+
+        Rom::new(&raw).unwrap()
+    }
+
+    #[test]
+    fn test() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.chr_rom, vec!(2; CHR_ROM_PAGE_SIZE));
+        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+    }
+
+    #[test]
+    fn test_with_trainer() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31 | 0b100, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: Some(vec![3; TRAINER_SIZE]),
+            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.chr_rom, vec!(2; CHR_ROM_PAGE_SIZE));
+        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert_eq!(rom.trainer, Some(vec!(3; TRAINER_SIZE)));
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+    }
+
+    #[test]
+    fn test_chr_ram_size() {
+        let ines = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+        let rom = Rom::new(&ines).unwrap();
+        assert!(rom.chr_rom.is_empty());
+        assert_eq!(rom.chr_ram_size, 8192);
+
+        // NES 2.0 with 32 KiB of CHR RAM (64 << 9)
+        let nes2 = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x01, 0x08, 00, 00, 00, 0x09, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+        assert_eq!(Rom::new(&nes2).unwrap().chr_ram_size, 32768);
+    }
+
+    #[test]
+    fn test_nes2_mapper_and_submapper() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x50, 0x18, 0x20, 00, 00, 00, 00, 00, 00, 0x08,
+            ],
+            trainer: None,
+            prg_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.mapper, 21);
+        assert_eq!(rom.submapper, 2);
+        assert_eq!(rom.screen_mirroring, Mirroring::Horizontal);
+        assert_eq!(rom.expansion_device, 0x08);
+    }
+
+    #[test]
+    fn test_not_ines() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x00, 0x01, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        assert!(Rom::new(&test_rom).is_err());
+    }
+
+    #[test]
+    fn test_no_prg_rom() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x00, 0x01, 0x00, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        assert!(Rom::new(&test_rom).is_err());
+    }
+}
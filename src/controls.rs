@@ -0,0 +1,228 @@
+use nes::input::bindings::{Bindings, HostInput, ACTIONS};
+use nes::input::family_basic::KEY_MATRIX;
+use nes::input::zapper::{FRAME_HEIGHT, FRAME_WIDTH};
+use nes::input::{InputState, Peripherals, PointerState};
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{KeyboardState, Keycode, Scancode};
+use sdl2::mouse::MouseButton;
+use sdl2::{EventPump, GameControllerSubsystem, Sdl};
+use std::path::{Path, PathBuf};
+
+// How far a stick has to be pushed to count as pressing that direction
+const AXIS_THRESHOLD: i16 = 16_384;
+// The Power Pad's 3 x 4 pads, top row first
+const MAT_KEYS: [&str; 12] = ["R", "T", "Y", "U", "F", "G", "H", "J", "V", "B", "N", "M"];
+
+// Connects the host's keyboard and game controllers to the console's controller ports
+pub struct Controls {
+    bindings: Bindings,
+    // Where rebinding saves to
+    path: PathBuf,
+    subsystem: Option<GameControllerSubsystem>,
+    // Given to the players in the order they were plugged in
+    controllers: Vec<GameController>,
+    // The player being rebound and their next entry in `ACTIONS`
+    rebinding: Option<(usize, usize)>,
+    frame: u64,
+    // The mouse moves the paddle and fires the Zapper: where it is in window coordinates, and the
+    // left button
+    mouse: Option<(i32, i32)>,
+    mouse_down: bool,
+    // Set when something may have been pressed or released since the last `poll_input`
+    changed: bool,
+}
+
+impl Controls {
+    pub fn new(sdl_context: &Sdl, path: &Path) -> Self {
+        let bindings = match std::fs::read_to_string(path) {
+            Ok(config) => Bindings::parse(&config).unwrap_or_else(|e| {
+                eprintln!("Couldn't read {}: {}, using the default bindings", path.display(), e);
+                Bindings::defaults()
+            }),
+            Err(_) => Bindings::defaults(),
+        };
+
+        // Game controllers are optional. SDL sends an added event for each one that's already
+        // connected, so they get opened with the hot plugged ones
+        let subsystem = sdl_context
+            .game_controller()
+            .map_err(|e| eprintln!("Couldn't start game controller support: {}", e))
+            .ok();
+
+        Controls {
+            bindings,
+            path: path.to_path_buf(),
+            subsystem,
+            controllers: Vec::new(),
+            rebinding: None,
+            frame: 0,
+            mouse: None,
+            mouse_down: false,
+            changed: true,
+        }
+    }
+
+    // Returns true if the event was used up rebinding, otherwise it is left to the caller
+    pub fn handle_event(&mut self, event: &Event) -> bool {
+        match event {
+            Event::ControllerDeviceAdded { which, .. } => self.open_controller(*which),
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.controllers.retain(|controller| controller.instance_id() != *which);
+            }
+            Event::MouseMotion { x, y, .. } => self.mouse = Some((*x, *y)),
+            Event::MouseButtonDown { mouse_btn: MouseButton::Left, .. } => self.mouse_down = true,
+            Event::MouseButtonUp { mouse_btn: MouseButton::Left, .. } => self.mouse_down = false,
+            Event::Window { win_event: WindowEvent::Leave, .. } => self.mouse = None,
+            _ => {}
+        }
+
+        if self.rebinding.is_some() {
+            let input = match event {
+                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                    println!("Stopped rebinding");
+                    self.rebinding = None;
+                    return true;
+                }
+                Event::KeyDown { keycode: Some(keycode), repeat: false, .. } => Some(HostInput::Key(keycode.name())),
+                Event::ControllerButtonDown { button, .. } => Some(HostInput::Button(button.string())),
+                Event::ControllerAxisMotion { axis, value, .. } if value.unsigned_abs() >= AXIS_THRESHOLD as u16 => {
+                    Some(HostInput::Axis(axis.string(), *value > 0))
+                }
+                _ => None,
+            };
+            if let Some(input) = input {
+                self.bind_next(input);
+                return true;
+            }
+        }
+
+        if matches!(
+            event,
+            Event::KeyDown { .. }
+                | Event::KeyUp { .. }
+                | Event::ControllerButtonDown { .. }
+                | Event::ControllerButtonUp { .. }
+                | Event::ControllerAxisMotion { .. }
+                | Event::ControllerDeviceRemoved { .. }
+                | Event::MouseMotion { .. }
+                | Event::MouseButtonDown { .. }
+                | Event::MouseButtonUp { .. }
+                | Event::Window { .. }
+        ) {
+            self.changed = true;
+        }
+        false
+    }
+
+    fn open_controller(&mut self, index: u32) {
+        let Some(subsystem) = self.subsystem.as_ref() else { return };
+        match subsystem.open(index) {
+            Ok(controller) => {
+                println!("{} is player {}", controller.name(), self.controllers.len() + 1);
+                self.controllers.push(controller);
+            }
+            Err(e) => eprintln!("Couldn't open game controller {}: {}", index, e),
+        }
+    }
+
+    // Asks for a key or controller input for each of the player's buttons in turn
+    pub fn start_rebinding(&mut self, player: usize) {
+        self.rebinding = Some((player, 0));
+        self.prompt();
+    }
+
+    fn prompt(&self) {
+        if let Some((player, next)) = self.rebinding {
+            println!("Player {} {}: press a key or controller button, Escape to stop", player + 1, ACTIONS[next].0);
+        }
+    }
+
+    fn bind_next(&mut self, input: HostInput) {
+        let Some((player, next)) = self.rebinding else { return };
+        self.bindings.rebind(player, ACTIONS[next].1, input);
+
+        if next + 1 < ACTIONS.len() {
+            self.rebinding = Some((player, next + 1));
+            self.prompt();
+            return;
+        }
+
+        self.rebinding = None;
+        match std::fs::write(&self.path, self.bindings.to_config()) {
+            Ok(()) => println!("Saved bindings to {}", self.path.display()),
+            Err(e) => eprintln!("Couldn't write {}: {}", self.path.display(), e),
+        }
+    }
+
+    // Turbo buttons change state with the frame, even when nothing else does
+    pub fn end_frame(&mut self) {
+        self.frame += 1;
+        self.changed = true;
+    }
+
+    // The state of every input device, if it may have changed since last time. `scale` is how
+    // many window pixels each picture pixel takes up
+    pub fn poll_input(&mut self, event_pump: &EventPump, scale: (f32, f32), peripherals: Peripherals) -> Option<InputState> {
+        if !std::mem::take(&mut self.changed) {
+            return None;
+        }
+        let keys = event_pump.keyboard_state();
+        let controllers = &self.controllers;
+        // The keyboard and the mats use keys the joypads are bound to, so those only get game
+        // controllers while they're connected
+        let keys_free = !matches!(
+            peripherals,
+            Peripherals::FamilyBasicKeyboard | Peripherals::PowerPad(_) | Peripherals::FamilyTrainer(_)
+        );
+        let mut input =
+            self.bindings.resolve(self.frame, keys_free, |player, input| held(&keys, controllers, player, input));
+        input.pointer = PointerState {
+            position: self.mouse.and_then(|mouse| picture_position(mouse, scale)),
+            button: self.mouse_down,
+        };
+
+        let key_held = |name: &str| held(&keys, controllers, 0, &HostInput::Key(name.to_string()));
+        for (position, name) in MAT_KEYS.iter().enumerate() {
+            if key_held(name) {
+                input.mat |= 1 << position;
+            }
+        }
+        for (row, columns) in KEY_MATRIX.iter().enumerate() {
+            for (column, names) in columns.iter().enumerate() {
+                for (i, name) in names.iter().enumerate() {
+                    if key_held(name) {
+                        input.keyboard[row][column] |= 0b1_0000 >> i;
+                    }
+                }
+            }
+        }
+        Some(input)
+    }
+}
+
+fn picture_position((x, y): (i32, i32), (scale_x, scale_y): (f32, f32)) -> Option<(u16, u16)> {
+    let x = (x as f32 / scale_x).floor();
+    let y = (y as f32 / scale_y).floor();
+    let on_screen = x >= 0.0 && y >= 0.0 && x < FRAME_WIDTH as f32 && y < FRAME_HEIGHT as f32;
+    on_screen.then_some((x as u16, y as u16))
+}
+
+fn held(keys: &KeyboardState, controllers: &[GameController], player: usize, input: &HostInput) -> bool {
+    match input {
+        HostInput::Key(name) => Keycode::from_name(name)
+            .and_then(Scancode::from_keycode)
+            .is_some_and(|scancode| keys.is_scancode_pressed(scancode)),
+        HostInput::Button(name) => match (controllers.get(player), Button::from_string(name)) {
+            (Some(controller), Some(button)) => controller.button(button),
+            _ => false,
+        },
+        HostInput::Axis(name, positive) => match (controllers.get(player), Axis::from_string(name)) {
+            (Some(controller), Some(axis)) => {
+                let value = controller.axis(axis);
+                if *positive { value >= AXIS_THRESHOLD } else { value <= -AXIS_THRESHOLD }
+            }
+            _ => false,
+        },
+    }
+}
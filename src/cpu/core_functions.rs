@@ -1,4 +1,4 @@
-use super::{CPU, CpuFlags, AddressingMode, Memory};
+use super::{CPU, CpuFlags, AddressingMode, Memory, Stack};
 
 impl CPU {
     pub fn add_to_register_a(&mut self, value: u8) {
@@ -80,7 +80,7 @@ impl CPU {
         self.update_zero_and_negative_flags(self.register_y);
     }
 
-    pub fn get_operand_address(&self, mode: &AddressingMode) -> u16 {
+    pub fn get_operand_address(&mut self, mode: &AddressingMode) -> u16 {
         match mode {
             AddressingMode::Immediate => self.program_counter,
 
@@ -129,4 +129,18 @@ impl CPU {
             AddressingMode::NonAddressing => panic!("Mode {:?} is not supported", mode),
         }
     }
+
+    // Push the program counter and status then jump through the IRQ vector at 0xFFFE
+    pub fn interrupt_request(&mut self) {
+        self.stack_push_u16(self.program_counter);
+
+        // Break flag is only set when the status is pushed by BRK or PHP
+        let mut flags = self.status;
+        flags.remove(CpuFlags::BREAK);
+        flags.insert(CpuFlags::BREAK2);
+        self.stack_push(flags.bits());
+
+        self.set_interrupt_disable();
+        self.program_counter = self.mem_read_u16(0xFFFE);
+    }
 }
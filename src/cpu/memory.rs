@@ -1,38 +1,33 @@
 use super::CPU;
 
 pub trait Memory {
-    fn mem_read(&self, addr: u16) -> u8;
-    fn mem_read_u16(&self, pos: u16) -> u16;
+    // Reads take &mut self since some hardware changes state when it is read from,
+    // eg. acknowledging an IRQ on the cartridge
+    fn mem_read(&mut self, addr: u16) -> u8;
+    fn mem_read_u16(&mut self, pos: u16) -> u16;
     fn mem_write(&mut self, addr: u16, data: u8);
     fn mem_write_u16(&mut self, pos: u16, data: u16);
 }
 
 
 impl Memory for CPU {
-    fn mem_read(&self, addr: u16) -> u8 {
-        self.memory[addr as usize]
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.bus.mem_read(addr)
     }
 
-    fn mem_read_u16(&self, pos: u16) -> u16 {
+    fn mem_read_u16(&mut self, pos: u16) -> u16 {
         // Using little endian. MSB is stored after the LSB
         // [LSB, MSB]
-        let lo = self.mem_read(pos); 
-        let hi = self.mem_read(pos + 1);
-        u16::from_le_bytes([lo, hi])
+        self.bus.mem_read_u16(pos)
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
-        self.memory[addr as usize] = data;
+        self.bus.mem_write(addr, data);
     }
 
 
     fn mem_write_u16(&mut self, pos: u16, data: u16) {
-        let [lo, hi] = data.to_le_bytes();
-
         // Using little endian. MSB is stored after the LSB
-        self.mem_write(pos, lo);
-        self.mem_write(pos + 1, hi);
+        self.bus.mem_write_u16(pos, data);
     }
 }
-
-
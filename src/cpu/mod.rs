@@ -6,9 +6,13 @@ pub mod stack;
 use memory::Memory;
 use stack::Stack;
 
+use crate::bus::Bus;
 use crate::opcodes;
+use crate::savestate::{self, Savestate};
 use bitflags::bitflags;
-use std::collections::HashMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 
 // Use bitflags to make bit operations more straightforward
 bitflags! {
@@ -24,6 +28,19 @@ bitflags! {
     }
 }
 
+impl Savestate for CpuFlags {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.bits().save(out);
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        let mut bits = 0u8;
+        bits.load(data)?;
+        *self = CpuFlags::from_bits_truncate(bits);
+        Ok(())
+    }
+}
+
 // Stack memory space is [0x0100 .. 0x1FF]
 // Stack starts at 0x1FF and grows down
 // On reset of stack pointer: LDX #$FF, TXS
@@ -51,7 +68,7 @@ pub struct CPU {
     pub status: CpuFlags,
     pub stack_pointer: u8,
     pub program_counter: u16,
-    memory: [u8; 0xFFFF],
+    pub bus: Bus,
 }
 
 impl CPU {
@@ -63,7 +80,7 @@ impl CPU {
             stack_pointer: STACK_RESET,
             program_counter: 0,
             status: CpuFlags::from_bits_truncate(0b00100100), // Set break 2 and interrupt disable: https://stackoverflow.com/questions/16913423/why-is-the-initial-state-of-the-interrupt-flag-of-the-6502-a-1
-            memory: [0; 0xFFFF], // 0xFFFF size array filled with 0
+            bus: Bus::new(),
         }
     }
 
@@ -73,9 +90,36 @@ impl CPU {
         self.register_y = 0;
         self.stack_pointer = STACK_RESET;
         self.status = CpuFlags::from_bits_truncate(0b00100100);
+        self.bus.reset();
         self.program_counter = self.mem_read_u16(0xFFFC);
     }
 
+    // A snapshot of the whole machine, see `savestate`
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        savestate::write_header(&mut out, self.bus.rom_hash());
+        Savestate::save(self, &mut out);
+        out
+    }
+
+    // Puts the machine back how it was when `state` was saved. The same cartridge and input
+    // devices have to be in. Nothing changes if the state can't be loaded
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let mut data = state;
+        savestate::read_header(&mut data, self.bus.rom_hash())?;
+
+        let backup = self.save_state();
+        let mut loaded = Savestate::load(self, &mut data);
+        if loaded.is_ok() && !data.is_empty() {
+            loaded = Err("Save state has data left over".to_string());
+        }
+        if loaded.is_err() {
+            let mut data = &backup[savestate::HEADER_SIZE..];
+            Savestate::load(self, &mut data).expect("Couldn't restore the machine after a failed load");
+        }
+        loaded
+    }
+
     pub fn load_and_run(&mut self, program: Vec<u8>) {
         self.load(program);
         self.reset();
@@ -89,7 +133,9 @@ impl CPU {
         // Basically, we're loading the op codes from the program into memory starting from 0x8000;
         // Program ROM is from 0x8000 to 0xFFFF
         // // (Changed to 0x0600 for the snake game)
-        self.memory[0x0600 .. (0x0600 + program.len())].copy_from_slice(&program[..]);
+        for (i, byte) in program.iter().enumerate() {
+            self.mem_write(0x0600 + i as u16, *byte);
+        }
         self.mem_write_u16(0xFFFC, 0x0600);
     } 
 
@@ -104,202 +150,232 @@ impl CPU {
     where 
         F: FnMut(&mut CPU),
     {
-        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
+        while self.step() == Ok(true) {
+            callback(self);
+        }
+    }
 
-        loop {
-            let code = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-            let program_counter_state = self.program_counter;
+    // Runs one instruction, then everything else on the bus for the cycles it took.
+    // Returns false on BRK, which ends the program. Opcodes we don't know are an error, with the
+    // program counter left on them
+    pub fn step(&mut self) -> Result<bool, String> {
+        // Cartridge hardware can pull the IRQ line low, which is ignored while interrupts are disabled
+        if self.bus.irq_pending() && !self.status.contains(CpuFlags::INTERRUPT_DISABLE) {
+            self.interrupt_request();
+        }
 
-            let opcode = opcodes.get(&code).unwrap();
+        let code = self.mem_read(self.program_counter);
+        self.program_counter += 1;
+        let program_counter_state = self.program_counter;
 
-            match code {
-                // ADC
-                0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode),
+        let Some(opcode) = opcodes::OPCODES_MAP[code as usize] else {
+            return self.unknown_opcode(code);
+        };
 
-                // AND
-                0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode),
+        match code {
+            // ADC
+            0x69 | 0x65 | 0x75 | 0x6d | 0x7d | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode),
 
-                // ASL
-                0x0a => self.asl_accumulator(),
-                0x06 | 0x16 | 0x0e | 0x1e => self.asl(&opcode.mode),
+            // AND
+            0x29 | 0x25 | 0x35 | 0x2d | 0x3d | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode),
 
-                // BCC
-                0x90 => self.branch(!self.status.contains(CpuFlags::CARRY)),
+            // ASL
+            0x0a => self.asl_accumulator(),
+            0x06 | 0x16 | 0x0e | 0x1e => self.asl(&opcode.mode),
 
-                // BCS
-                0xb0 => self.branch(self.status.contains(CpuFlags::CARRY)),
+            // BCC
+            0x90 => self.branch(!self.status.contains(CpuFlags::CARRY)),
 
-                // BEQ
-                0xf0 => self.branch(self.status.contains(CpuFlags::ZERO)),
+            // BCS
+            0xb0 => self.branch(self.status.contains(CpuFlags::CARRY)),
 
-                // BIT
-                0x24 | 0x2c => self.bit(&opcode.mode),
+            // BEQ
+            0xf0 => self.branch(self.status.contains(CpuFlags::ZERO)),
 
-                // BMI
-                0x30 => self.branch(self.status.contains(CpuFlags::NEGATIVE)),
+            // BIT
+            0x24 | 0x2c => self.bit(&opcode.mode),
 
-                // BNE
-                0xd0 => self.branch(!self.status.contains(CpuFlags::ZERO)),
+            // BMI
+            0x30 => self.branch(self.status.contains(CpuFlags::NEGATIVE)),
 
-                // BPL
-                0x10 => self.branch(!self.status.contains(CpuFlags::NEGATIVE)),
+            // BNE
+            0xd0 => self.branch(!self.status.contains(CpuFlags::ZERO)),
 
-                // BRK
-                0x00 => return,
+            // BPL
+            0x10 => self.branch(!self.status.contains(CpuFlags::NEGATIVE)),
 
-                // BVC
-                0x50 => self.branch(!self.status.contains(CpuFlags::OVERFLOW)),
+            // BRK
+            0x00 => return Ok(false),
 
-                // BVS
-                0x70 => self.branch(self.status.contains(CpuFlags::OVERFLOW)),
+            // BVC
+            0x50 => self.branch(!self.status.contains(CpuFlags::OVERFLOW)),
 
-                // CLC
-                0x18 => self.clear_carry_flag(), 
+            // BVS
+            0x70 => self.branch(self.status.contains(CpuFlags::OVERFLOW)),
 
-                // CLD
-                0xd8 => self.clear_decimal_flag(), 
+            // CLC
+            0x18 => self.clear_carry_flag(), 
 
-                // CLI
-                0x58 => self.clear_interrupt_flag(), 
+            // CLD
+            0xd8 => self.clear_decimal_flag(), 
 
-                // CLV
-                0xb8 => self.clear_overflow_flag(),
+            // CLI
+            0x58 => self.clear_interrupt_flag(), 
 
-                // CMP
-                0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => self.cmp(&opcode.mode, self.register_a),
+            // CLV
+            0xb8 => self.clear_overflow_flag(),
 
-                // CPX
-                0xe0 | 0xe4 | 0xec => self.cmp(&opcode.mode, self.register_x),
+            // CMP
+            0xc9 | 0xc5 | 0xd5 | 0xcd | 0xdd | 0xd9 | 0xc1 | 0xd1 => self.cmp(&opcode.mode, self.register_a),
 
-                // CPY
-                0xc0 | 0xc4 | 0xcc => self.cmp(&opcode.mode, self.register_y),
+            // CPX
+            0xe0 | 0xe4 | 0xec => self.cmp(&opcode.mode, self.register_x),
 
-                // DEC
-                0xc6 | 0xd6 | 0xce | 0xde => self.decrement_memory(&opcode.mode),
+            // CPY
+            0xc0 | 0xc4 | 0xcc => self.cmp(&opcode.mode, self.register_y),
 
-                // DEX
-                0xca => self.decrement_register_x(),
+            // DEC
+            0xc6 | 0xd6 | 0xce | 0xde => self.decrement_memory(&opcode.mode),
 
-                // DEY
-                0x88 => self.decrement_register_y(),
+            // DEX
+            0xca => self.decrement_register_x(),
 
-                // EOR
-                0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => self.exclusive_or(&opcode.mode),
+            // DEY
+            0x88 => self.decrement_register_y(),
 
-                // INC
-                0xe6 | 0xf6 | 0xee | 0xfe => self.increment_memory(&opcode.mode),
+            // EOR
+            0x49 | 0x45 | 0x55 | 0x4d | 0x5d | 0x59 | 0x41 | 0x51 => self.exclusive_or(&opcode.mode),
 
-                // INX
-                0xe8 => self.increment_register_x(), 
+            // INC
+            0xe6 | 0xf6 | 0xee | 0xfe => self.increment_memory(&opcode.mode),
 
-                // INY 
-                0xc8 => self.increment_register_y(), 
+            // INX
+            0xe8 => self.increment_register_x(), 
 
-                // JMP - Absolute
-                0x4c => self.jump_absolute(),
-                
-                // Jmp - Indirect
-                0x6c => self.jump_indirect(), 
+            // INY 
+            0xc8 => self.increment_register_y(), 
 
-                // JSR
-                0x20 => self.jump_sub_routine(),
+            // JMP - Absolute
+            0x4c => self.jump_absolute(),
+            
+            // Jmp - Indirect
+            0x6c => self.jump_indirect(), 
 
-                // LDA 
-                0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => self.load_a_register(&opcode.mode),
+            // JSR
+            0x20 => self.jump_sub_routine(),
 
-                // LDX
-                0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => self.load_x_register(&opcode.mode),
+            // LDA 
+            0xa9 | 0xa5 | 0xb5 | 0xad | 0xbd | 0xb9 | 0xa1 | 0xb1 => self.load_a_register(&opcode.mode),
 
-                // LDY
-                0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => self.load_y_register(&opcode.mode),
+            // LDX
+            0xa2 | 0xa6 | 0xb6 | 0xae | 0xbe => self.load_x_register(&opcode.mode),
 
-                // LSR
-                0x4a => self.logical_shift_right_accumulator(),
-                0x46 | 0x56 | 0x4e | 0x5e => self.logical_shift_right(&opcode.mode),
+            // LDY
+            0xa0 | 0xa4 | 0xb4 | 0xac | 0xbc => self.load_y_register(&opcode.mode),
 
-                // NOP
-                0xea => {},
+            // LSR
+            0x4a => self.logical_shift_right_accumulator(),
+            0x46 | 0x56 | 0x4e | 0x5e => self.logical_shift_right(&opcode.mode),
 
-                // ORA
-                0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => self.logical_inclusive_or(&opcode.mode),
+            // NOP
+            0xea => {},
 
-                // PHA
-                0x48 => self.stack_push(self.register_a),
+            // ORA
+            0x09 | 0x05 | 0x15 | 0x0d | 0x1d | 0x19 | 0x01 | 0x11 => self.logical_inclusive_or(&opcode.mode),
 
-                // PHP
-                0x08 => self.push_processor_status(),
+            // PHA
+            0x48 => self.stack_push(self.register_a),
 
-                // PLA
-                0x68 => self.pull_accumulator(),
+            // PHP
+            0x08 => self.push_processor_status(),
 
-                // PLP
-                0x28 => self.pull_processor_status(),
+            // PLA
+            0x68 => self.pull_accumulator(),
 
-                // ROL
-                0x2a => self.rotate_left_accumulator(),
-                0x26 | 0x36 | 0x2e | 0x3e => self.rotate_left(&opcode.mode), 
+            // PLP
+            0x28 => self.pull_processor_status(),
 
-                // ROR
-                0x6a => self.rotate_right_accumulator(),
-                0x66 | 0x76 | 0x6e | 0x7e => self.rotate_right(&opcode.mode),
+            // ROL
+            0x2a => self.rotate_left_accumulator(),
+            0x26 | 0x36 | 0x2e | 0x3e => self.rotate_left(&opcode.mode), 
 
-                // RTI
-                0x40 => self.return_from_interrupt(),
+            // ROR
+            0x6a => self.rotate_right_accumulator(),
+            0x66 | 0x76 | 0x6e | 0x7e => self.rotate_right(&opcode.mode),
 
-                // RTS
-                0x60 => self.program_counter = self.stack_pop_u16() + 1,
+            // RTI
+            0x40 => self.return_from_interrupt(),
 
-                // SBC
-                0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => self.subtract_with_carry(&opcode.mode),
+            // RTS
+            0x60 => self.program_counter = self.stack_pop_u16() + 1,
 
-                // SEC
-                0x38 => self.set_carry_flag(),
+            // SBC
+            0xe9 | 0xe5 | 0xf5 | 0xed | 0xfd | 0xf9 | 0xe1 | 0xf1 => self.subtract_with_carry(&opcode.mode),
 
-                // SED
-                0xf8 => self.set_decimal_flag(),
+            // SEC
+            0x38 => self.set_carry_flag(),
 
-                // SEI
-                0x78 => self.set_interrupt_disable(),
+            // SED
+            0xf8 => self.set_decimal_flag(),
 
-                // STA
-                0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => self.store_accumulator(&opcode.mode),
+            // SEI
+            0x78 => self.set_interrupt_disable(),
 
-                // STX
-                0x86 | 0x96 | 0x8e => self.store_x_register(&opcode.mode),
+            // STA
+            0x85 | 0x95 | 0x8d | 0x9d | 0x99 | 0x81 | 0x91 => self.store_accumulator(&opcode.mode),
 
-                // STY
-                0x84 | 0x94 | 0x8c => self.store_y_register(&opcode.mode),
+            // STX
+            0x86 | 0x96 | 0x8e => self.store_x_register(&opcode.mode),
 
-                // TAX
-                0xAA => self.transfer_accumulator_x(),
+            // STY
+            0x84 | 0x94 | 0x8c => self.store_y_register(&opcode.mode),
 
-                // TAY
-                0xa8 => self.transfer_accumulator_y(),
+            // TAX
+            0xAA => self.transfer_accumulator_x(),
 
-                // TSX
-                0xBA => self.transfer_stack_pointer_to_x(),
+            // TAY
+            0xa8 => self.transfer_accumulator_y(),
 
-                // TXA 
-                0x8A => self.transfer_x_accumulator(),
+            // TSX
+            0xBA => self.transfer_stack_pointer_to_x(),
 
-                // TXS
-                0x9A => self.transfer_x_to_stack_pointer(),
+            // TXA 
+            0x8A => self.transfer_x_accumulator(),
 
-                // TYA
-                0x98 => self.transfer_y_accumulator(),
+            // TXS
+            0x9A => self.transfer_x_to_stack_pointer(),
 
-                _ => panic!("Unexpected op code"),
+            // TYA
+            0x98 => self.transfer_y_accumulator(),
 
-            }
+            _ => return self.unknown_opcode(code),
 
-            if program_counter_state == self.program_counter {
-                self.program_counter += (opcode.len - 1) as u16;
-            }
+        }
 
-            callback(self);
+        if program_counter_state == self.program_counter {
+            self.program_counter += (opcode.len - 1) as u16;
         }
+
+        self.bus.tick(opcode.cycles);
+        Ok(true)
     }
+
+    fn unknown_opcode(&mut self, code: u8) -> Result<bool, String> {
+        self.program_counter -= 1;
+        Err(format!("Unknown opcode ${:02X} at ${:04X}", code, self.program_counter))
+    }
+}
+
+impl Savestate for CPU {
+    crate::savestate_fields!(
+        register_a,
+        register_x,
+        register_y,
+        status,
+        stack_pointer,
+        program_counter,
+        bus,
+    );
 }
 
 #[cfg(test)]
@@ -358,4 +434,39 @@ mod test {
         cpu.load_and_run(vec![0xA9, 0x05, 0x69, 0xFF, 0x00]);
         assert_eq!(cpu.register_a, 4);
     }
+
+    #[test]
+    fn test_save_state_round_trip() {
+        // INX, INX, BRK
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xe8, 0xe8, 0x00]);
+        cpu.reset();
+        cpu.mem_write(0x10, 0x55);
+        cpu.step().unwrap();
+        let state = cpu.save_state();
+
+        cpu.step().unwrap();
+        cpu.mem_write(0x10, 0xAA);
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.register_x, 1);
+        assert_eq!(cpu.program_counter, 0x0601);
+        assert_eq!(cpu.mem_read(0x10), 0x55);
+        assert_eq!(cpu.save_state(), state);
+
+        // A state that doesn't load leaves the machine as it was
+        cpu.step().unwrap();
+        assert!(cpu.load_state(&state[..state.len() - 1]).is_err());
+        assert_eq!(cpu.register_x, 2);
+
+        let mut other_game = CPU::new();
+        other_game.bus.insert_cartridge(crate::cartridge::test::test_rom(0, 1, 1)).unwrap();
+        assert!(other_game.load_state(&state).unwrap_err().contains("another game"));
+
+        let mut zapper = CPU::new();
+        zapper.bus.controllers().connect(crate::input::Peripherals::Zapper);
+        assert_eq!(
+            zapper.load_state(&state).unwrap_err(),
+            "Save state was made with joypads connected, not zapper"
+        );
+    }
 }
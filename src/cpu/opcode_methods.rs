@@ -237,7 +237,8 @@ impl CPU {
 
     pub fn logical_inclusive_or(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
-        self.set_register_a(self.register_a | self.mem_read(addr));
+        let value = self.mem_read(addr);
+        self.set_register_a(self.register_a | value);
     }
 
     pub fn push_processor_status(&mut self) {
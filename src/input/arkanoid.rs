@@ -0,0 +1,164 @@
+use super::zapper::FRAME_WIDTH;
+use super::{ControllerDevice, ExpansionDevice, InputState};
+use crate::savestate::Savestate;
+
+// The range of the Vaus' potentiometer, far left to far right
+const PADDLE_MIN: u8 = 98;
+const PADDLE_MAX: u8 = 242;
+
+// The Arkanoid Vaus controller: a knob and a fire button. The strobe latches the knob's
+// position, which then shifts out inverted and most significant bit first.
+struct Paddle {
+    position: u8,
+    button: bool,
+    shift: u8,
+}
+
+impl Paddle {
+    fn new() -> Self {
+        Paddle {
+            position: PADDLE_MIN,
+            button: false,
+            shift: 0,
+        }
+    }
+
+    fn write(&mut self, data: u8) {
+        if data & 1 != 0 {
+            self.shift = !self.position;
+        }
+    }
+
+    fn read_bit(&mut self) -> u8 {
+        let bit = self.shift >> 7;
+        self.shift <<= 1;
+        bit
+    }
+
+    // The knob follows the pointer across the screen, and stays put when it leaves
+    fn update(&mut self, input: &InputState) {
+        if let Some((x, _)) = input.pointer.position {
+            let range = (PADDLE_MAX - PADDLE_MIN) as usize;
+            let x = (x as usize).min(FRAME_WIDTH - 1);
+            self.position = PADDLE_MIN + (x * range / (FRAME_WIDTH - 1)) as u8;
+        }
+        self.button = input.pointer.button;
+    }
+}
+
+// The NES version, in port 2: the button on bit 3 and the knob on bit 4
+pub struct Vaus {
+    paddle: Paddle,
+}
+
+impl Vaus {
+    pub fn new() -> Self {
+        Vaus { paddle: Paddle::new() }
+    }
+}
+
+impl Default for Vaus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ControllerDevice for Vaus {
+    fn write(&mut self, data: u8) {
+        self.paddle.write(data);
+    }
+
+    fn read(&mut self) -> u8 {
+        let button = if self.paddle.button { 0b0_1000 } else { 0 };
+        button | self.paddle.read_bit() << 4
+    }
+
+    fn update(&mut self, input: &InputState) {
+        self.paddle.update(input);
+    }
+}
+
+// The Famicom version, in the expansion port: the button on bit 1 of $4016 and the knob on bit
+// 1 of $4017
+pub struct FamicomVaus {
+    paddle: Paddle,
+}
+
+impl FamicomVaus {
+    pub fn new() -> Self {
+        FamicomVaus { paddle: Paddle::new() }
+    }
+}
+
+impl Default for FamicomVaus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExpansionDevice for FamicomVaus {
+    fn write(&mut self, data: u8) {
+        self.paddle.write(data);
+    }
+
+    fn read(&mut self, port: usize) -> u8 {
+        match port {
+            0 => (self.paddle.button as u8) << 1,
+            _ => self.paddle.read_bit() << 1,
+        }
+    }
+
+    fn update(&mut self, input: &InputState) {
+        self.paddle.update(input);
+    }
+}
+
+impl Savestate for Paddle {
+    crate::savestate_fields!(position, button, shift);
+}
+
+impl Savestate for Vaus {
+    crate::savestate_fields!(paddle);
+}
+
+impl Savestate for FamicomVaus {
+    crate::savestate_fields!(paddle);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::PointerState;
+
+    fn pointer(x: u16, button: bool) -> InputState {
+        InputState {
+            pointer: PointerState { position: Some((x, 100)), button },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_knob_shifts_out_inverted() {
+        let mut vaus = Vaus::new();
+        vaus.update(&pointer(0, true));
+        vaus.write(1);
+        vaus.write(0);
+
+        // 98 is 0b0110_0010, inverted 0b1001_1101
+        let reads: Vec<u8> = (0..8).map(|_| vaus.read()).collect();
+        assert_eq!(reads, [0x18, 0x08, 0x08, 0x18, 0x18, 0x18, 0x08, 0x18]);
+    }
+
+    #[test]
+    fn test_famicom_vaus() {
+        let mut vaus = FamicomVaus::new();
+        vaus.update(&pointer(FRAME_WIDTH as u16 - 1, false));
+        vaus.write(1);
+        vaus.write(0);
+
+        // 242 is 0b1111_0010, inverted 0b0000_1101
+        assert_eq!(vaus.read(0), 0);
+        let reads: Vec<u8> = (0..8).map(|_| vaus.read(1)).collect();
+        assert_eq!(reads, [0, 0, 0, 0, 0b10, 0b10, 0, 0b10]);
+    }
+}
@@ -0,0 +1,304 @@
+use super::{Buttons, InputState};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+// Turbo buttons are pressed this many times a second unless the config says otherwise. A press
+// and a release take at least a frame each, so there can be at most one every two frames
+const DEFAULT_TURBO_RATE: u32 = 15;
+const MAX_TURBO_RATE: u32 = 30;
+const FRAMES_PER_SECOND: u32 = 60;
+
+// Something on the host that can be bound to an NES button. Names are SDL's: key names like
+// "X" or "Left Shift", and game controller button and axis names like "a", "dpup" or "leftx"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostInput {
+    Key(String),
+    // On the game controller assigned to the binding's player
+    Button(String),
+    // An axis pushed past halfway, in the positive or negative direction
+    Axis(String, bool),
+}
+
+impl HostInput {
+    fn parse(text: &str) -> Option<HostInput> {
+        let (kind, name) = text.split_once(':')?;
+        let name = name.trim();
+        match kind.trim() {
+            "key" => Some(HostInput::Key(name.to_string())),
+            "button" => Some(HostInput::Button(name.to_string())),
+            "axis" => {
+                let positive = name.ends_with('+');
+                if !positive && !name.ends_with('-') {
+                    return None;
+                }
+                Some(HostInput::Axis(name[..name.len() - 1].to_string(), positive))
+            }
+            _ => None,
+        }
+    }
+
+    fn to_config(&self) -> String {
+        match self {
+            HostInput::Key(name) => format!("key:{}", name),
+            HostInput::Button(name) => format!("button:{}", name),
+            HostInput::Axis(name, positive) => format!("axis:{}{}", name, if *positive { '+' } else { '-' }),
+        }
+    }
+
+    fn is_key(&self) -> bool {
+        matches!(self, HostInput::Key(_))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Action {
+    pub button: Buttons,
+    // Held down, the button is pressed and released over and over at the turbo rate
+    pub turbo: bool,
+}
+
+const fn action(button: Buttons, turbo: bool) -> Action {
+    Action { button, turbo }
+}
+
+// Everything that can be bound, by its name in the config file
+pub const ACTIONS: [(&str, Action); 10] = [
+    ("up", action(Buttons::UP, false)),
+    ("down", action(Buttons::DOWN, false)),
+    ("left", action(Buttons::LEFT, false)),
+    ("right", action(Buttons::RIGHT, false)),
+    ("select", action(Buttons::SELECT, false)),
+    ("start", action(Buttons::START, false)),
+    ("b", action(Buttons::B, false)),
+    ("a", action(Buttons::A, false)),
+    ("turbo_b", action(Buttons::B, true)),
+    ("turbo_a", action(Buttons::A, true)),
+];
+
+// Which host inputs press which buttons for which player. Saved as lines of
+// `<player>.<action> = <input>, <input>...`, eg. `1.a = key:X, button:b`, plus `turbo_rate = 15`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bindings {
+    // Player counting from 0, the action and what triggers it
+    bindings: Vec<(usize, Action, HostInput)>,
+    turbo_rate: u32,
+}
+
+impl Bindings {
+    pub fn new() -> Self {
+        Bindings {
+            bindings: Vec::new(),
+            turbo_rate: DEFAULT_TURBO_RATE,
+        }
+    }
+
+    // Player 1 on the keyboard, and a game controller for each player laid out like the NES pad:
+    // the bottom face button is B and the right one A
+    pub fn defaults() -> Self {
+        let mut bindings = Bindings::new();
+        let keys = [
+            ("up", &["Up", "W"][..]),
+            ("down", &["Down", "S"]),
+            ("left", &["Left", "A"]),
+            ("right", &["Right", "D"]),
+            ("select", &["Right Shift"]),
+            ("start", &["Return"]),
+            ("b", &["Z"]),
+            ("a", &["X"]),
+            ("turbo_b", &["C"]),
+            ("turbo_a", &["V"]),
+        ];
+        for (name, keys) in keys {
+            for key in keys {
+                bindings.add(0, find_action(name).unwrap(), HostInput::Key(key.to_string()));
+            }
+        }
+
+        let controller = [
+            ("up", vec![HostInput::Button("dpup".into()), HostInput::Axis("lefty".into(), false)]),
+            ("down", vec![HostInput::Button("dpdown".into()), HostInput::Axis("lefty".into(), true)]),
+            ("left", vec![HostInput::Button("dpleft".into()), HostInput::Axis("leftx".into(), false)]),
+            ("right", vec![HostInput::Button("dpright".into()), HostInput::Axis("leftx".into(), true)]),
+            ("select", vec![HostInput::Button("back".into())]),
+            ("start", vec![HostInput::Button("start".into())]),
+            ("b", vec![HostInput::Button("a".into())]),
+            ("a", vec![HostInput::Button("b".into())]),
+            ("turbo_b", vec![HostInput::Button("x".into())]),
+            ("turbo_a", vec![HostInput::Button("y".into())]),
+        ];
+        for player in 0..InputState::default().buttons.len() {
+            for (name, inputs) in &controller {
+                for input in inputs {
+                    bindings.add(player, find_action(name).unwrap(), input.clone());
+                }
+            }
+        }
+        bindings
+    }
+
+    pub fn parse(config: &str) -> Result<Self, String> {
+        let players = InputState::default().buttons.len();
+        let mut bindings = Bindings::new();
+
+        for (number, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: &str| format!("Line {}: {}", number + 1, message);
+
+            let (key, value) = line.split_once('=').ok_or_else(|| error("expected `name = value`"))?;
+            let (key, value) = (key.trim(), value.trim());
+            if key == "turbo_rate" {
+                bindings.turbo_rate = value
+                    .parse()
+                    .ok()
+                    .filter(|rate| (1..=MAX_TURBO_RATE).contains(rate))
+                    .ok_or_else(|| error(&format!("turbo_rate must be from 1 to {}", MAX_TURBO_RATE)))?;
+                continue;
+            }
+
+            let (player, name) = key.split_once('.').ok_or_else(|| error("expected `<player>.<button>`"))?;
+            let player = player
+                .parse::<usize>()
+                .ok()
+                .filter(|player| (1..=players).contains(player))
+                .ok_or_else(|| error(&format!("players are numbered 1 to {}", players)))?;
+            let action = find_action(name).ok_or_else(|| error(&format!("unknown button {}", name)))?;
+
+            for input in value.split(',').filter(|input| !input.trim().is_empty()) {
+                let input = HostInput::parse(input)
+                    .ok_or_else(|| error(&format!("can't read {}", input.trim())))?;
+                bindings.add(player - 1, action, input);
+            }
+        }
+
+        Ok(bindings)
+    }
+
+    pub fn to_config(&self) -> String {
+        let mut config = format!("turbo_rate = {}\n", self.turbo_rate);
+        for player in 0..InputState::default().buttons.len() {
+            for (name, action) in ACTIONS {
+                let inputs: Vec<String> = self
+                    .bindings
+                    .iter()
+                    .filter(|(p, a, _)| *p == player && *a == action)
+                    .map(|(_, _, input)| input.to_config())
+                    .collect();
+                if !inputs.is_empty() {
+                    config += &format!("{}.{} = {}\n", player + 1, name, inputs.join(", "));
+                }
+            }
+        }
+        config
+    }
+
+    pub fn turbo_rate(&self) -> u32 {
+        self.turbo_rate
+    }
+
+    pub fn set_turbo_rate(&mut self, rate: u32) {
+        self.turbo_rate = rate.clamp(1, MAX_TURBO_RATE);
+    }
+
+    pub fn add(&mut self, player: usize, action: Action, input: HostInput) {
+        self.bindings.push((player, action, input));
+    }
+
+    // Makes `input` the only keyboard or only controller binding for the action, taking it
+    // away from anything else it was bound to
+    pub fn rebind(&mut self, player: usize, action: Action, input: HostInput) {
+        self.bindings.retain(|(p, a, i)| {
+            let same_action = *p == player && *a == action && i.is_key() == input.is_key();
+            // Controller inputs are per player, so only clash with the same player
+            let same_input = *i == input && (input.is_key() || *p == player);
+            !same_action && !same_input
+        });
+        self.add(player, action, input);
+    }
+
+    // Works out the buttons each player is holding. `held` says whether a host input is down, for
+    // controller inputs on the controller assigned to the given player. `frame` drives turbo.
+    // Without `keys` only controller bindings count, for when the keyboard is playing something else
+    pub fn resolve(&self, frame: u64, keys: bool, held: impl Fn(usize, &HostInput) -> bool) -> InputState {
+        let period = (FRAMES_PER_SECOND / self.turbo_rate).max(2) as u64;
+        let turbo_on = frame % period < period / 2;
+
+        let mut state = InputState::default();
+        for (player, action, input) in &self.bindings {
+            let usable = keys || !matches!(input, HostInput::Key(_));
+            if usable && (!action.turbo || turbo_on) && held(*player, input) {
+                state.buttons[*player] |= action.button;
+            }
+        }
+        state
+    }
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self::defaults()
+    }
+}
+
+pub fn find_action(name: &str) -> Option<Action> {
+    ACTIONS.iter().find(|(n, _)| *n == name).map(|(_, action)| *action)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_config_round_trip() {
+        let config = "# comment\nturbo_rate = 10\n1.a = key:X, button:b\n2.up = axis:lefty-\n";
+        let bindings = Bindings::parse(config).unwrap();
+        assert_eq!(bindings.turbo_rate(), 10);
+        assert_eq!(Bindings::parse(&bindings.to_config()).unwrap(), bindings);
+        let defaults = Bindings::defaults().to_config();
+        assert_eq!(Bindings::parse(&defaults).unwrap().to_config(), defaults);
+
+        assert!(Bindings::parse("5.a = key:X").is_err());
+        assert!(Bindings::parse("turbo_rate = 31").is_err());
+        assert!(Bindings::parse("1.c = key:X").is_err());
+        assert!(Bindings::parse("1.a = axis:leftx").is_err());
+    }
+
+    #[test]
+    fn test_resolve_players_and_turbo() {
+        let bindings = Bindings::parse("turbo_rate = 15\n1.a = key:X\n1.turbo_b = key:C\n2.a = button:b").unwrap();
+        let held = |player: usize, input: &HostInput| match input {
+            HostInput::Key(name) => name == "X" || name == "C",
+            HostInput::Button(name) => player == 1 && name == "b",
+            _ => false,
+        };
+
+        // 15 Hz turbo is two frames pressed, two released
+        let frames: Vec<Buttons> = (0..4).map(|frame| bindings.resolve(frame, true, held).buttons[0]).collect();
+        assert_eq!(frames, [Buttons::A | Buttons::B, Buttons::A | Buttons::B, Buttons::A, Buttons::A]);
+        assert_eq!(bindings.resolve(0, true, held).buttons[1], Buttons::A);
+    }
+
+    #[test]
+    fn test_resolve_without_keys() {
+        let bindings = Bindings::defaults();
+        let held = |_, input: &HostInput| match input {
+            HostInput::Key(name) => name == "Z" || name == "X",
+            HostInput::Button(name) => name == "dpup",
+            _ => false,
+        };
+        assert_eq!(bindings.resolve(0, true, held).buttons[0], Buttons::A | Buttons::B | Buttons::UP);
+        // Typing Z and X on the Family BASIC keyboard doesn't press B and A as well
+        assert_eq!(bindings.resolve(0, false, held).buttons[0], Buttons::UP);
+    }
+
+    #[test]
+    fn test_rebind() {
+        let mut bindings = Bindings::parse("1.a = key:X, button:b\n1.b = key:Z").unwrap();
+        bindings.rebind(0, find_action("a").unwrap(), HostInput::Key("Z".into()));
+        assert_eq!(bindings.to_config(), "turbo_rate = 15\n1.a = button:b, key:Z\n");
+    }
+}
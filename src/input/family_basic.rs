@@ -0,0 +1,110 @@
+use super::{ExpansionDevice, InputState};
+use crate::savestate::Savestate;
+
+// The host key that presses each key of the Family BASIC keyboard, by row, then column, then
+// the $4017 bit it reads on from bit 4 down to bit 1. Names are SDL's. Keys the host doesn't
+// have use something nearby: STOP is End, ¥ is Backslash, KANA is Right Alt, _ is Right Ctrl,
+// GRPH is Left Alt, CLR is Home and ESC is Tab, since Escape quits
+pub const KEY_MATRIX: [[[&str; 4]; 2]; 9] = [
+    [["]", "[", "Return", "F8"], ["End", "\\", "Right Shift", "Right Alt"]],
+    [[";", "'", "`", "F7"], ["=", "-", "/", "Right Ctrl"]],
+    [["K", "L", "O", "F6"], ["0", "P", ",", "."]],
+    [["J", "U", "I", "F5"], ["8", "9", "N", "M"]],
+    [["H", "G", "Y", "F4"], ["6", "7", "V", "B"]],
+    [["D", "R", "T", "F3"], ["4", "5", "C", "F"]],
+    [["A", "S", "W", "F2"], ["3", "E", "Z", "X"]],
+    [["Left Ctrl", "Q", "Tab", "F1"], ["2", "1", "Left Alt", "Left Shift"]],
+    [["Left", "Right", "Up", "Home"], ["Insert", "Delete", "Space", "Down"]],
+];
+
+// The keyboard that came with Family BASIC, in the expansion port. Bit 2 of $4016 turns it on,
+// bit 1 picks one of the two columns of the current row, and bit 0 goes back to the first row.
+// Going from column 1 to 0 moves down a row. $4017 reads bits 4 - 1 of the selected half row,
+// 0 for pressed keys. There's nothing past the last row, which is how games find the keyboard
+pub struct FamilyBasicKeyboard {
+    keys: [[u8; 2]; 9],
+    enabled: bool,
+    row: usize,
+    column: usize,
+}
+
+impl FamilyBasicKeyboard {
+    pub fn new() -> Self {
+        FamilyBasicKeyboard {
+            keys: [[0; 2]; 9],
+            enabled: false,
+            row: 0,
+            column: 0,
+        }
+    }
+}
+
+impl Default for FamilyBasicKeyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExpansionDevice for FamilyBasicKeyboard {
+    fn write(&mut self, data: u8) {
+        let column = ((data >> 1) & 1) as usize;
+        self.enabled = data & 0b100 != 0;
+        if self.enabled {
+            if self.column == 1 && column == 0 {
+                self.row = (self.row + 1).min(self.keys.len());
+            }
+            if data & 1 != 0 {
+                self.row = 0;
+            }
+        }
+        self.column = column;
+    }
+
+    fn read(&mut self, port: usize) -> u8 {
+        if port == 0 || !self.enabled {
+            return 0;
+        }
+        let pressed = self.keys.get(self.row).map_or(0, |row| row[self.column]);
+        !pressed & 0b1_1110
+    }
+
+    fn update(&mut self, input: &InputState) {
+        self.keys = input.keyboard;
+    }
+}
+
+impl Savestate for FamilyBasicKeyboard {
+    crate::savestate_fields!(keys, enabled, row, column);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scans_rows_and_columns() {
+        // RETURN is row 0, column 0, bit 2 and SPACE row 8, column 1, bit 2
+        let mut keyboard = FamilyBasicKeyboard::new();
+        let mut input = InputState::default();
+        input.keyboard[0][0] = 0b0_0100;
+        input.keyboard[8][1] = 0b0_0100;
+        keyboard.update(&input);
+
+        keyboard.write(0b101);
+        assert_eq!(keyboard.read(1), 0b1_1010);
+        keyboard.write(0b110);
+        assert_eq!(keyboard.read(1), 0b1_1110);
+
+        // Down to row 8, column 1, then off the end
+        for _ in 0..8 {
+            keyboard.write(0b100);
+            keyboard.write(0b110);
+        }
+        assert_eq!(keyboard.read(1), 0b1_1010);
+        keyboard.write(0b100);
+        assert_eq!(keyboard.read(1), 0b1_1110);
+
+        keyboard.write(0b000);
+        assert_eq!(keyboard.read(1), 0);
+    }
+}
@@ -0,0 +1,103 @@
+use super::{Buttons, ControllerDevice, InputState};
+use crate::savestate::Savestate;
+
+// The standard controller: a 4021 shift register that latches the buttons while the strobe is
+// high and then shifts them out one per read, A first. Once all 8 are out it reads 1s.
+pub struct Joypad {
+    player: usize,
+    buttons: Buttons,
+    strobe: bool,
+    shift: u8,
+    // Bits left in the shift register, the rest have been filled with 1s
+    remaining: u8,
+}
+
+impl Joypad {
+    // `player` picks which of the input state's button sets this joypad follows
+    pub fn new(player: usize) -> Self {
+        Joypad {
+            player,
+            buttons: Buttons::empty(),
+            strobe: false,
+            shift: 0,
+            remaining: 0,
+        }
+    }
+
+    fn latch(&mut self) {
+        self.shift = self.buttons.bits();
+        self.remaining = 8;
+    }
+}
+
+impl ControllerDevice for Joypad {
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 != 0;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        // While the strobe is held the register keeps reloading, so reads only ever see A
+        if self.strobe {
+            return self.buttons.contains(Buttons::A) as u8;
+        }
+        if self.remaining == 0 {
+            return 1;
+        }
+        let bit = self.shift & 1;
+        self.shift >>= 1;
+        self.remaining -= 1;
+        bit
+    }
+
+    fn update(&mut self, input: &InputState) {
+        self.buttons = input.buttons[self.player];
+        if self.strobe {
+            self.latch();
+        }
+    }
+}
+
+impl Savestate for Joypad {
+    crate::savestate_fields!(buttons, strobe, shift, remaining);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_all(joypad: &mut Joypad, reads: usize) -> Vec<u8> {
+        (0..reads).map(|_| joypad.read()).collect()
+    }
+
+    #[test]
+    fn test_shifts_out_buttons_in_order() {
+        let mut joypad = Joypad::new(0);
+        let mut input = InputState::default();
+        input.buttons[0] = Buttons::A | Buttons::START | Buttons::RIGHT;
+        joypad.update(&input);
+
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(read_all(&mut joypad, 10), [1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_strobe_held_reads_a() {
+        let mut joypad = Joypad::new(1);
+        let mut input = InputState::default();
+        input.buttons[1] = Buttons::A | Buttons::B;
+        joypad.update(&input);
+
+        joypad.write(1);
+        assert_eq!(read_all(&mut joypad, 3), [1, 1, 1]);
+
+        // Buttons change while strobing are picked up straight away
+        input.buttons[1] = Buttons::B;
+        joypad.update(&input);
+        joypad.write(0);
+        assert_eq!(read_all(&mut joypad, 2), [0, 1]);
+    }
+}
@@ -0,0 +1,304 @@
+pub mod arkanoid;
+pub mod bindings;
+pub mod family_basic;
+pub mod joypad;
+pub mod multitap;
+pub mod power_pad;
+pub mod zapper;
+
+use arkanoid::{FamicomVaus, Vaus};
+use bitflags::bitflags;
+use crate::savestate::Savestate;
+use family_basic::FamilyBasicKeyboard;
+use joypad::Joypad;
+use multitap::{FamicomFourPlayer, FourScore};
+use power_pad::{FamilyTrainer, MatSide, PowerPad};
+use zapper::Zapper;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+// The upper bits of a $4016 / $4017 read aren't driven by anything, so they keep the last value
+// on the data bus. That's almost always the $40 from the address of the read itself
+const OPEN_BUS: u8 = 0x40;
+
+bitflags! {
+    // In the order a joypad shifts them out
+    #[derive(Default)]
+    pub struct Buttons: u8 {
+        const A      = 0b00000001;
+        const B      = 0b00000010;
+        const SELECT = 0b00000100;
+        const START  = 0b00001000;
+        const UP     = 0b00010000;
+        const DOWN   = 0b00100000;
+        const LEFT   = 0b01000000;
+        const RIGHT  = 0b10000000;
+    }
+}
+
+// What the players are holding, however the frontend found out. Devices pick out their part
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InputState {
+    // Players 3 and 4 need a four player adapter to be plugged in
+    pub buttons: [Buttons; 4],
+    // The Zapper and the Arkanoid paddle
+    pub pointer: PointerState,
+    // Power Pad and Family Trainer mat, bit 4 * row + column for each of the 3 x 4 pads, as the
+    // player sees them from whichever side is up
+    pub mat: u16,
+    // Family BASIC keyboard keys held, by row and column, on the bits they are read from $4017.
+    // See `family_basic::KEY_MATRIX`
+    pub keyboard: [[u8; 2]; 9],
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PointerState {
+    // In picture pixels, None when pointed away from the screen
+    pub position: Option<(u16, u16)>,
+    pub button: bool,
+}
+
+impl Savestate for Buttons {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.bits().save(out);
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        let mut bits = 0u8;
+        bits.load(data)?;
+        *self = Buttons::from_bits_truncate(bits);
+        Ok(())
+    }
+}
+
+// Something plugged into one of the controller ports
+pub trait ControllerDevice: Savestate {
+    // $4016 writes, which go to both ports. Bit 0 is the strobe that latches joypad state
+    fn write(&mut self, data: u8);
+    // The low 5 bits of a read from the device's port
+    fn read(&mut self) -> u8;
+    fn update(&mut self, input: &InputState);
+}
+
+// Something in the Famicom's expansion port. It sees all three output bits of $4016 writes,
+// and can drive bits 1 - 4 of both $4016 and $4017 reads
+pub trait ExpansionDevice: Savestate {
+    fn write(&mut self, data: u8);
+    // Bits 1 - 4 of a read from $4016 (port 0) or $4017 (port 1)
+    fn read(&mut self, port: usize) -> u8;
+    fn update(&mut self, input: &InputState);
+}
+
+// The input hardware a game is played with, for the frontend to pick from or a NES 2.0 header
+// to suggest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peripherals {
+    Joypads,
+    FourScore,
+    FamicomFourPlayer,
+    Zapper,
+    PowerPad(MatSide),
+    FamilyTrainer(MatSide),
+    ArkanoidNes,
+    ArkanoidFamicom,
+    FamilyBasicKeyboard,
+}
+
+impl Peripherals {
+    // From the default expansion device in byte 15 of a NES 2.0 header. None for the devices
+    // we don't have, and when the header doesn't say
+    pub fn from_nes2(device: u8) -> Option<Self> {
+        match device {
+            0x01 => Some(Peripherals::Joypads),
+            0x02 => Some(Peripherals::FourScore),
+            0x03 => Some(Peripherals::FamicomFourPlayer),
+            0x08 => Some(Peripherals::Zapper),
+            0x0B => Some(Peripherals::PowerPad(MatSide::A)),
+            0x0C => Some(Peripherals::PowerPad(MatSide::B)),
+            0x0D => Some(Peripherals::FamilyTrainer(MatSide::A)),
+            0x0E => Some(Peripherals::FamilyTrainer(MatSide::B)),
+            0x0F => Some(Peripherals::ArkanoidNes),
+            0x10 => Some(Peripherals::ArkanoidFamicom),
+            0x23 => Some(Peripherals::FamilyBasicKeyboard),
+            _ => None,
+        }
+    }
+
+    // The other way round, for save states to record what was connected
+    pub fn nes2_device(self) -> u8 {
+        match self {
+            Peripherals::Joypads => 0x01,
+            Peripherals::FourScore => 0x02,
+            Peripherals::FamicomFourPlayer => 0x03,
+            Peripherals::Zapper => 0x08,
+            Peripherals::PowerPad(MatSide::A) => 0x0B,
+            Peripherals::PowerPad(MatSide::B) => 0x0C,
+            Peripherals::FamilyTrainer(MatSide::A) => 0x0D,
+            Peripherals::FamilyTrainer(MatSide::B) => 0x0E,
+            Peripherals::ArkanoidNes => 0x0F,
+            Peripherals::ArkanoidFamicom => 0x10,
+            Peripherals::FamilyBasicKeyboard => 0x23,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "joypads" => Some(Peripherals::Joypads),
+            "four-score" => Some(Peripherals::FourScore),
+            "famicom-four-player" => Some(Peripherals::FamicomFourPlayer),
+            "zapper" => Some(Peripherals::Zapper),
+            "power-pad-a" => Some(Peripherals::PowerPad(MatSide::A)),
+            "power-pad" | "power-pad-b" => Some(Peripherals::PowerPad(MatSide::B)),
+            "family-trainer-a" => Some(Peripherals::FamilyTrainer(MatSide::A)),
+            "family-trainer" | "family-trainer-b" => Some(Peripherals::FamilyTrainer(MatSide::B)),
+            "arkanoid" => Some(Peripherals::ArkanoidNes),
+            "arkanoid-famicom" => Some(Peripherals::ArkanoidFamicom),
+            "family-basic-keyboard" => Some(Peripherals::FamilyBasicKeyboard),
+            _ => None,
+        }
+    }
+
+    // What `from_name` takes
+    pub fn name(self) -> &'static str {
+        match self {
+            Peripherals::Joypads => "joypads",
+            Peripherals::FourScore => "four-score",
+            Peripherals::FamicomFourPlayer => "famicom-four-player",
+            Peripherals::Zapper => "zapper",
+            Peripherals::PowerPad(MatSide::A) => "power-pad-a",
+            Peripherals::PowerPad(MatSide::B) => "power-pad-b",
+            Peripherals::FamilyTrainer(MatSide::A) => "family-trainer-a",
+            Peripherals::FamilyTrainer(MatSide::B) => "family-trainer-b",
+            Peripherals::ArkanoidNes => "arkanoid",
+            Peripherals::ArkanoidFamicom => "arkanoid-famicom",
+            Peripherals::FamilyBasicKeyboard => "family-basic-keyboard",
+        }
+    }
+}
+
+impl Savestate for Peripherals {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.nes2_device().save(out);
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        let mut device = 0u8;
+        device.load(data)?;
+        *self = Peripherals::from_nes2(device).ok_or_else(|| format!("Save state has unknown input device {:02X}", device))?;
+        Ok(())
+    }
+}
+
+// $4016 and $4017, joypads in both unless something else is plugged in, and on a Famicom
+// whatever is in the expansion port
+pub struct ControllerPorts {
+    devices: [Box<dyn ControllerDevice>; 2],
+    expansion: Option<Box<dyn ExpansionDevice>>,
+    // What was last connected, devices plugged in by hand aren't tracked
+    peripherals: Peripherals,
+}
+
+impl ControllerPorts {
+    pub fn new() -> Self {
+        ControllerPorts {
+            devices: [Box::new(Joypad::new(0)), Box::new(Joypad::new(1))],
+            expansion: None,
+            peripherals: Peripherals::Joypads,
+        }
+    }
+
+    pub fn plug(&mut self, port: usize, device: Box<dyn ControllerDevice>) {
+        self.devices[port] = device;
+    }
+
+    pub fn plug_expansion(&mut self, device: Option<Box<dyn ExpansionDevice>>) {
+        self.expansion = device;
+    }
+
+    // Unplugs everything, then puts the joypads back along with the chosen hardware
+    pub fn connect(&mut self, peripherals: Peripherals) {
+        *self = ControllerPorts::new();
+        self.peripherals = peripherals;
+        match peripherals {
+            Peripherals::Joypads => {}
+            Peripherals::FourScore => {
+                self.plug(0, Box::new(FourScore::new(0)));
+                self.plug(1, Box::new(FourScore::new(1)));
+            }
+            Peripherals::FamicomFourPlayer => self.plug_expansion(Some(Box::new(FamicomFourPlayer::new()))),
+            Peripherals::Zapper => self.plug(1, Box::new(Zapper::new())),
+            Peripherals::PowerPad(side) => self.plug(1, Box::new(PowerPad::new(side))),
+            Peripherals::FamilyTrainer(side) => self.plug_expansion(Some(Box::new(FamilyTrainer::new(side)))),
+            Peripherals::ArkanoidNes => self.plug(1, Box::new(Vaus::new())),
+            Peripherals::ArkanoidFamicom => self.plug_expansion(Some(Box::new(FamicomVaus::new()))),
+            Peripherals::FamilyBasicKeyboard => self.plug_expansion(Some(Box::new(FamilyBasicKeyboard::new()))),
+        }
+    }
+
+    pub fn peripherals(&self) -> Peripherals {
+        self.peripherals
+    }
+
+    pub fn update(&mut self, input: &InputState) {
+        for device in self.devices.iter_mut() {
+            device.update(input);
+        }
+        if let Some(expansion) = self.expansion.as_mut() {
+            expansion.update(input);
+        }
+    }
+
+    pub fn write(&mut self, data: u8) {
+        for device in self.devices.iter_mut() {
+            device.write(data);
+        }
+        if let Some(expansion) = self.expansion.as_mut() {
+            expansion.write(data);
+        }
+    }
+
+    pub fn read(&mut self, port: usize) -> u8 {
+        let expansion = self.expansion.as_mut().map_or(0, |expansion| expansion.read(port));
+        OPEN_BUS | (self.devices[port].read() & 0b1_1111) | (expansion & 0b1_1110)
+    }
+}
+
+// Save states keep what the devices have latched, so the same ones need to be plugged in
+impl Savestate for ControllerPorts {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.peripherals.save(out);
+        for device in self.devices.iter() {
+            device.save(out);
+        }
+        self.expansion.is_some().save(out);
+        if let Some(expansion) = self.expansion.as_ref() {
+            expansion.save(out);
+        }
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        let mut saved = Peripherals::Joypads;
+        saved.load(data)?;
+        if saved != self.peripherals {
+            return Err(format!("Save state was made with {} connected, not {}", saved.name(), self.peripherals.name()));
+        }
+        for device in self.devices.iter_mut() {
+            device.load(data)?;
+        }
+        let mut has_expansion = false;
+        has_expansion.load(data)?;
+        match (has_expansion, self.expansion.as_mut()) {
+            (true, Some(expansion)) => expansion.load(data),
+            (false, None) => Ok(()),
+            _ => Err("Save state was made with a different expansion port device".to_string()),
+        }
+    }
+}
+
+impl Default for ControllerPorts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
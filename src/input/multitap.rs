@@ -0,0 +1,163 @@
+use super::joypad::Joypad;
+use super::{Buttons, ControllerDevice, ExpansionDevice, InputState};
+use crate::savestate::Savestate;
+
+// Reads 17 - 24 of each port identify the Four Score, read first bit first
+const FOUR_SCORE_SIGNATURES: [u8; 2] = [0b0000_1000, 0b0000_0100];
+
+// The NES Four Score, which takes up both ports. Each port shifts out 24 bits: its first
+// player's buttons, then the player plugged in beside them (3 for port 1, 4 for port 2), then a
+// signature games check for before they trust the extra players. After that it reads 1s.
+pub struct FourScore {
+    port: usize,
+    players: [Buttons; 2],
+    strobe: bool,
+    shift: u32,
+    remaining: u8,
+}
+
+impl FourScore {
+    // One of these goes in each port
+    pub fn new(port: usize) -> Self {
+        FourScore {
+            port,
+            players: [Buttons::empty(); 2],
+            strobe: false,
+            shift: 0,
+            remaining: 0,
+        }
+    }
+
+    fn latch(&mut self) {
+        self.shift = self.players[0].bits() as u32
+            | (self.players[1].bits() as u32) << 8
+            | (FOUR_SCORE_SIGNATURES[self.port] as u32) << 16;
+        self.remaining = 24;
+    }
+}
+
+impl ControllerDevice for FourScore {
+    fn write(&mut self, data: u8) {
+        self.strobe = data & 1 != 0;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.players[0].contains(Buttons::A) as u8;
+        }
+        if self.remaining == 0 {
+            return 1;
+        }
+        let bit = (self.shift & 1) as u8;
+        self.shift >>= 1;
+        self.remaining -= 1;
+        bit
+    }
+
+    fn update(&mut self, input: &InputState) {
+        self.players = [input.buttons[self.port], input.buttons[self.port + 2]];
+        if self.strobe {
+            self.latch();
+        }
+    }
+}
+
+// The Famicom way of getting four players: joypads in the expansion port show up on bit 1 of
+// $4016 and $4017, next to the built in controllers on bit 0.
+pub struct FamicomFourPlayer {
+    joypads: [Joypad; 2],
+}
+
+impl FamicomFourPlayer {
+    pub fn new() -> Self {
+        FamicomFourPlayer {
+            joypads: [Joypad::new(2), Joypad::new(3)],
+        }
+    }
+}
+
+impl Default for FamicomFourPlayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExpansionDevice for FamicomFourPlayer {
+    fn write(&mut self, data: u8) {
+        for joypad in self.joypads.iter_mut() {
+            joypad.write(data);
+        }
+    }
+
+    fn read(&mut self, port: usize) -> u8 {
+        self.joypads[port].read() << 1
+    }
+
+    fn update(&mut self, input: &InputState) {
+        for joypad in self.joypads.iter_mut() {
+            joypad.update(input);
+        }
+    }
+}
+
+impl Savestate for FourScore {
+    crate::savestate_fields!(players, strobe, shift, remaining);
+}
+
+impl Savestate for FamicomFourPlayer {
+    crate::savestate_fields!(joypads);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn input() -> InputState {
+        InputState {
+            buttons: [Buttons::A, Buttons::B, Buttons::START, Buttons::RIGHT],
+            ..Default::default()
+        }
+    }
+
+    fn read_all(device: &mut dyn ControllerDevice, reads: usize) -> Vec<u8> {
+        (0..reads).map(|_| device.read()).collect()
+    }
+
+    #[test]
+    fn test_four_score_reports() {
+        let mut ports = [FourScore::new(0), FourScore::new(1)];
+        for port in ports.iter_mut() {
+            port.update(&input());
+            port.write(1);
+            port.write(0);
+        }
+
+        let reads = read_all(&mut ports[0], 26);
+        assert_eq!(reads[0..8], [1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(reads[8..16], [0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(reads[16..24], [0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(reads[24..], [1, 1]);
+
+        let reads = read_all(&mut ports[1], 24);
+        assert_eq!(reads[0..8], [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(reads[8..16], [0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(reads[16..24], [0, 0, 1, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_famicom_expansion_players() {
+        let mut expansion = FamicomFourPlayer::new();
+        expansion.update(&input());
+        expansion.write(1);
+        expansion.write(0);
+
+        // Player 3's Start and player 4's Right, on bit 1
+        let reads: Vec<u8> = (0..8).map(|_| expansion.read(0)).collect();
+        assert_eq!(reads, [0, 0, 0, 0b10, 0, 0, 0, 0]);
+        let reads: Vec<u8> = (0..8).map(|_| expansion.read(1)).collect();
+        assert_eq!(reads, [0, 0, 0, 0, 0, 0, 0, 0b10]);
+    }
+}
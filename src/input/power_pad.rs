@@ -0,0 +1,162 @@
+use super::{ControllerDevice, ExpansionDevice, InputState};
+use crate::savestate::Savestate;
+
+// The mat's two sides. Side B has all 12 pads numbered left to right, top to bottom. Side A is
+// the back of the same mat, so the pads are mirrored left to right
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatSide {
+    A,
+    B,
+}
+
+// Turns the pads the player is standing on, as they see them, into pad numbers 1 - 12 as
+// bits 0 - 11
+fn pads_pressed(mat: u16, side: MatSide) -> u16 {
+    match side {
+        MatSide::B => mat & 0x0FFF,
+        MatSide::A => (0..12)
+            .filter(|position| mat & (1 << position) != 0)
+            .map(|position| 1 << (position / 4 * 4 + 3 - position % 4))
+            .fold(0, |pads, pad| pads | pad),
+    }
+}
+
+fn pad(pads: u16, number: u16) -> u8 {
+    ((pads >> (number - 1)) & 1) as u8
+}
+
+// The NES Power Pad, in port 2. The strobe latches all 12 pads, which then shift out 8 on
+// bit 3 and 4 on bit 4, in a wiring order, followed by 1s.
+pub struct PowerPad {
+    side: MatSide,
+    pads: u16,
+    low: u8,
+    high: u8,
+}
+
+impl PowerPad {
+    pub fn new(side: MatSide) -> Self {
+        PowerPad { side, pads: 0, low: 0xFF, high: 0xFF }
+    }
+}
+
+impl ControllerDevice for PowerPad {
+    fn write(&mut self, data: u8) {
+        if data & 1 == 0 {
+            return;
+        }
+        let pads = self.pads;
+        self.low = [2, 1, 5, 9, 6, 10, 11, 7]
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, &number)| bits | pad(pads, number) << i);
+        self.high = [4, 3, 12, 8]
+            .iter()
+            .enumerate()
+            .fold(0xF0, |bits, (i, &number)| bits | pad(pads, number) << i);
+    }
+
+    fn read(&mut self) -> u8 {
+        let bits = (self.low & 1) << 3 | (self.high & 1) << 4;
+        self.low = self.low >> 1 | 0x80;
+        self.high = self.high >> 1 | 0x80;
+        bits
+    }
+
+    fn update(&mut self, input: &InputState) {
+        self.pads = pads_pressed(input.mat, self.side);
+    }
+}
+
+// Bandai's Famicom version of the mat, in the expansion port. The low 3 bits of $4016 pick
+// rows, active low: bit 2 for pads 1 - 4, bit 1 for 5 - 8 and bit 0 for 9 - 12. $4017 bits 4
+// down to 1 then read the selected rows' four columns, 0 when pressed.
+pub struct FamilyTrainer {
+    side: MatSide,
+    pads: u16,
+    rows: u8,
+}
+
+impl FamilyTrainer {
+    pub fn new(side: MatSide) -> Self {
+        FamilyTrainer { side, pads: 0, rows: 0b111 }
+    }
+}
+
+impl ExpansionDevice for FamilyTrainer {
+    fn write(&mut self, data: u8) {
+        self.rows = data & 0b111;
+    }
+
+    fn read(&mut self, port: usize) -> u8 {
+        if port == 0 {
+            return 0;
+        }
+
+        let mut columns = 0;
+        for row in 0..3 {
+            if self.rows & (0b100 >> row) == 0 {
+                columns |= (self.pads >> (row * 4)) & 0b1111;
+            }
+        }
+        // Pad 1 of a row is on bit 4, pad 4 on bit 1
+        let pressed = (0..4).fold(0, |bits, column| bits | ((columns >> column) & 1) << (4 - column));
+        !pressed as u8 & 0b1_1110
+    }
+
+    fn update(&mut self, input: &InputState) {
+        self.pads = pads_pressed(input.mat, self.side);
+    }
+}
+
+impl Savestate for PowerPad {
+    crate::savestate_fields!(pads, low, high);
+}
+
+impl Savestate for FamilyTrainer {
+    crate::savestate_fields!(pads, rows);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn standing_on(positions: &[u16]) -> InputState {
+        InputState {
+            mat: positions.iter().fold(0, |mat, position| mat | 1 << position),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_power_pad_serial_order() {
+        // Pads 1 and 12 on side B
+        let mut mat = PowerPad::new(MatSide::B);
+        mat.update(&standing_on(&[0, 11]));
+        mat.write(1);
+        mat.write(0);
+
+        let reads: Vec<u8> = (0..9).map(|_| mat.read()).collect();
+        assert_eq!(reads, [0, 0x08, 0x10, 0, 0x10, 0x10, 0x10, 0x10, 0x18]);
+
+        // Top left on side A is pad 4
+        let mut mat = PowerPad::new(MatSide::A);
+        mat.update(&standing_on(&[0]));
+        mat.write(1);
+        assert_eq!(mat.read(), 0x10);
+    }
+
+    #[test]
+    fn test_family_trainer_rows() {
+        // Pads 2 and 7
+        let mut mat = FamilyTrainer::new(MatSide::B);
+        mat.update(&standing_on(&[1, 6]));
+
+        mat.write(0b011);
+        assert_eq!(mat.read(1), 0b1_0110);
+        mat.write(0b101);
+        assert_eq!(mat.read(1), 0b1_1010);
+        mat.write(0b110);
+        assert_eq!(mat.read(1), 0b1_1110);
+    }
+}
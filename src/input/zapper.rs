@@ -0,0 +1,61 @@
+use super::{ControllerDevice, InputState};
+use crate::savestate::Savestate;
+
+// The NES picture, which pointer positions are in
+pub const FRAME_WIDTH: usize = 256;
+pub const FRAME_HEIGHT: usize = 240;
+
+// The light gun, read through bits 3 (0 while light is sensed) and 4 (1 while the trigger is
+// pulled) of its port. Only the trigger works for now: sensing light needs a PPU drawing real
+// frames and a beam position to compare the aim against, so the sensor always reports dark and
+// games never register a hit.
+pub struct Zapper {
+    trigger: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Zapper { trigger: false }
+    }
+}
+
+impl Default for Zapper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ControllerDevice for Zapper {
+    fn write(&mut self, _data: u8) {}
+
+    fn read(&mut self) -> u8 {
+        let trigger = if self.trigger { 0b1_0000 } else { 0 };
+        0b0_1000 | trigger
+    }
+
+    fn update(&mut self, input: &InputState) {
+        self.trigger = input.pointer.button;
+    }
+}
+
+impl Savestate for Zapper {
+    crate::savestate_fields!(trigger);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::PointerState;
+
+    #[test]
+    fn test_trigger_without_light() {
+        let mut zapper = Zapper::new();
+        assert_eq!(zapper.read(), 0b0_1000);
+
+        zapper.update(&InputState {
+            pointer: PointerState { position: Some((100, 50)), button: true },
+            ..Default::default()
+        });
+        assert_eq!(zapper.read(), 0b1_1000);
+    }
+}
@@ -0,0 +1,29 @@
+// The emulator itself, with no frontend. The SDL player and the headless runner are both built
+// on top of this. Only the `std` feature brings in the standard library, for saving files and
+// the like, the core needs nothing more than an allocator
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+// Public for `savestate_fields!`, which expands to functions taking Vec and String
+#[doc(hidden)]
+pub extern crate alloc;
+
+pub mod apu;
+#[cfg(feature = "std")]
+pub mod battery;
+pub mod bus;
+pub mod cartridge;
+pub mod cpu;
+pub mod input;
+pub mod movie;
+pub mod mapper;
+mod math;
+pub mod nametables;
+pub mod nes;
+pub mod nsf;
+pub mod opcodes;
+pub mod rewind;
+pub mod savestate;
+#[cfg(feature = "std")]
+pub mod wav;
+
+pub use crate::nes::{AudioSample, FrameBuffer, Nes};
@@ -1,84 +1,589 @@
-pub mod cpu;
-pub mod opcodes;
+mod controls;
 
-use cpu::CPU;
-use cpu::memory::Memory;
+use controls::Controls;
+use nes::apu;
+use nes::battery::BatterySave;
+use nes::cartridge::Rom;
+use nes::cpu::CPU;
+use nes::input::{InputState, Peripherals};
+use nes::movie::{self, Movie, MovieSession};
+use nes::nes::{AUDIO_SAMPLE_RATE, CYCLES_PER_FRAME, SNAKE_GAME};
+use nes::nsf::{Nsf, NsfPlayer};
+use nes::rewind::Rewind;
+use nes::savestate::SaveSlots;
+use nes::wav::WavRecorder;
+use nes::Nes;
 
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::event::Event;
 use sdl2::EventPump;
 use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
-use rand::Rng;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[macro_use]
-extern crate lazy_static;
+// Frames of audio we try to keep queued, enough to ride out a late frame
+const AUDIO_TARGET_FRAMES: f64 = 3.0;
+// The most the resampling rate gets nudged either way, too little to hear as a pitch change
+const MAX_RATE_ADJUST: f64 = 0.005;
+// How much of an NSF tune --render-wav writes unless --seconds says otherwise
+const DEFAULT_RENDER_SECONDS: f64 = 150.0;
+const DEFAULT_BINDINGS_PATH: &str = "bindings.cfg";
+// Frames between rewind snapshots, and the memory they can take up. Snapshots are a few hundred
+// bytes for most frames, so this is minutes of rewind
+const REWIND_INTERVAL: u32 = 1;
+const REWIND_MAX_BYTES: usize = 32 * 1024 * 1024;
 
-fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
+struct AudioOutput {
+    queue: AudioQueue<i16>,
+    samples_per_frame: f64,
+}
+
+impl AudioOutput {
+    fn queued_frames(&self) -> f64 {
+        let samples = self.queue.size() as usize / std::mem::size_of::<i16>();
+        samples as f64 / self.samples_per_frame
+    }
+
+    fn rate_adjust(&self) -> f64 {
+        rate_adjust(self.queued_frames())
+    }
+
+    fn queue(&mut self, samples: &[i16]) {
+        if let Err(e) = self.queue.queue_audio(samples) {
+            eprintln!("Couldn't queue audio: {}", e);
+        }
+
+        // Rate control only covers small drift. If we are well ahead of the sound card, wait
+        // for it rather than let the latency grow
+        while self.queued_frames() > 2.0 * AUDIO_TARGET_FRAMES {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+// Makes slightly more samples when the queue is running low and fewer when it is filling up
+fn rate_adjust(queued_frames: f64) -> f64 {
+    let error = (AUDIO_TARGET_FRAMES - queued_frames) / AUDIO_TARGET_FRAMES;
+    1.0 + (error * MAX_RATE_ADJUST).clamp(-MAX_RATE_ADJUST, MAX_RATE_ADJUST)
+}
+
+// Sound is optional, carry on silently if there is no audio device
+fn open_audio(sdl_context: &sdl2::Sdl, cpu: &mut CPU) -> Option<AudioOutput> {
+    let desired = AudioSpecDesired {
+        freq: Some(AUDIO_SAMPLE_RATE as i32),
+        channels: Some(1),
+        samples: Some(1024),
+    };
+
+    let queue = sdl_context
+        .audio()
+        .and_then(|audio| audio.open_queue::<i16, _>(None, &desired));
+    let queue = match queue {
+        Ok(queue) => queue,
+        Err(e) => {
+            eprintln!("Couldn't open audio: {}", e);
+            return None;
+        }
+    };
+
+    // The device may not give us the rate we asked for
+    let sample_rate = queue.spec().freq as u32;
+    cpu.bus.apu().set_sample_rate(sample_rate);
+    queue.resume();
+
+    Some(AudioOutput {
+        queue,
+        samples_per_frame: sample_rate as f64 * CYCLES_PER_FRAME as f64 / apu::pipeline::CPU_CLOCK_RATE,
+    })
+}
+
+// Resamples a little faster or slower to keep the sound card's queue where we want it. Channels
+// being recorded on their own follow along so they stay in step with the mix
+fn adjust_audio_rate(cpu: &mut CPU, audio: &Option<AudioOutput>) {
+    let adjust = audio.as_ref().map_or(1.0, |audio| audio.rate_adjust());
+    let apu = cpu.bus.apu();
+    if let Some(pipeline) = apu.audio() {
+        pipeline.set_rate_adjust(adjust);
+    }
+    for pipeline in apu.channel_audio() {
+        pipeline.set_rate_adjust(adjust);
+    }
+}
+
+// The samples made since the last call, for the NSF player which runs the CPU itself
+fn take_audio(cpu: &mut CPU) -> Vec<i16> {
+    cpu.bus.apu().audio().map_or(Vec::new(), |pipeline| pipeline.end_frame_i16().to_vec())
+}
+
+// Hands the frame's samples to the sound card and whatever is recording them. Muted frames are
+// silence for the sound card, which still paces us, and aren't recorded
+fn end_audio_frame(
+    cpu: &mut CPU,
+    samples: &[i16],
+    audio: &mut Option<AudioOutput>,
+    recorder: &mut Option<WavRecorder>,
+    muted: bool,
+) {
+    if muted {
+        if let Some(audio) = audio.as_mut() {
+            audio.queue(&vec![0; samples.len()]);
+        }
+        return;
+    }
+    if let Some(audio) = audio.as_mut() {
+        audio.queue(samples);
+    }
+    if let Some(rec) = recorder.as_mut() {
+        if let Err(e) = rec.write_frame(samples, cpu.bus.apu()) {
+            eprintln!("Couldn't write {}: {}", rec.path().display(), e);
+            *recorder = None;
+        }
+    }
+}
+
+fn start_recording(cpu: &mut CPU, path: &Path, per_channel: bool) -> Option<WavRecorder> {
+    match WavRecorder::start(cpu.bus.apu(), path, per_channel) {
+        Ok(recorder) => {
+            println!("Recording audio to {}", path.display());
+            Some(recorder)
+        }
+        Err(e) => {
+            eprintln!("Couldn't record to {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+fn stop_recording(cpu: &mut CPU, recorder: &mut Option<WavRecorder>) {
+    if let Some(rec) = recorder.take() {
+        let path = rec.path().to_path_buf();
+        match rec.finish(cpu.bus.apu()) {
+            Ok(()) => println!("Saved {}", path.display()),
+            Err(e) => eprintln!("Couldn't finish {}: {}", path.display(), e),
+        }
+    }
+}
+
+fn start_movie_playback(
+    cpu: &mut CPU,
+    movie: Movie,
+    path: &Path,
+    rom_checksum: &[u8; 16],
+) -> Result<MovieSession, String> {
+    if movie.rom_checksum != *rom_checksum {
+        eprintln!(
+            "{} was recorded with a different ROM ({}), it may not play back right",
+            path.display(),
+            movie.rom_filename
+        );
+    }
+    let session = MovieSession::start_playback(cpu, movie, path)?;
+    println!("Playing {}", path.display());
+    Ok(session)
+}
+
+fn stop_movie(movie: &mut Option<MovieSession>) {
+    if let Some(session) = movie.take() {
+        let path = session.path().to_path_buf();
+        let playing = session.is_playing();
+        match session.finish() {
+            Ok(()) if playing => println!("Stopped playing {}", path.display()),
+            Ok(()) => println!("Saved {}", path.display()),
+            Err(e) => eprintln!("Couldn't save {}: {}", path.display(), e),
+        }
+    }
+}
+
+// Where the hotkeys record to, named so that repeated recordings don't overwrite each other
+fn hotkey_path(name: &str, extension: &str) -> PathBuf {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    PathBuf::from(format!("{}-{}.{}", name, secs, extension))
+}
+
+enum UserAction {
+    None,
+    Quit,
+    ToggleRecording,
+    // Skip forwards or backwards through an NSF's tracks
+    ChangeTrack(i16),
+    SaveState,
+    LoadState,
+    NextSlot,
+    // Holding the rewind key steps back a frame at a time
+    StartRewind,
+    StopRewind,
+    ToggleMovie,
+}
+
+// The Family BASIC keyboard has F1 - F8 keys, while it's connected they're only for it
+fn handle_user_input(event_pump: &mut EventPump, controls: &mut Controls, family_basic: bool) -> UserAction {
     for event in event_pump.poll_iter() {
-        // Checking for a keydown event
-        // If the keycode matches do the specified logic
-        // 0xff is where the last user input is stored
+        if controls.handle_event(&event) {
+            continue;
+        }
+        let function_key = matches!(
+            event,
+            Event::KeyDown {
+                keycode: Some(
+                    Keycode::F2 | Keycode::F3 | Keycode::F4 | Keycode::F5 | Keycode::F6 | Keycode::F7 | Keycode::F8
+                ),
+                ..
+            }
+        );
+        if family_basic && function_key {
+            continue;
+        }
         match event {
             Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                std::process::exit(0);
-            },
-            Event::KeyDown { keycode: Some(Keycode::W), .. } => {
-                cpu.mem_write(0xff, 0x77);
+                return UserAction::Quit;
             },
-            Event::KeyDown { keycode: Some(Keycode::A), .. } => {
-                cpu.mem_write(0xff, 0x61);
+            Event::KeyDown { keycode: Some(Keycode::F9), repeat: false, .. } => {
+                return UserAction::ToggleRecording;
             },
-            Event::KeyDown { keycode: Some(Keycode::S), .. } => {
-                cpu.mem_write(0xff, 0x73);
-            },
-            Event::KeyDown { keycode: Some(Keycode::D), .. } => {
-                cpu.mem_write(0xff, 0x64);
+            // Rebinding asks for each of the player's buttons in turn
+            Event::KeyDown { keycode: Some(Keycode::F2), repeat: false, .. } => controls.start_rebinding(0),
+            Event::KeyDown { keycode: Some(Keycode::F3), repeat: false, .. } => controls.start_rebinding(1),
+            Event::KeyDown { keycode: Some(Keycode::F4), repeat: false, .. } => controls.start_rebinding(2),
+            Event::KeyDown { keycode: Some(Keycode::F5), repeat: false, .. } => controls.start_rebinding(3),
+            Event::KeyDown { keycode: Some(Keycode::F6), repeat: false, .. } => return UserAction::SaveState,
+            Event::KeyDown { keycode: Some(Keycode::F7), repeat: false, .. } => return UserAction::LoadState,
+            Event::KeyDown { keycode: Some(Keycode::F8), repeat: false, .. } => return UserAction::NextSlot,
+            Event::KeyDown { keycode: Some(Keycode::F10), repeat: false, .. } => return UserAction::ToggleMovie,
+            Event::KeyDown { keycode: Some(Keycode::Backspace), repeat: false, .. } => {
+                return UserAction::StartRewind;
             }
+            Event::KeyUp { keycode: Some(Keycode::Backspace), .. } => return UserAction::StopRewind,
             _ => {}
         }
     }
+    UserAction::None
+}
+
+#[derive(Default)]
+struct Args {
+    rom_path: Option<PathBuf>,
+    save_dir: Option<PathBuf>,
+    record_wav: Option<PathBuf>,
+    record_channels: bool,
+    bindings: Option<PathBuf>,
+    input: Option<Peripherals>,
+    record_movie: Option<PathBuf>,
+    play_movie: Option<PathBuf>,
+    // NSF options, tracks count from 1
+    track: Option<u8>,
+    render_wav: Option<PathBuf>,
+    seconds: Option<f64>,
+}
+
+const USAGE: &str = "\
+Usage: nes [rom.nes] [--save-dir <dir>] [--record-wav <file.wav>] [--record-channels]
+           [--bindings <file>] [--input <device>] [--record-movie | --play-movie <file.fm2>]
+       nes <tune.nsf|tune.nsfe> [--track <n>] [--render-wav <file.wav> [--seconds <s>]]";
+
+// Without a ROM the built in snake game is run. F9 starts and stops recording audio while
+// running, --record-channels also records each APU channel to its own file. NSF tunes play in
+// a small window where left and right change track, or with --render-wav are written straight
+// to a file without opening a window or sound device.
+// F6 saves the machine's state to the current slot and F7 loads it back, F8 moves on to the next
+// of the 9 slots. They are kept next to the ROM, or in the --save-dir, as <rom>.ss1 - .ss9.
+// Holding backspace rewinds, with the sound muted.
+// Movies of the controller input are FCEUX .fm2 files. --record-movie records one from power on
+// and --play-movie plays one back, both without the battery save. F10 starts recording one from
+// the current state, and F10 again saves it. Loading states and rewinding are off while a movie
+// is running, and movies only work with joypads or the Four Score.
+// Controls come from bindings.cfg, or --bindings, if it exists. F2 - F5 rebind players 1 - 4
+// and save the result there. F2 - F8 are left to the Family BASIC keyboard when it's connected.
+// A NES 2.0 header can say what the game is played with, otherwise it's joypads unless --input
+// picks one of:
+//   four-score, famicom-four-player  for players 3 and 4
+//   zapper, arkanoid, arkanoid-famicom  the mouse moves the paddle, the left button fires
+//   power-pad[-a|-b], family-trainer[-a|-b]  the mat's rows are R T Y U, F G H J, V B N M
+//   family-basic-keyboard  typed on the host keyboard
+// The keyboard and the mats take over the host keyboard, joypads are then only played with game
+// controllers. Only the Zapper's trigger works for now, it can't sense light without a PPU.
+fn parse_args() -> Args {
+    let mut parsed = Args::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--save-dir" => parsed.save_dir = args.next().map(PathBuf::from),
+            "--record-wav" => parsed.record_wav = args.next().map(PathBuf::from),
+            "--record-channels" => parsed.record_channels = true,
+            "--bindings" => parsed.bindings = args.next().map(PathBuf::from),
+            "--input" => match args.next().as_deref().map(|name| (name, Peripherals::from_name(name))) {
+                Some((_, Some(peripherals))) => parsed.input = Some(peripherals),
+                Some((name, None)) => eprintln!("Unknown input device {}, using the default", name),
+                None => {}
+            },
+            "--record-movie" => parsed.record_movie = args.next().map(PathBuf::from),
+            "--play-movie" => parsed.play_movie = args.next().map(PathBuf::from),
+            "--track" => parsed.track = args.next().and_then(|track| track.parse().ok()),
+            "--render-wav" => parsed.render_wav = args.next().map(PathBuf::from),
+            "--seconds" => parsed.seconds = args.next().and_then(|secs| secs.parse().ok()),
+            _ if arg.starts_with('-') => {
+                eprintln!("Unknown option {}\n{}", arg, USAGE);
+                std::process::exit(1);
+            }
+            _ => parsed.rom_path = Some(PathBuf::from(arg)),
+        }
+    }
+
+    parsed
+}
+
+fn is_nsf(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("nsf") || ext.eq_ignore_ascii_case("nsfe"))
+}
+
+fn run_nsf(path: &Path, args: &Args) -> Result<(), String> {
+    let raw = std::fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+    let nsf = Nsf::parse(&raw)?;
+    println!("{} - {} ({}), {} tracks", nsf.name, nsf.artist, nsf.copyright, nsf.songs);
+
+    let track = args.track.map_or(nsf.start_song, |track| track.saturating_sub(1));
+    let mut player = NsfPlayer::new(nsf);
+    player.start_song(track)?;
+
+    match &args.render_wav {
+        Some(out) => {
+            let seconds = args.seconds.unwrap_or(DEFAULT_RENDER_SECONDS);
+            render_nsf(&mut player, out, seconds, args.record_channels)
+        }
+        None => play_nsf(&mut player, args),
+    }
+}
+
+// Runs the tune as fast as it will go, straight into a WAV file
+fn render_nsf(player: &mut NsfPlayer, path: &Path, seconds: f64, per_channel: bool) -> Result<(), String> {
+    player.cpu().bus.apu().set_sample_rate(AUDIO_SAMPLE_RATE);
+    let recorder = WavRecorder::start(player.cpu().bus.apu(), path, per_channel)
+        .map_err(|e| format!("Couldn't record to {}: {}", path.display(), e))?;
+    let mut recorder = Some(recorder);
+
+    let end = player.cpu().bus.cycles() + (seconds * apu::pipeline::CPU_CLOCK_RATE) as u64;
+    while player.cpu().bus.cycles() < end {
+        player.play()?;
+        let samples = take_audio(player.cpu());
+        end_audio_frame(player.cpu(), &samples, &mut None, &mut recorder, false);
+    }
+    stop_recording(player.cpu(), &mut recorder);
+    Ok(())
 }
 
-fn color (byte: u8) -> Color {
-    // Only bytes 0 and 1 matter. Must be black and white. 
-    // The values are in the range of 0 to 255
-    // Because that is what we write to 0xfe which is a random num generator
-    // Apple will always be green due to range of rands written to 0xfe
-    match byte {
-        0 => sdl2::pixels::Color::BLACK,
-        1 => sdl2::pixels::Color::WHITE,
-        _ => sdl2::pixels::Color::GREEN,
+fn nsf_title(player: &NsfPlayer) -> String {
+    let nsf = player.nsf();
+    let song = player.song() as usize;
+    match nsf.track_labels.get(song) {
+        Some(label) => format!("{} - {}/{} {}", nsf.name, song + 1, nsf.songs, label),
+        None => format!("{} - {}/{}", nsf.name, song + 1, nsf.songs),
     }
 }
 
-fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
-    let mut frame_idx = 0;
-    let mut update = false;
+fn handle_nsf_input(event_pump: &mut EventPump) -> UserAction {
+    for event in event_pump.poll_iter() {
+        match event {
+            Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                return UserAction::Quit;
+            }
+            Event::KeyDown { keycode: Some(Keycode::F9), repeat: false, .. } => {
+                return UserAction::ToggleRecording;
+            }
+            Event::KeyDown { keycode: Some(Keycode::Left), .. } => return UserAction::ChangeTrack(-1),
+            Event::KeyDown { keycode: Some(Keycode::Right), .. } => return UserAction::ChangeTrack(1),
+            _ => {}
+        }
+    }
+    UserAction::None
+}
+
+// Plays the tune through the sound card, paced by the audio queue like cartridges are
+fn play_nsf(player: &mut NsfPlayer, args: &Args) -> Result<(), String> {
+    let sdl_context = sdl2::init()?;
+    let window = sdl_context
+        .video()?
+        .window(&nsf_title(player), 320, 80)
+        .position_centered()
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+    canvas.clear();
+    canvas.present();
+    let mut event_pump = sdl_context.event_pump()?;
+
+    let mut audio = open_audio(&sdl_context, player.cpu());
+    let mut recorder = args
+        .record_wav
+        .as_deref()
+        .and_then(|path| start_recording(player.cpu(), path, args.record_channels));
+
+    loop {
+        match handle_nsf_input(&mut event_pump) {
+            UserAction::Quit => {
+                stop_recording(player.cpu(), &mut recorder);
+                return Ok(());
+            }
+            UserAction::ToggleRecording if recorder.is_some() => stop_recording(player.cpu(), &mut recorder),
+            UserAction::ToggleRecording => {
+                recorder = start_recording(player.cpu(), &hotkey_path("recording", "wav"), args.record_channels);
+            }
+            UserAction::ChangeTrack(step) => {
+                let songs = player.nsf().songs as i16;
+                let song = (player.song() as i16 + step).rem_euclid(songs);
+                player.start_song(song as u8)?;
+                let title = nsf_title(player);
+                if let Err(e) = canvas.window_mut().set_title(&title) {
+                    eprintln!("Couldn't set the window title: {}", e);
+                }
+            }
+            UserAction::SaveState
+            | UserAction::LoadState
+            | UserAction::NextSlot
+            | UserAction::StartRewind
+            | UserAction::StopRewind
+            | UserAction::ToggleMovie
+            | UserAction::None => {}
+        }
+
+        adjust_audio_rate(player.cpu(), &audio);
+        player.play()?;
+        let samples = take_audio(player.cpu());
+        end_audio_frame(player.cpu(), &samples, &mut audio, &mut recorder, false);
 
-    // Memory mapping of the game
-    for i in 0x0200..0x600 {
-        let color_idx = cpu.mem_read(i as u16);
-        // Only update the screen state if b1/b2/b3 have changed
-        let (b1, b2, b3) = color(color_idx).rgb();
-        if frame[frame_idx] != b1 || frame[frame_idx + 1] != b2 || frame[frame_idx + 2] != b3 {
-            frame[frame_idx] = b1;
-            frame[frame_idx + 1] = b2;
-            frame[frame_idx + 2] = b3;
-            update = true;
+        if audio.is_none() {
+            std::thread::sleep(Duration::from_micros(player.nsf().ntsc_speed as u64));
         }
+    }
+}
 
-        frame_idx += 3;
-    } 
-    update
+// Returns the console with the cartridge in and the ROM's MD5, which movies are checked against
+fn insert_cartridge(rom_path: &Path) -> Result<(Nes, [u8; 16]), String> {
+    let raw = std::fs::read(rom_path).map_err(|e| format!("Couldn't read {}: {}", rom_path.display(), e))?;
+    let rom = Rom::new(&raw).map_err(|e| format!("{}: {}", rom_path.display(), e))?;
+    let checksum = rom.md5();
+    let nes = Nes::new(rom).map_err(|e| format!("{}: {}", rom_path.display(), e))?;
+    Ok((nes, checksum))
+}
+
+fn load_battery(cpu: &mut CPU, rom_path: &Path, save_dir: Option<&Path>) -> Option<BatterySave> {
+    // Only battery backed cartridges get a save file
+    let ram = cpu.bus.battery_ram()?;
+    let mut save = BatterySave::new(rom_path, save_dir);
+    if let Err(e) = save.load(ram) {
+        eprintln!("Couldn't load {}: {}", save.path().display(), e);
+    }
+    Some(save)
+}
+
+fn flush_battery(cpu: &mut CPU, battery: &mut Option<BatterySave>, force: bool) {
+    if let (Some(save), Some(ram)) = (battery.as_mut(), cpu.bus.battery_ram()) {
+        let result = if force { save.flush(ram) } else { save.flush_periodically(ram) };
+        if let Err(e) = result {
+            eprintln!("Couldn't write {}: {}", save.path().display(), e);
+        }
+    }
 }
 
 fn main() {
+    let args = parse_args();
+    let rom_path = args.rom_path.clone();
+    let save_dir = args.save_dir.clone();
+
+    if let Some(path) = rom_path.as_deref().filter(|path| is_nsf(path)) {
+        if let Err(e) = run_nsf(path, &args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // The snake game's randomness is seeded so that movies replay it
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(1, |time| time.as_nanos() as u64);
+    let play_movie = args.play_movie.as_deref().map(|path| match Movie::load(path) {
+        Ok(movie) => (movie, path),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    });
+
+    let (mut nes, rom_checksum) = match &rom_path {
+        Some(path) => match insert_cartridge(path) {
+            Ok(inserted) => inserted,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            let seed = play_movie.as_ref().map_or(seed, |(movie, _)| movie.rng_seed);
+            (Nes::snake(seed), movie::md5(&SNAKE_GAME))
+        }
+    };
+    // Movies from power on start with the cartridge's RAM blank, as it was when they were made, and
+    // leave the save file alone
+    let power_on_movie = match &play_movie {
+        Some((movie, _)) => movie.savestate.is_none(),
+        None => args.record_movie.is_some(),
+    };
+    let mut battery = match &rom_path {
+        Some(path) if !power_on_movie => load_battery(nes.cpu(), path, save_dir.as_deref()),
+        _ => None,
+    };
+    if let Some(peripherals) = args.input {
+        nes.cpu().bus.controllers().connect(peripherals);
+    }
+    if nes.cpu().bus.controllers().peripherals() == Peripherals::Zapper {
+        eprintln!("Only the Zapper's trigger works until there's a PPU, it never senses light so it can't hit anything");
+    }
+    let snake = nes.is_snake();
+    let rom_name = rom_path
+        .as_deref()
+        .and_then(Path::file_stem)
+        .map_or("snake".to_string(), |name| name.to_string_lossy().into_owned());
+
+    let mut movie = match (play_movie, &args.record_movie) {
+        (Some((movie, path)), _) => match start_movie_playback(nes.cpu(), movie, path, &rom_checksum) {
+            Ok(session) => Some(session),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        (None, Some(path)) => {
+            let recording = Movie::new(&rom_name, rom_checksum, nes.rng_state());
+            match MovieSession::start_recording(nes.cpu(), recording, path) {
+                Ok(session) => {
+                    println!("Recording a movie to {}", path.display());
+                    Some(session)
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        (None, None) => None,
+    };
+    // F10 waits for the end of the frame to start recording
+    let mut start_movie = false;
+
     // Standard sdl2 setup
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
+    let (width, height) = (nes.frame().width() as u32, nes.frame().height() as u32);
+    // The snake game's 32 x 32 screen is blown up further than a cartridge's
+    let scale = if snake { 10 } else { 2 };
+    let title = if snake { "Snake Game" } else { rom_name.as_str() };
     let window = video_subsystem
-        .window("Snake Game", 32 * 10, 32 * 10)
+        .window(title, width * scale, height * scale)
         .position_centered()
         .build()
         .unwrap();
@@ -87,62 +592,125 @@ fn main() {
     canvas.clear();
 
     let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(10.0, 10.0).unwrap();
-
+    let bindings_path = args.bindings.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_BINDINGS_PATH));
+    let mut controls = Controls::new(&sdl_context, &bindings_path);
+    canvas.set_scale(scale as f32, scale as f32).unwrap();
 
     let creator = canvas.texture_creator();
 
     // RGB24 means 3 bytes per pixel. Each byte refers to one color value
-    let mut texture = creator.create_texture_target(PixelFormatEnum::RGB24, 32, 32).unwrap();
-
-    // Snake Machine code
-    let game_code = vec![
-        0x20, 0x06, 0x06, 0x20, 0x38, 0x06, 0x20, 0x0d, 0x06, 0x20, 0x2a, 0x06, 0x60, 0xa9, 0x02, 0x85,
-        0x02, 0xa9, 0x04, 0x85, 0x03, 0xa9, 0x11, 0x85, 0x10, 0xa9, 0x10, 0x85, 0x12, 0xa9, 0x0f, 0x85,
-        0x14, 0xa9, 0x04, 0x85, 0x11, 0x85, 0x13, 0x85, 0x15, 0x60, 0xa5, 0xfe, 0x85, 0x00, 0xa5, 0xfe,
-        0x29, 0x03, 0x18, 0x69, 0x02, 0x85, 0x01, 0x60, 0x20, 0x4d, 0x06, 0x20, 0x8d, 0x06, 0x20, 0xc3,
-        0x06, 0x20, 0x19, 0x07, 0x20, 0x20, 0x07, 0x20, 0x2d, 0x07, 0x4c, 0x38, 0x06, 0xa5, 0xff, 0xc9,
-        0x77, 0xf0, 0x0d, 0xc9, 0x64, 0xf0, 0x14, 0xc9, 0x73, 0xf0, 0x1b, 0xc9, 0x61, 0xf0, 0x22, 0x60,
-        0xa9, 0x04, 0x24, 0x02, 0xd0, 0x26, 0xa9, 0x01, 0x85, 0x02, 0x60, 0xa9, 0x08, 0x24, 0x02, 0xd0,
-        0x1b, 0xa9, 0x02, 0x85, 0x02, 0x60, 0xa9, 0x01, 0x24, 0x02, 0xd0, 0x10, 0xa9, 0x04, 0x85, 0x02,
-        0x60, 0xa9, 0x02, 0x24, 0x02, 0xd0, 0x05, 0xa9, 0x08, 0x85, 0x02, 0x60, 0x60, 0x20, 0x94, 0x06,
-        0x20, 0xa8, 0x06, 0x60, 0xa5, 0x00, 0xc5, 0x10, 0xd0, 0x0d, 0xa5, 0x01, 0xc5, 0x11, 0xd0, 0x07,
-        0xe6, 0x03, 0xe6, 0x03, 0x20, 0x2a, 0x06, 0x60, 0xa2, 0x02, 0xb5, 0x10, 0xc5, 0x10, 0xd0, 0x06,
-        0xb5, 0x11, 0xc5, 0x11, 0xf0, 0x09, 0xe8, 0xe8, 0xe4, 0x03, 0xf0, 0x06, 0x4c, 0xaa, 0x06, 0x4c,
-        0x35, 0x07, 0x60, 0xa6, 0x03, 0xca, 0x8a, 0xb5, 0x10, 0x95, 0x12, 0xca, 0x10, 0xf9, 0xa5, 0x02,
-        0x4a, 0xb0, 0x09, 0x4a, 0xb0, 0x19, 0x4a, 0xb0, 0x1f, 0x4a, 0xb0, 0x2f, 0xa5, 0x10, 0x38, 0xe9,
-        0x20, 0x85, 0x10, 0x90, 0x01, 0x60, 0xc6, 0x11, 0xa9, 0x01, 0xc5, 0x11, 0xf0, 0x28, 0x60, 0xe6,
-        0x10, 0xa9, 0x1f, 0x24, 0x10, 0xf0, 0x1f, 0x60, 0xa5, 0x10, 0x18, 0x69, 0x20, 0x85, 0x10, 0xb0,
-        0x01, 0x60, 0xe6, 0x11, 0xa9, 0x06, 0xc5, 0x11, 0xf0, 0x0c, 0x60, 0xc6, 0x10, 0xa5, 0x10, 0x29,
-        0x1f, 0xc9, 0x1f, 0xf0, 0x01, 0x60, 0x4c, 0x35, 0x07, 0xa0, 0x00, 0xa5, 0xfe, 0x91, 0x00, 0x60,
-        0xa6, 0x03, 0xa9, 0x00, 0x81, 0x10, 0xa2, 0x00, 0xa9, 0x01, 0x81, 0x10, 0x60, 0xa2, 0x00, 0xea,
-        0xea, 0xca, 0xd0, 0xfb, 0x60,
-    ];
-
-    let mut cpu = CPU::new();
-    cpu.load(game_code);
-    cpu.reset();
-
-    // 32 x 32 pixels * 3 bytes per pixel
-    let mut screen_state = [0 as u8; 32 * 3 * 32];
-    let mut rng = rand::thread_rng();
-
-    // Transfer ownership of CPU into the callback
-    cpu.run_with_callback(move |cpu| {
-        handle_user_input(cpu, &mut event_pump);
-
-        // Random number to address 0xfe
-        // Exclude 0 and 1 so we don't have a black or white
-        cpu.mem_write(0xfe, rng.gen_range(2..255));
-
-        if read_screen_state(cpu, &mut screen_state) {
-            texture.update(None, &screen_state, 32 * 3).unwrap();
-
-            canvas.copy(&texture, None, None).unwrap();
-
-            canvas.present();
-        }
-
-        ::std::thread::sleep(std::time::Duration::new(0, 500));
-    });
+    let mut texture = creator.create_texture_target(PixelFormatEnum::RGB24, width, height).unwrap();
+
+    // The snake game makes no sound and is paced by sleeping, cartridges are paced by the audio
+    let mut audio = if snake { None } else { open_audio(&sdl_context, nes.cpu()) };
+    let mut recorder = args
+        .record_wav
+        .as_deref()
+        .and_then(|path| start_recording(nes.cpu(), path, args.record_channels));
+    let frame_time = Duration::from_secs_f64(CYCLES_PER_FRAME as f64 / apu::pipeline::CPU_CLOCK_RATE);
+    let mut next_frame = Instant::now();
+    let mut rewind = Rewind::new(REWIND_INTERVAL, REWIND_MAX_BYTES);
+    let mut rewinding = false;
+    let mut save_slots = SaveSlots::new(rom_path.as_deref().unwrap_or(Path::new("snake")), save_dir.as_deref());
+    let mut input = InputState::default();
+
+    let peripherals = nes.cpu().bus.controllers().peripherals();
+    let family_basic = peripherals == Peripherals::FamilyBasicKeyboard;
+
+    loop {
+        match handle_user_input(&mut event_pump, &mut controls, family_basic) {
+            UserAction::Quit => {
+                stop_recording(nes.cpu(), &mut recorder);
+                stop_movie(&mut movie);
+                flush_battery(nes.cpu(), &mut battery, true);
+                return;
+            }
+            UserAction::ToggleRecording if recorder.is_some() => stop_recording(nes.cpu(), &mut recorder),
+            UserAction::ToggleRecording => {
+                recorder = start_recording(nes.cpu(), &hotkey_path("recording", "wav"), args.record_channels);
+            }
+            UserAction::ToggleMovie if movie.is_some() => stop_movie(&mut movie),
+            UserAction::ToggleMovie => start_movie = true,
+            UserAction::LoadState | UserAction::StartRewind if movie.is_some() => {
+                println!("Can't go back while a movie is running, F10 stops it");
+            }
+            UserAction::SaveState => match save_slots.save(&nes.cpu().save_state()) {
+                Ok(()) => println!("Saved state to slot {}", save_slots.slot()),
+                Err(e) => eprintln!("Couldn't write {}: {}", save_slots.path().display(), e),
+            },
+            UserAction::LoadState => {
+                let loaded = save_slots
+                    .load()
+                    .map_err(|e| e.to_string())
+                    .and_then(|state| nes.cpu().load_state(&state));
+                match loaded {
+                    Ok(()) => println!("Loaded state from slot {}", save_slots.slot()),
+                    Err(e) => eprintln!("Couldn't load {}: {}", save_slots.path().display(), e),
+                }
+            }
+            UserAction::NextSlot => println!("Save state slot {}", save_slots.next_slot()),
+            UserAction::StartRewind => rewinding = true,
+            UserAction::StopRewind => rewinding = false,
+            UserAction::ChangeTrack(_) | UserAction::None => {}
+        }
+        if let Some(polled) = controls.poll_input(&event_pump, canvas.scale(), peripherals) {
+            input = polled;
+        }
+        flush_battery(nes.cpu(), &mut battery, false);
+
+        // The same frame loop as nes-headless, so movies play back the same in both
+        let frame_input = match movie.as_mut().map(|session| nes.movie_input(session, &input)) {
+            Some(Some(recorded)) => recorded,
+            Some(None) => {
+                println!("Movie finished");
+                movie = None;
+                input
+            }
+            None => input,
+        };
+        adjust_audio_rate(nes.cpu(), &audio);
+        let (frame, samples) = nes.run_frame(&frame_input);
+        texture.update(None, frame.pixels(), frame.width() * 3).unwrap();
+        canvas.copy(&texture, None, None).unwrap();
+        canvas.present();
+        let samples = samples.to_vec();
+        end_audio_frame(nes.cpu(), &samples, &mut audio, &mut recorder, rewinding);
+
+        // Not mid rewind, the movie would start from wherever it stopped
+        if !rewinding && std::mem::take(&mut start_movie) {
+            let path = hotkey_path(&rom_name, "fm2");
+            let mut recording = Movie::new(&rom_name, rom_checksum, nes.rng_state());
+            recording.savestate = Some(nes.cpu().save_state());
+            match MovieSession::start_recording(nes.cpu(), recording, &path) {
+                Ok(session) => {
+                    println!("Recording a movie to {}", path.display());
+                    movie = Some(session);
+                }
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        if !rewinding {
+            rewind.end_frame(nes.cpu());
+        } else if let Some(state) = rewind.pop() {
+            // Go back to the snapshot and show the frame that follows it
+            if let Err(e) = nes.cpu().load_state(&state) {
+                eprintln!("Couldn't rewind: {}", e);
+            }
+            // Stay on the oldest snapshot rather than running on from it
+            if rewind.is_empty() {
+                rewind.push(state);
+            }
+        }
+        controls.end_frame();
+
+        // Without a sound card to keep time, sleep off whatever is left of the frame
+        if audio.is_none() {
+            next_frame += frame_time;
+            let now = Instant::now();
+            match next_frame.checked_duration_since(now) {
+                Some(wait) => std::thread::sleep(wait),
+                None => next_frame = now,
+            }
+        }
+    }
 }
@@ -0,0 +1,324 @@
+use crate::savestate::Savestate;
+
+const WAVE_SIZE: usize = 64;
+// Gains above 32 are allowed but the output stops getting louder there
+const MAX_GAIN: u8 = 32;
+// At full volume the FDS is about 2.4 times as loud as an APU pulse at volume 15
+const LEVEL_STEP: f32 = 0.358 / (63.0 * MAX_GAIN as f32);
+// Master volume, $4089 bits 0 - 1, as fractions of full volume
+const MASTER_VOLUMES: [f32; 4] = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0];
+// How each modulation table entry changes the mod counter, 4 resets it instead
+const MOD_ADJUSTMENTS: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+const MOD_RESET: u8 = 4;
+
+#[derive(Default)]
+struct Envelope {
+    // $4080 / $4084: MDSS SSSS, M = no envelope and S is the gain, D = increase
+    disabled: bool,
+    increase: bool,
+    speed: u8,
+    gain: u8,
+    timer: u32,
+}
+
+impl Envelope {
+    fn write(&mut self, data: u8, master_speed: u8) {
+        self.disabled = data & 0x80 != 0;
+        self.increase = data & 0x40 != 0;
+        self.speed = data & 0x3F;
+        if self.disabled {
+            self.gain = self.speed;
+        }
+        self.reset_timer(master_speed);
+    }
+
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (master_speed as u32 + 1) * (self.speed as u32 + 1);
+    }
+
+    fn clock(&mut self, master_speed: u8) {
+        if self.disabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.reset_timer(master_speed);
+
+        if self.increase && self.gain < MAX_GAIN {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+}
+
+// The Famicom Disk System's 2C33 sound: one 64 step wavetable channel with a volume envelope,
+// and a modulator that bends its pitch from a second table. Registers are at $4040 - $408A.
+pub struct FdsAudio {
+    wave: [u8; WAVE_SIZE],
+    // $4089 bit 7, the wave can only be written while the channel holds its output
+    wave_writable: bool,
+    master_volume: u8,
+
+    // $4082 / $4083
+    wave_frequency: u16,
+    wave_halted: bool,
+    envelopes_halted: bool,
+    // Steps through the wave every time the bottom 16 bits overflow
+    wave_accumulator: u32,
+    output: u8,
+
+    volume: Envelope,
+    mod_envelope: Envelope,
+    // $408A, 0 stops both envelopes
+    envelope_speed: u8,
+
+    mod_table: [u8; WAVE_SIZE],
+    mod_position: usize,
+    // $4085, 7 bit signed
+    mod_counter: i8,
+    // $4086 / $4087
+    mod_frequency: u16,
+    mod_halted: bool,
+    mod_accumulator: u32,
+}
+
+impl FdsAudio {
+    pub fn new() -> Self {
+        FdsAudio {
+            wave: [0; WAVE_SIZE],
+            wave_writable: false,
+            master_volume: 0,
+            wave_frequency: 0,
+            wave_halted: true,
+            envelopes_halted: true,
+            wave_accumulator: 0,
+            output: 0,
+            volume: Envelope::default(),
+            mod_envelope: Envelope::default(),
+            envelope_speed: 0xE8,
+            mod_table: [0; WAVE_SIZE],
+            mod_position: 0,
+            mod_counter: 0,
+            mod_frequency: 0,
+            mod_halted: true,
+            mod_accumulator: 0,
+        }
+    }
+
+    pub fn read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4040..=0x407F => self.wave[(addr - 0x4040) as usize],
+            0x4090 => self.volume.gain | 0x40,
+            0x4092 => self.mod_envelope.gain | 0x40,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4040..=0x407F if self.wave_writable => self.wave[(addr - 0x4040) as usize] = data & 0x3F,
+            0x4080 => self.volume.write(data, self.envelope_speed),
+            0x4082 => self.wave_frequency = (self.wave_frequency & 0xF00) | data as u16,
+            // HE-- FFFF: halt the wave, halt the envelopes
+            0x4083 => {
+                self.wave_frequency = (self.wave_frequency & 0x0FF) | ((data & 0x0F) as u16) << 8;
+                self.wave_halted = data & 0x80 != 0;
+                self.envelopes_halted = data & 0x40 != 0;
+                if self.wave_halted {
+                    self.wave_accumulator = 0;
+                }
+                if self.envelopes_halted {
+                    self.volume.reset_timer(self.envelope_speed);
+                    self.mod_envelope.reset_timer(self.envelope_speed);
+                }
+            }
+            0x4084 => self.mod_envelope.write(data, self.envelope_speed),
+            0x4085 => self.mod_counter = sign_extend_7(data),
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0xF00) | data as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x0FF) | ((data & 0x0F) as u16) << 8;
+                self.mod_halted = data & 0x80 != 0;
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // Each write fills two entries, only while the modulator is halted
+            0x4088 if self.mod_halted => {
+                self.mod_table[self.mod_position] = data & 0b111;
+                self.mod_table[self.mod_position + 1] = data & 0b111;
+                self.mod_position = (self.mod_position + 2) % WAVE_SIZE;
+            }
+            0x4089 => {
+                self.wave_writable = data & 0x80 != 0;
+                self.master_volume = data & 0b11;
+            }
+            0x408A => self.envelope_speed = data,
+            _ => {}
+        }
+    }
+
+    // The wave frequency bent by the mod counter times the mod gain, with the chip's rounding
+    fn modulated_frequency(&self) -> u32 {
+        let counter = self.mod_counter as i32;
+        let mut offset = counter * self.mod_envelope.gain as i32;
+        let remainder = offset & 0x0F;
+        offset >>= 4;
+        if remainder > 0 && offset & 0x80 == 0 {
+            offset += if counter < 0 { -1 } else { 2 };
+        }
+        if offset >= 192 {
+            offset -= 256;
+        } else if offset < -64 {
+            offset += 256;
+        }
+
+        let pitch = self.wave_frequency as i32;
+        let mut offset = pitch * offset;
+        let remainder = offset & 0x3F;
+        offset >>= 6;
+        if remainder >= 32 {
+            offset += 1;
+        }
+        (pitch + offset).max(0) as u32
+    }
+
+    pub fn clock(&mut self) {
+        if !self.envelopes_halted && !self.wave_halted && self.envelope_speed != 0 {
+            self.volume.clock(self.envelope_speed);
+            self.mod_envelope.clock(self.envelope_speed);
+        }
+
+        if !self.mod_halted {
+            self.mod_accumulator += self.mod_frequency as u32;
+            if self.mod_accumulator >= 0x10000 {
+                self.mod_accumulator -= 0x10000;
+                let step = self.mod_table[self.mod_position];
+                self.mod_counter = if step == MOD_RESET {
+                    0
+                } else {
+                    sign_extend_7(self.mod_counter.wrapping_add(MOD_ADJUSTMENTS[step as usize]) as u8)
+                };
+                self.mod_position = (self.mod_position + 1) % WAVE_SIZE;
+            }
+        }
+
+        if !self.wave_halted {
+            self.wave_accumulator = (self.wave_accumulator + self.modulated_frequency()) & 0x3F_FFFF;
+        }
+        // The output holds still while the wave can be written
+        if !self.wave_writable {
+            self.output = self.wave[(self.wave_accumulator >> 16) as usize];
+        }
+    }
+
+    pub fn output(&self) -> f32 {
+        let gain = self.volume.gain.min(MAX_GAIN);
+        (self.output as u16 * gain as u16) as f32 * LEVEL_STEP * MASTER_VOLUMES[self.master_volume as usize]
+    }
+}
+
+impl Default for FdsAudio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The mod counter wraps around at 7 bits
+fn sign_extend_7(data: u8) -> i8 {
+    ((data << 1) as i8) >> 1
+}
+
+impl Savestate for Envelope {
+    crate::savestate_fields!(disabled, increase, speed, gain, timer);
+}
+
+impl Savestate for FdsAudio {
+    crate::savestate_fields!(
+        wave,
+        wave_writable,
+        master_volume,
+        wave_frequency,
+        wave_halted,
+        envelopes_halted,
+        wave_accumulator,
+        output,
+        volume,
+        mod_envelope,
+        envelope_speed,
+        mod_table,
+        mod_position,
+        mod_counter,
+        mod_frequency,
+        mod_halted,
+        mod_accumulator,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A rising ramp at full volume
+    fn ramp() -> FdsAudio {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4089, 0x80);
+        for i in 0..WAVE_SIZE as u16 {
+            audio.write(0x4040 + i, i as u8);
+        }
+        audio.write(0x4089, 0);
+        audio.write(0x4080, 0x80 | MAX_GAIN);
+        audio
+    }
+
+    #[test]
+    fn test_wave_steps() {
+        let mut audio = ramp();
+        // One step every 32 cycles
+        audio.write(0x4082, 0x00);
+        audio.write(0x4083, 0x08);
+
+        for _ in 0..(32 * 10) {
+            audio.clock();
+        }
+        assert_eq!(audio.output, 10);
+        assert!(audio.output() > 0.0);
+    }
+
+    #[test]
+    fn test_modulation_bends_pitch() {
+        let mut audio = ramp();
+        audio.write(0x4082, 0x00);
+        audio.write(0x4083, 0x01);
+        audio.write(0x4084, 0x80 | 0x20);
+        // Mod counter at +16 with gain 32 raises the pitch by half, and -16 lowers it by half
+        audio.write(0x4085, 16);
+        assert_eq!(audio.modulated_frequency(), 0x180);
+        audio.write(0x4085, 0x70);
+        assert_eq!(audio.modulated_frequency(), 0x80);
+    }
+
+    #[test]
+    fn test_mod_table_steps_counter() {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4087, 0x80);
+        // The whole table, so writing wraps back around to the start
+        for step in [1, 1, 7, 4].into_iter().chain([0; 28]) {
+            audio.write(0x4088, step);
+        }
+        // One step every 32 cycles
+        audio.write(0x4086, 0x00);
+        audio.write(0x4087, 0x08);
+
+        let mut counters = Vec::new();
+        for _ in 0..8 {
+            for _ in 0..32 {
+                audio.clock();
+            }
+            counters.push(audio.mod_counter);
+        }
+        assert_eq!(counters, [1, 2, 3, 4, 3, 2, 0, 0]);
+    }
+}
@@ -0,0 +1,224 @@
+use super::sunsoft5b_audio::Sunsoft5bAudio;
+use super::{chr_memory, Mapper};
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::Savestate;
+use alloc::vec::Vec;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// Mapper 69, the Sunsoft FME-7 and its 5B variant with the extra sound chip.
+// Everything goes through a command register at $8000 and a parameter register at $A000.
+pub struct Fme7 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_ram: [u8; PRG_RAM_SIZE],
+
+    command: u8,
+    chr_banks: [u8; 8],
+    // Command 8: bank at $6000, bit 6 picks RAM over ROM and bit 7 enables the RAM
+    prg_6000: u8,
+    prg_banks: [u8; 3],
+    mirroring: Mirroring,
+
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_counter: u16,
+    irq_pending: bool,
+
+    audio: Sunsoft5bAudio,
+}
+
+impl Fme7 {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(rom.chr_rom, rom.chr_ram_size);
+
+        Fme7 {
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            prg_ram: [0; PRG_RAM_SIZE],
+            command: 0,
+            chr_banks: [0; 8],
+            prg_6000: 0,
+            prg_banks: [0; 3],
+            mirroring: rom.screen_mirroring,
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_counter: 0,
+            irq_pending: false,
+            audio: Sunsoft5bAudio::new(),
+        }
+    }
+
+    fn prg_rom_byte(&self, bank: u8, addr: u16) -> u8 {
+        let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+        self.prg_rom[(bank as usize % banks) * PRG_BANK_SIZE + addr as usize % PRG_BANK_SIZE]
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr as usize / CHR_BANK_SIZE) & 7] as usize;
+        (bank * CHR_BANK_SIZE + addr as usize % CHR_BANK_SIZE) % self.chr.len()
+    }
+
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = data,
+            0x8 => self.prg_6000 = data,
+            0x9..=0xB => self.prg_banks[(self.command - 0x9) as usize] = data & 0x3F,
+            0xC => {
+                self.mirroring = match data & 0b11 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenLower,
+                    _ => Mirroring::SingleScreenUpper,
+                };
+            }
+            0xD => {
+                // Any write to the control register acknowledges the IRQ
+                self.irq_enabled = data & 0x01 != 0;
+                self.irq_counter_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0xE => self.irq_counter = (self.irq_counter & 0xFF00) | data as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00FF) | (data as u16) << 8,
+        }
+    }
+}
+
+impl Mapper for Fme7 {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => {
+                let ram = self.prg_6000 & 0x40 != 0;
+                let ram_enabled = self.prg_6000 & 0x80 != 0;
+                match (ram, ram_enabled) {
+                    (false, _) => self.prg_rom_byte(self.prg_6000 & 0x3F, addr),
+                    (true, true) => self.prg_ram[(addr - 0x6000) as usize],
+                    // Open bus
+                    (true, false) => 0,
+                }
+            }
+            0x8000..=0xDFFF => {
+                let slot = ((addr - 0x8000) / 0x2000) as usize;
+                self.prg_rom_byte(self.prg_banks[slot], addr)
+            }
+            0xE000..=0xFFFF => {
+                let last = (self.prg_rom.len() / PRG_BANK_SIZE - 1) as u8;
+                self.prg_rom_byte(last, addr)
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.prg_6000 & 0xC0 == 0xC0 => {
+                self.prg_ram[(addr - 0x6000) as usize] = data;
+            }
+            0x8000..=0x9FFF => self.command = data & 0x0F,
+            0xA000..=0xBFFF => self.write_parameter(data),
+            0xC000..=0xDFFF => self.audio.select(data),
+            0xE000..=0xFFFF => self.audio.write(data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn cpu_clock(&mut self) {
+        self.audio.clock();
+
+        if !self.irq_counter_enabled {
+            return;
+        }
+
+        // The IRQ fires as the counter wraps from $0000 to $FFFF
+        self.irq_counter = self.irq_counter.wrapping_sub(1);
+        if self.irq_counter == 0xFFFF && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+impl Savestate for Fme7 {
+    crate::savestate_fields!(
+        chr if chr_is_ram,
+        prg_ram,
+        command,
+        chr_banks,
+        prg_6000,
+        prg_banks,
+        mirroring,
+        irq_enabled,
+        irq_counter_enabled,
+        irq_counter,
+        irq_pending,
+        audio,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_prg_ram_at_6000() {
+        let mut mapper = Fme7::new(test_rom(69, 2, 1));
+        mapper.cpu_write(0x8000, 0x8);
+        mapper.cpu_write(0xA000, 0xC0);
+        mapper.cpu_write(0x6000, 0x42);
+        assert_eq!(mapper.cpu_read(0x6000), 0x42);
+
+        // Switching back to ROM hides the RAM
+        mapper.cpu_write(0xA000, 0x00);
+        assert_eq!(mapper.cpu_read(0x6000), 0x00);
+    }
+
+    #[test]
+    fn test_irq_on_wrap() {
+        let mut mapper = Fme7::new(test_rom(69, 2, 1));
+        mapper.cpu_write(0x8000, 0xE);
+        mapper.cpu_write(0xA000, 0x01);
+        mapper.cpu_write(0x8000, 0xF);
+        mapper.cpu_write(0xA000, 0x00);
+        mapper.cpu_write(0x8000, 0xD);
+        mapper.cpu_write(0xA000, 0x81);
+
+        mapper.cpu_clock();
+        assert!(!mapper.irq_pending());
+        mapper.cpu_clock();
+        assert!(mapper.irq_pending());
+
+        mapper.cpu_write(0xA000, 0x81);
+        assert!(!mapper.irq_pending());
+    }
+}
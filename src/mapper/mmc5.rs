@@ -0,0 +1,586 @@
+use super::mmc5_audio::Mmc5Audio;
+use super::{chr_memory, Mapper, PpuFetch};
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::Savestate;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// MMC5 boards carry up to 64 KiB of PRG RAM in eight 8 KiB banks
+const PRG_RAM_SIZE: usize = 0x10000;
+const EXRAM_SIZE: usize = 0x400;
+
+// Nametables live at $2000 - $2FFF. The first 960 bytes of each are tile indexes,
+// the last 64 are attributes.
+const ATTRIBUTE_OFFSET: usize = 0x3C0;
+
+// Fill mode, extended attributes, the vertical split and the scanline IRQ all hang off the PPU
+// hooks, so they only run in the tests until there's a PPU to call them.
+pub struct Mmc5 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_ram: Vec<u8>,
+    exram: [u8; EXRAM_SIZE],
+
+    // $5100 - $5107
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+
+    // $5113 - $5117. Bit 7 selects ROM (1) or RAM (0), $5117 is always ROM
+    prg_banks: [u8; 5],
+
+    // $5120 - $5127 is set A (sprites), $5128 - $512B is set B (background with 8x16 sprites).
+    // The upper bits from $5130 are latched in when a register is written
+    chr_banks: [u16; 12],
+    chr_upper: u8,
+    last_chr_write_set_b: bool,
+
+    // $5200 - $5202
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+
+    // $5203 / $5204
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    in_frame: bool,
+    scanline_counter: u8,
+
+    // $5205 / $5206
+    multiplicand: u8,
+    multiplier: u8,
+
+    // What we know about the PPU from snooping $2000 / $2001 and the fetch hooks
+    sprite_16: bool,
+    rendering_enabled: bool,
+    fetch: PpuFetch,
+    scanline: u16,
+    tile_column: u8,
+    last_tile: usize,
+    in_split: bool,
+
+    audio: Mmc5Audio,
+}
+
+impl Mmc5 {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(rom.chr_rom, rom.chr_ram_size);
+
+        Mmc5 {
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            exram: [0; EXRAM_SIZE],
+            // Power on in 8 KiB mode with the last bank at $E000 so the reset vector is reachable
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0; 2],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            prg_banks: [0, 0, 0, 0, 0xFF],
+            chr_banks: [0; 12],
+            chr_upper: 0,
+            last_chr_write_set_b: false,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            in_frame: false,
+            scanline_counter: 0,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            sprite_16: false,
+            rendering_enabled: false,
+            fetch: PpuFetch::Background,
+            scanline: 0,
+            tile_column: 0,
+            last_tile: 0,
+            in_split: false,
+            audio: Mmc5Audio::new(),
+        }
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        // Both protect registers must hold their magic values before PRG RAM accepts writes
+        self.prg_ram_protect[0] & 0b11 == 0b10 && self.prg_ram_protect[1] & 0b11 == 0b01
+    }
+
+    // Returns whether $8000 - $FFFF is backed by ROM at this address and the offset into it
+    fn prg_address(&self, addr: u16) -> (bool, usize) {
+        // (index into prg_banks, bank size)
+        let (reg, size) = match self.prg_mode {
+            0 => (4, 0x8000),
+            1 => if addr < 0xC000 { (2, 0x4000) } else { (4, 0x4000) },
+            2 => match addr {
+                0x8000..=0xBFFF => (2, 0x4000),
+                0xC000..=0xDFFF => (3, 0x2000),
+                _ => (4, 0x2000),
+            },
+            _ => (((addr - 0x8000) / 0x2000) as usize + 1, 0x2000),
+        };
+
+        let bank = self.prg_banks[reg];
+        let rom = reg == 4 || bank & 0x80 != 0;
+
+        // Bank numbers are always in 8 KiB units, larger banks ignore the low bits
+        let bank = (bank & 0x7F) as usize & !(size / 0x2000 - 1);
+        (rom, bank * 0x2000 + (addr as usize & (size - 1)))
+    }
+
+    fn in_split_region(&self) -> bool {
+        if self.split_control & 0x80 == 0 || self.exram_mode > 1 {
+            return false;
+        }
+
+        let threshold = self.split_control & 0x1F;
+        if self.split_control & 0x40 != 0 {
+            self.tile_column >= threshold
+        } else {
+            self.tile_column < threshold
+        }
+    }
+
+    // The split region scrolls vertically on its own, wrapping around the 30 tile rows
+    fn split_y(&self) -> usize {
+        (self.split_scroll as usize + self.scanline as usize) % 240
+    }
+
+    fn background_fetch(&self) -> bool {
+        self.rendering_enabled && self.in_frame && self.fetch == PpuFetch::Background
+    }
+
+    fn chr_address(&self, addr: u16) -> usize {
+        let addr = addr as usize & 0x1FFF;
+
+        if self.background_fetch() {
+            if self.in_split {
+                // Split tiles come from a single 4 KiB bank using the split's own fine y
+                let fine_y = self.split_y() & 0b111;
+                return self.split_bank as usize * 0x1000 + ((addr & 0xFF8) | fine_y);
+            }
+
+            if self.exram_mode == 1 {
+                // Extended attribute mode, every tile picks its own 4 KiB bank
+                let bank = (self.exram[self.last_tile] & 0x3F) as usize | (self.chr_upper as usize) << 6;
+                return bank * 0x1000 + (addr & 0xFFF);
+            }
+        }
+
+        // Set B is only used for the background when 8x16 sprites are enabled. Outside of
+        // rendering the last written set wins.
+        let set_b = self.sprite_16
+            && if self.rendering_enabled && self.in_frame {
+                self.fetch == PpuFetch::Background
+            } else {
+                self.last_chr_write_set_b
+            };
+
+        let (reg, size) = match self.chr_mode {
+            0 => (if set_b { 11 } else { 7 }, 0x2000),
+            1 => (if set_b { 11 } else if addr < 0x1000 { 3 } else { 7 }, 0x1000),
+            2 => {
+                let reg = if set_b { [9, 11][(addr / 0x800) & 1] } else { [1, 3, 5, 7][addr / 0x800] };
+                (reg, 0x800)
+            }
+            _ => (if set_b { 8 + (addr / 0x400) % 4 } else { addr / 0x400 }, 0x400),
+        };
+
+        self.chr_banks[reg] as usize * size + (addr & (size - 1))
+    }
+
+    // Where nametable reads and writes end up, by the two bits for each table in $5105
+    fn nametable_source(&self, addr: u16) -> u8 {
+        let table = (addr >> 10) & 0b11;
+        (self.nametable_mapping >> (table * 2)) & 0b11
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x5015 => self.audio.read_status(),
+            0x5204 => {
+                let mut status = 0;
+                if self.irq_pending {
+                    status |= 0x80;
+                }
+                if self.in_frame {
+                    status |= 0x40;
+                }
+
+                // Reading acknowledges the IRQ
+                self.irq_pending = false;
+                status
+            }
+            0x5205 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+            0x5206 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+            // ExRAM can only be read back in modes 2 and 3
+            0x5C00..=0x5FFF if self.exram_mode >= 2 => self.exram[(addr - 0x5C00) as usize],
+            0x6000..=0x7FFF => {
+                let bank = (self.prg_banks[0] & 0x07) as usize;
+                self.prg_ram[bank * 0x2000 + (addr - 0x6000) as usize]
+            }
+            0x8000..=0xFFFF => {
+                let (rom, offset) = self.prg_address(addr);
+                let data = if rom {
+                    self.prg_rom[offset % self.prg_rom.len()]
+                } else {
+                    self.prg_ram[offset % PRG_RAM_SIZE]
+                };
+                self.audio.cpu_read(addr, data);
+                data
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5015 => self.audio.write(addr, data),
+            0x5100 => self.prg_mode = data & 0b11,
+            0x5101 => self.chr_mode = data & 0b11,
+            0x5102 | 0x5103 => self.prg_ram_protect[(addr - 0x5102) as usize] = data,
+            0x5104 => self.exram_mode = data & 0b11,
+            0x5105 => self.nametable_mapping = data,
+            0x5106 => self.fill_tile = data,
+            0x5107 => self.fill_attribute = data & 0b11,
+            0x5113..=0x5117 => self.prg_banks[(addr - 0x5113) as usize] = data,
+            0x5120..=0x512B => {
+                let reg = (addr - 0x5120) as usize;
+                self.chr_banks[reg] = data as u16 | (self.chr_upper as u16) << 8;
+                self.last_chr_write_set_b = reg >= 8;
+            }
+            0x5130 => self.chr_upper = data & 0b11,
+            0x5200 => self.split_control = data,
+            0x5201 => self.split_scroll = data,
+            0x5202 => self.split_bank = data,
+            0x5203 => self.irq_compare = data,
+            0x5204 => self.irq_enabled = data & 0x80 != 0,
+            0x5205 => self.multiplicand = data,
+            0x5206 => self.multiplier = data,
+            0x5C00..=0x5FFF => {
+                let offset = (addr - 0x5C00) as usize;
+                match self.exram_mode {
+                    // In the nametable modes the PPU owns ExRAM, writes outside of
+                    // rendering store zero instead
+                    0 | 1 => self.exram[offset] = if self.in_frame { data } else { 0 },
+                    2 => self.exram[offset] = data,
+                    _ => {}
+                }
+            }
+            0x6000..=0x7FFF if self.prg_ram_writable() => {
+                let bank = (self.prg_banks[0] & 0x07) as usize;
+                self.prg_ram[bank * 0x2000 + (addr - 0x6000) as usize] = data;
+            }
+            0x8000..=0xDFFF if self.prg_ram_writable() => {
+                let (rom, offset) = self.prg_address(addr);
+                if !rom {
+                    self.prg_ram[offset % PRG_RAM_SIZE] = data;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        let offset = self.chr_address(addr);
+        self.chr[offset % self.chr.len()]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let offset = self.chr_address(addr) % self.chr.len();
+            self.chr[offset] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        // Only an approximation, $5105 can map the nametables in ways the
+        // cartridge header can't describe. The nametable hooks below are authoritative.
+        match self.nametable_mapping {
+            0x44 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn nametable_read(&mut self, addr: u16, vram: &[u8]) -> u8 {
+        let offset = (addr & 0x3FF) as usize;
+        let background = self.background_fetch();
+
+        if background && offset < ATTRIBUTE_OFFSET {
+            // Every tile fetch moves us along the scanline
+            self.in_split = self.in_split_region();
+            self.tile_column = self.tile_column.wrapping_add(1);
+            self.last_tile = offset;
+        }
+
+        if background && self.in_split {
+            let y = self.split_y();
+            let column = (self.tile_column.wrapping_sub(1) & 0x1F) as usize;
+
+            return if offset < ATTRIBUTE_OFFSET {
+                self.exram[(y / 8) * 32 + column]
+            } else {
+                let attribute = self.exram[ATTRIBUTE_OFFSET + (y / 32) * 8 + column / 4];
+                let shift = ((y / 8) & 0b10) << 1 | (column & 0b10);
+                // Repeat the palette in all four quadrants so it doesn't matter which the PPU picks
+                ((attribute >> shift) & 0b11) * 0x55
+            };
+        }
+
+        if background && self.exram_mode == 1 && offset >= ATTRIBUTE_OFFSET {
+            // Extended attribute mode, the palette comes from the top two bits of the tile's ExRAM byte
+            return (self.exram[self.last_tile] >> 6) * 0x55;
+        }
+
+        match self.nametable_source(addr) {
+            0 => vram[offset],
+            1 => vram[0x400 + offset],
+            2 => if self.exram_mode <= 1 { self.exram[offset] } else { 0 },
+            _ => if offset < ATTRIBUTE_OFFSET { self.fill_tile } else { self.fill_attribute * 0x55 },
+        }
+    }
+
+    fn nametable_write(&mut self, addr: u16, data: u8, vram: &mut [u8]) {
+        let offset = (addr & 0x3FF) as usize;
+
+        match self.nametable_source(addr) {
+            0 => vram[offset] = data,
+            1 => vram[0x400 + offset] = data,
+            2 if self.exram_mode <= 1 => self.exram[offset] = data,
+            _ => {}
+        }
+    }
+
+    fn ppu_register_write(&mut self, addr: u16, data: u8) {
+        match addr & 0x2007 {
+            0x2000 => self.sprite_16 = data & 0b0010_0000 != 0,
+            0x2001 => {
+                // Show background or sprites
+                self.rendering_enabled = data & 0b0001_1000 != 0;
+                if !self.rendering_enabled {
+                    self.in_frame = false;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_fetch(&mut self, fetch: PpuFetch) {
+        self.fetch = fetch;
+    }
+
+    fn scanline(&mut self, scanline: u16, rendering: bool) {
+        self.tile_column = 0;
+        self.in_split = false;
+
+        if !rendering || scanline >= 240 {
+            self.in_frame = false;
+            return;
+        }
+
+        self.scanline = scanline;
+
+        if self.in_frame {
+            self.scanline_counter = self.scanline_counter.wrapping_add(1);
+            if self.scanline_counter == self.irq_compare {
+                self.irq_pending = true;
+            }
+        } else {
+            // First rendered scanline of the frame
+            self.in_frame = true;
+            self.scanline_counter = 0;
+            self.irq_pending = false;
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.audio.clock();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_enabled && self.irq_pending
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+impl Savestate for Mmc5 {
+    crate::savestate_fields!(
+        chr if chr_is_ram,
+        prg_ram,
+        exram,
+        prg_mode,
+        chr_mode,
+        prg_ram_protect,
+        exram_mode,
+        nametable_mapping,
+        fill_tile,
+        fill_attribute,
+        prg_banks,
+        chr_banks,
+        chr_upper,
+        last_chr_write_set_b,
+        split_control,
+        split_scroll,
+        split_bank,
+        irq_compare,
+        irq_enabled,
+        irq_pending,
+        in_frame,
+        scanline_counter,
+        multiplicand,
+        multiplier,
+        sprite_16,
+        rendering_enabled,
+        fetch,
+        scanline,
+        tile_column,
+        last_tile,
+        in_split,
+        audio,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    fn mmc5() -> Mmc5 {
+        let mut rom = test_rom(5, 8, 8);
+        // Tag every 8 KiB PRG bank and 1 KiB CHR bank with its number
+        for (i, byte) in rom.prg_rom.iter_mut().enumerate() {
+            *byte = (i / 0x2000) as u8;
+        }
+        for (i, byte) in rom.chr_rom.iter_mut().enumerate() {
+            *byte = (i / 0x400) as u8;
+        }
+        Mmc5::new(rom)
+    }
+
+    #[test]
+    fn test_prg_modes() {
+        let mut mapper = mmc5();
+        // Power on state has the last bank at $E000
+        assert_eq!(mapper.cpu_read(0xFFFC), 15);
+
+        mapper.cpu_write(0x5114, 0x83);
+        mapper.cpu_write(0x5115, 0x85);
+        mapper.cpu_write(0x5116, 0x87);
+        mapper.cpu_write(0x5117, 0x89);
+        assert_eq!(mapper.cpu_read(0x8000), 3);
+        assert_eq!(mapper.cpu_read(0xA000), 5);
+        assert_eq!(mapper.cpu_read(0xC000), 7);
+        assert_eq!(mapper.cpu_read(0xE000), 9);
+
+        // 32 KiB mode ignores the low two bits of $5117
+        mapper.cpu_write(0x5100, 0);
+        assert_eq!(mapper.cpu_read(0x8000), 8);
+        assert_eq!(mapper.cpu_read(0xE000), 11);
+
+        // 16 + 8 + 8 KiB
+        mapper.cpu_write(0x5100, 2);
+        assert_eq!(mapper.cpu_read(0x8000), 4);
+        assert_eq!(mapper.cpu_read(0xA000), 5);
+        assert_eq!(mapper.cpu_read(0xC000), 7);
+    }
+
+    #[test]
+    fn test_prg_ram_protect() {
+        let mut mapper = mmc5();
+        mapper.cpu_write(0x6000, 0x42);
+        assert_eq!(mapper.cpu_read(0x6000), 0);
+
+        mapper.cpu_write(0x5102, 0b10);
+        mapper.cpu_write(0x5103, 0b01);
+        mapper.cpu_write(0x6000, 0x42);
+        assert_eq!(mapper.cpu_read(0x6000), 0x42);
+
+        // RAM can be banked into $8000 as well
+        mapper.cpu_write(0x5114, 0x00);
+        assert_eq!(mapper.cpu_read(0x8000), 0x42);
+    }
+
+    #[test]
+    fn test_chr_sets_with_8x16_sprites() {
+        let mut mapper = mmc5();
+        mapper.cpu_write(0x5101, 3);
+        for i in 0..12 {
+            mapper.cpu_write(0x5120 + i, 16 + i as u8);
+        }
+        mapper.ppu_register_write(0x2000, 0b0010_0000);
+        mapper.ppu_register_write(0x2001, 0b0001_1000);
+        mapper.scanline(0, true);
+
+        mapper.ppu_fetch(PpuFetch::Sprite);
+        assert_eq!(mapper.ppu_read(0x1400), 21);
+        mapper.ppu_fetch(PpuFetch::Background);
+        assert_eq!(mapper.ppu_read(0x1400), 25);
+    }
+
+    #[test]
+    fn test_multiplier() {
+        let mut mapper = mmc5();
+        mapper.cpu_write(0x5205, 200);
+        mapper.cpu_write(0x5206, 100);
+        assert_eq!(mapper.cpu_read(0x5205), (20000 & 0xFF) as u8);
+        assert_eq!(mapper.cpu_read(0x5206), (20000 >> 8) as u8);
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mapper = mmc5();
+        mapper.cpu_write(0x5203, 3);
+        mapper.cpu_write(0x5204, 0x80);
+        mapper.ppu_register_write(0x2001, 0b0001_1000);
+
+        for scanline in 0..3 {
+            mapper.scanline(scanline, true);
+            assert!(!mapper.irq_pending());
+        }
+        mapper.scanline(3, true);
+        assert!(mapper.irq_pending());
+
+        // Status read reports and acknowledges it
+        assert_eq!(mapper.cpu_read(0x5204), 0xC0);
+        assert!(!mapper.irq_pending());
+
+        mapper.scanline(241, true);
+        assert_eq!(mapper.cpu_read(0x5204), 0x00);
+    }
+
+    #[test]
+    fn test_fill_mode_and_exram_nametable() {
+        let mut mapper = mmc5();
+        let mut vram = [0; 0x800];
+        // $2000 CIRAM A, $2400 CIRAM B, $2800 ExRAM, $2C00 fill
+        mapper.cpu_write(0x5105, 0b11_10_01_00);
+        mapper.cpu_write(0x5106, 0x24);
+        mapper.cpu_write(0x5107, 0x02);
+
+        mapper.nametable_write(0x2405, 0x11, &mut vram);
+        mapper.nametable_write(0x2805, 0x22, &mut vram);
+        assert_eq!(vram[0x405], 0x11);
+        assert_eq!(mapper.nametable_read(0x2805, &vram), 0x22);
+        assert_eq!(mapper.nametable_read(0x2C10, &vram), 0x24);
+        assert_eq!(mapper.nametable_read(0x2FC0, &vram), 0xAA);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::apu::mixer::{pulse_level, tnd_level};
+use crate::apu::pulse::{Pulse, Sweep};
+use crate::savestate::Savestate;
+
+// The MMC5 clocks its envelopes and length counters at a fixed 240 Hz
+const FRAME_PERIOD: u16 = 7457;
+
+// Two copies of the APU's pulse channel without the sweep units, plus an 8 bit PCM channel.
+// Registers are at $5000 - $5015.
+pub struct Mmc5Audio {
+    pulses: [Pulse; 2],
+    pcm: u8,
+    // $5010 bit 0, PCM samples are taken from CPU reads instead of $5011 writes
+    pcm_read_mode: bool,
+    frame_timer: u16,
+    odd_cycle: bool,
+}
+
+impl Mmc5Audio {
+    pub fn new() -> Self {
+        Mmc5Audio {
+            pulses: [Pulse::new(Sweep::None), Pulse::new(Sweep::None)],
+            pcm: 0,
+            pcm_read_mode: false,
+            frame_timer: FRAME_PERIOD,
+            odd_cycle: false,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5007 => {
+                let pulse = &mut self.pulses[((addr - 0x5000) / 4) as usize];
+                match addr & 0b11 {
+                    0 => pulse.write_control(data),
+                    // No sweep unit behind $5001 / $5005
+                    1 => {}
+                    2 => pulse.write_timer_low(data),
+                    _ => pulse.write_length(data),
+                }
+            }
+            0x5010 => self.pcm_read_mode = data & 0b1 != 0,
+            // Writing zero is ignored, it's reserved for the read mode IRQ
+            0x5011 if !self.pcm_read_mode && data != 0 => self.pcm = data,
+            0x5015 => {
+                self.pulses[0].length_counter.set_enabled(data & 0b01 != 0);
+                self.pulses[1].length_counter.set_enabled(data & 0b10 != 0);
+            }
+            _ => {}
+        }
+    }
+
+    // $5015: ---- --21
+    pub fn read_status(&self) -> u8 {
+        let mut status = 0;
+        if self.pulses[0].length_counter.active() {
+            status |= 0b01;
+        }
+        if self.pulses[1].length_counter.active() {
+            status |= 0b10;
+        }
+        status
+    }
+
+    // In read mode the PCM channel plays whatever the CPU reads from $8000 - $BFFF
+    pub fn cpu_read(&mut self, addr: u16, data: u8) {
+        if self.pcm_read_mode && (0x8000..=0xBFFF).contains(&addr) && data != 0 {
+            self.pcm = data;
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if self.odd_cycle {
+            for pulse in self.pulses.iter_mut() {
+                pulse.clock_timer();
+            }
+        }
+        self.odd_cycle = !self.odd_cycle;
+
+        self.frame_timer -= 1;
+        if self.frame_timer == 0 {
+            self.frame_timer = FRAME_PERIOD;
+            for pulse in self.pulses.iter_mut() {
+                pulse.envelope.clock();
+                pulse.length_counter.clock();
+            }
+        }
+    }
+
+    // The pulses go through the same resistor network as the APU's, the PCM channel is about
+    // as loud as the DMC
+    pub fn output(&self) -> f32 {
+        let pulses = self.pulses[0].output() + self.pulses[1].output();
+        pulse_level(pulses) + tnd_level(self.pcm as u16 / 2)
+    }
+}
+
+impl Default for Mmc5Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Mmc5Audio {
+    crate::savestate_fields!(pulses, pcm, pcm_read_mode, frame_timer, odd_cycle);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_length_counters_and_status() {
+        let mut audio = Mmc5Audio::new();
+        audio.write(0x5015, 0b11);
+        // Length index 3 is 2 frames
+        audio.write(0x5000, 0b1001_1111);
+        audio.write(0x5007, 0b0001_1000);
+        assert_eq!(audio.read_status(), 0b10);
+
+        for _ in 0..FRAME_PERIOD {
+            audio.clock();
+        }
+        assert_eq!(audio.read_status(), 0b10);
+        for _ in 0..FRAME_PERIOD {
+            audio.clock();
+        }
+        assert_eq!(audio.read_status(), 0);
+    }
+
+    #[test]
+    fn test_pcm_write_mode() {
+        let mut audio = Mmc5Audio::new();
+        audio.write(0x5011, 0x80);
+        assert_eq!(audio.pcm, 0x80);
+        audio.write(0x5011, 0x00);
+        assert_eq!(audio.pcm, 0x80);
+        assert!(audio.output() > 0.0);
+    }
+}
@@ -0,0 +1,129 @@
+pub mod fds_audio;
+pub mod fme7;
+pub mod mmc5;
+pub mod mmc5_audio;
+pub mod nrom;
+pub mod nsf;
+pub mod sunsoft5b_audio;
+pub mod namco163;
+pub mod namco163_audio;
+pub mod vrc;
+pub mod vrc6;
+pub mod vrc6_audio;
+pub mod vrc7;
+pub mod vrc7_audio;
+pub mod vrc_irq;
+
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::Savestate;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// Whether the PPU is currently fetching tiles for the background or for sprites.
+// Boards like the MMC5 bank the pattern tables differently for each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PpuFetch {
+    Background,
+    Sprite,
+}
+
+impl Savestate for PpuFetch {
+    fn save(&self, out: &mut Vec<u8>) {
+        (*self == PpuFetch::Sprite).save(out);
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        let mut sprite = false;
+        sprite.load(data)?;
+        *self = if sprite { PpuFetch::Sprite } else { PpuFetch::Background };
+        Ok(())
+    }
+}
+
+// A cartridge board. The CPU sees it from $4020 - $FFFF, the PPU sees it through the pattern
+// tables ($0000 - $1FFF) and, for boards that wire up their own nametables, $2000 - $2FFF.
+// Save states cover its registers and RAM, but not the ROM it was made from.
+pub trait Mapper: Savestate {
+    fn cpu_read(&mut self, addr: u16) -> u8;
+    fn cpu_write(&mut self, addr: u16, data: u8);
+
+    fn ppu_read(&mut self, addr: u16) -> u8;
+    fn ppu_write(&mut self, addr: u16, data: u8);
+
+    fn mirroring(&self) -> Mirroring;
+
+    // Nametable accesses get the console's 2 KiB of VRAM so boards can decide where each of the
+    // four logical nametables really lives. Most boards only pick a mirroring mode.
+    fn nametable_read(&mut self, addr: u16, vram: &[u8]) -> u8 {
+        vram[self.mirroring().vram_offset(addr)]
+    }
+
+    fn nametable_write(&mut self, addr: u16, data: u8, vram: &mut [u8]) {
+        vram[self.mirroring().vram_offset(addr)] = data;
+    }
+
+    // Pattern table accesses get the VRAM too, for the few boards that can map it in place of
+    // CHR. Everything else only has CHR there
+    fn pattern_read(&mut self, addr: u16, _vram: &[u8]) -> u8 {
+        self.ppu_read(addr)
+    }
+
+    fn pattern_write(&mut self, addr: u16, data: u8, _vram: &mut [u8]) {
+        self.ppu_write(addr, data);
+    }
+
+    // Boards that snoop the PPU registers ($2000 - $2007) see every CPU write to them
+    fn ppu_register_write(&mut self, _addr: u16, _data: u8) {}
+
+    // Called by the PPU before it starts fetching background or sprite data for a scanline
+    fn ppu_fetch(&mut self, _fetch: PpuFetch) {}
+
+    // Called by the PPU at the start of every scanline (0 - 261)
+    fn scanline(&mut self, _scanline: u16, _rendering: bool) {}
+
+    // Called once for every CPU cycle, for boards with cycle counting IRQs or sound chips
+    fn cpu_clock(&mut self) {}
+
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // The level of the cartridge's own sound chip, mixed in with the APU. In the same units as
+    // the APU mix, where one pulse channel at full volume comes out at about 0.149
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+
+    // The cartridge's PRG RAM, which is what gets kept in the .sav file on battery backed boards
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+}
+
+// The board's CHR ROM, or CHR RAM of the size given by the header when it has none.
+// Returns whether it is RAM, since only then do PPU writes go through.
+pub fn chr_memory(chr_rom: Vec<u8>, chr_ram_size: usize) -> (Vec<u8>, bool) {
+    if chr_rom.is_empty() {
+        // A header claiming neither CHR ROM nor RAM is wrong, give it the usual 8 KiB
+        let size = if chr_ram_size == 0 { 0x2000 } else { chr_ram_size };
+        (vec![0; size], true)
+    } else {
+        (chr_rom, false)
+    }
+}
+
+pub fn from_rom(rom: Rom) -> Result<Box<dyn Mapper>, String> {
+    match rom.mapper {
+        0 => Ok(Box::new(nrom::Nrom::new(rom))),
+        5 => Ok(Box::new(mmc5::Mmc5::new(rom))),
+        19 => Ok(Box::new(namco163::Namco163::new(rom))),
+        21 | 22 | 23 | 25 => Ok(Box::new(vrc::Vrc::new(rom))),
+        24 | 26 => Ok(Box::new(vrc6::Vrc6::new(rom))),
+        69 => Ok(Box::new(fme7::Fme7::new(rom))),
+        85 => Ok(Box::new(vrc7::Vrc7::new(rom))),
+        mapper => Err(format!("Mapper {} is not supported", mapper)),
+    }
+}
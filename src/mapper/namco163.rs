@@ -0,0 +1,323 @@
+use super::namco163_audio::Namco163Audio;
+use super::{chr_memory, Mapper};
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::Savestate;
+use alloc::vec::Vec;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// Bank numbers from $E0 up select one of the console's VRAM pages instead of CHR ROM
+const CIRAM_BANK: u8 = 0xE0;
+
+// Mapper 19, the Namco 129 and 163. Nametables can be pointed at CHR ROM, which games use for
+// large static backgrounds.
+pub struct Namco163 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_ram: [u8; PRG_RAM_SIZE],
+
+    // $F800: the sound chip's internal RAM address, doubling as PRG RAM write protect
+    address_port: u8,
+
+    chr_banks: [u8; 8],
+    nametable_banks: [u8; 4],
+    prg_banks: [u8; 3],
+    // $E800 bits 6 and 7. When set, banks >= $E0 in that pattern table are CHR ROM after all
+    ciram_disabled: [bool; 2],
+
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    // $E000 bit 6
+    sound_disabled: bool,
+    audio: Namco163Audio,
+}
+
+impl Namco163 {
+    pub fn new(rom: Rom) -> Self {
+        // Until the game sets them, use the header's mirroring for the nametables
+        let nametable_banks = match rom.screen_mirroring {
+            Mirroring::Horizontal => [0xE0, 0xE0, 0xE1, 0xE1],
+            _ => [0xE0, 0xE1, 0xE0, 0xE1],
+        };
+
+        let (chr, chr_is_ram) = chr_memory(rom.chr_rom, rom.chr_ram_size);
+
+        Namco163 {
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            prg_ram: [0; PRG_RAM_SIZE],
+            address_port: 0,
+            chr_banks: [0; 8],
+            nametable_banks,
+            prg_banks: [0; 3],
+            ciram_disabled: [false; 2],
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            sound_disabled: false,
+            audio: Namco163Audio::new(),
+        }
+    }
+
+    fn prg_ram_writable(&self, addr: u16) -> bool {
+        // The upper nibble of $F800 must be 0b0100, then each low bit protects a 2 KiB window
+        let window = (addr - 0x6000) / 0x800;
+        self.address_port & 0xF0 == 0x40 && self.address_port & (1 << window) == 0
+    }
+
+    fn chr_offset(&self, bank: u8, addr: u16) -> usize {
+        (bank as usize * CHR_BANK_SIZE + addr as usize % CHR_BANK_SIZE) % self.chr.len()
+    }
+
+    // Pattern table banks >= $E0 are VRAM pages, unless $E800 has turned VRAM off for that half
+    // of the pattern tables. Only the tests reach this until there's a PPU
+    fn pattern_vram_offset(&self, addr: u16) -> Option<usize> {
+        let bank = self.chr_banks[(addr as usize / CHR_BANK_SIZE) & 7];
+        let half = (addr as usize >> 12) & 1;
+        if bank >= CIRAM_BANK && !self.ciram_disabled[half] {
+            Some((bank & 1) as usize * 0x400 + (addr & 0x3FF) as usize)
+        } else {
+            None
+        }
+    }
+}
+
+impl Mapper for Namco163 {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4800..=0x4FFF => self.audio.read_data(),
+            0x5000..=0x57FF => self.irq_counter as u8,
+            0x5800..=0x5FFF => {
+                let enabled = if self.irq_enabled { 0x80 } else { 0 };
+                enabled | (self.irq_counter >> 8) as u8
+            }
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xFFFF => {
+                let banks = self.prg_rom.len() / PRG_BANK_SIZE;
+                let slot = ((addr - 0x8000) / 0x2000) as usize;
+                let bank = if slot < 3 { self.prg_banks[slot] as usize } else { banks - 1 };
+                self.prg_rom[(bank % banks) * PRG_BANK_SIZE + addr as usize % PRG_BANK_SIZE]
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4800..=0x4FFF => self.audio.write_data(data),
+            0x5000..=0x57FF => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | data as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | ((data & 0x7F) as u16) << 8;
+                self.irq_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x6000..=0x7FFF if self.prg_ram_writable(addr) => {
+                self.prg_ram[(addr - 0x6000) as usize] = data;
+            }
+            0x8000..=0xBFFF => self.chr_banks[((addr - 0x8000) / 0x800) as usize] = data,
+            0xC000..=0xDFFF => self.nametable_banks[((addr - 0xC000) / 0x800) as usize] = data,
+            0xE000..=0xE7FF => {
+                self.prg_banks[0] = data & 0x3F;
+                self.sound_disabled = data & 0x40 != 0;
+            }
+            0xE800..=0xEFFF => {
+                self.prg_banks[1] = data & 0x3F;
+                self.ciram_disabled = [data & 0x40 != 0, data & 0x80 != 0];
+            }
+            0xF000..=0xF7FF => self.prg_banks[2] = data & 0x3F,
+            0xF800..=0xFFFF => {
+                self.address_port = data;
+                self.audio.set_address(data);
+            }
+            _ => {}
+        }
+    }
+
+    // Without the VRAM to hand every bank reads CHR, see `pattern_read`
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        let bank = self.chr_banks[(addr as usize / CHR_BANK_SIZE) & 7];
+        self.chr[self.chr_offset(bank, addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let bank = self.chr_banks[(addr as usize / CHR_BANK_SIZE) & 7];
+            let offset = self.chr_offset(bank, addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn pattern_read(&mut self, addr: u16, vram: &[u8]) -> u8 {
+        match self.pattern_vram_offset(addr) {
+            Some(offset) => vram[offset],
+            None => self.ppu_read(addr),
+        }
+    }
+
+    fn pattern_write(&mut self, addr: u16, data: u8, vram: &mut [u8]) {
+        match self.pattern_vram_offset(addr) {
+            Some(offset) => vram[offset] = data,
+            None => self.ppu_write(addr, data),
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        // Only an approximation, the nametable hooks below are authoritative
+        match self.nametable_banks {
+            [0xE0, 0xE0, 0xE1, 0xE1] => Mirroring::Horizontal,
+            _ => Mirroring::Vertical,
+        }
+    }
+
+    fn nametable_read(&mut self, addr: u16, vram: &[u8]) -> u8 {
+        let bank = self.nametable_banks[((addr >> 10) & 0b11) as usize];
+        if bank >= CIRAM_BANK {
+            vram[(bank & 1) as usize * 0x400 + (addr & 0x3FF) as usize]
+        } else {
+            self.chr[self.chr_offset(bank, addr)]
+        }
+    }
+
+    fn nametable_write(&mut self, addr: u16, data: u8, vram: &mut [u8]) {
+        let bank = self.nametable_banks[((addr >> 10) & 0b11) as usize];
+        if bank >= CIRAM_BANK {
+            vram[(bank & 1) as usize * 0x400 + (addr & 0x3FF) as usize] = data;
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        if !self.sound_disabled {
+            self.audio.clock();
+        }
+
+        // Counts up and stops once it reaches $7FFF
+        if self.irq_enabled && self.irq_counter < 0x7FFF {
+            self.irq_counter += 1;
+            if self.irq_counter == 0x7FFF {
+                self.irq_pending = true;
+            }
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio_output(&self) -> f32 {
+        if self.sound_disabled {
+            0.0
+        } else {
+            self.audio.output()
+        }
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+impl Savestate for Namco163 {
+    crate::savestate_fields!(
+        chr if chr_is_ram,
+        prg_ram,
+        address_port,
+        chr_banks,
+        nametable_banks,
+        prg_banks,
+        ciram_disabled,
+        irq_counter,
+        irq_enabled,
+        irq_pending,
+        sound_disabled,
+        audio,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_chr_rom_nametables() {
+        let mut rom = test_rom(19, 2, 1);
+        for (i, byte) in rom.chr_rom.iter_mut().enumerate() {
+            *byte = (i / CHR_BANK_SIZE) as u8;
+        }
+        let mut mapper = Namco163::new(rom);
+        let mut vram = [0; 0x800];
+
+        // $2000 from CHR ROM bank 5, $2400 from the second VRAM page
+        mapper.cpu_write(0xC000, 0x05);
+        mapper.cpu_write(0xC800, 0xE1);
+        mapper.nametable_write(0x2000, 0x42, &mut vram);
+        mapper.nametable_write(0x2410, 0x42, &mut vram);
+
+        assert_eq!(mapper.nametable_read(0x2000, &vram), 5);
+        assert_eq!(vram[0x410], 0x42);
+        assert_eq!(mapper.nametable_read(0x2410, &vram), 0x42);
+    }
+
+    #[test]
+    fn test_vram_pattern_banks() {
+        let mut rom = test_rom(19, 2, 1);
+        rom.chr_rom.fill(7);
+        let mut mapper = Namco163::new(rom);
+        let mut vram = [0; 0x800];
+
+        // $0000 and $1000 both from the second VRAM page
+        mapper.cpu_write(0x8000, 0xE1);
+        mapper.cpu_write(0xA000, 0xE1);
+        mapper.pattern_write(0x0010, 0x42, &mut vram);
+        assert_eq!(vram[0x410], 0x42);
+        assert_eq!(mapper.pattern_read(0x1010, &vram), 0x42);
+
+        // Turning VRAM off for $1000 - $1FFF leaves it reading CHR
+        mapper.cpu_write(0xE800, 0x80);
+        assert_eq!(mapper.pattern_read(0x0010, &vram), 0x42);
+        assert_eq!(mapper.pattern_read(0x1010, &vram), 7);
+    }
+
+    #[test]
+    fn test_cycle_irq() {
+        let mut mapper = Namco163::new(test_rom(19, 2, 1));
+        mapper.cpu_write(0x5000, 0xFD);
+        mapper.cpu_write(0x5800, 0x80 | 0x7F);
+
+        mapper.cpu_clock();
+        assert!(!mapper.irq_pending());
+        mapper.cpu_clock();
+        assert!(mapper.irq_pending());
+        assert_eq!(mapper.cpu_read(0x5800), 0xFF);
+
+        // Stays put once it has fired
+        mapper.cpu_clock();
+        assert_eq!(mapper.cpu_read(0x5000), 0xFF);
+
+        mapper.cpu_write(0x5000, 0);
+        assert!(!mapper.irq_pending());
+    }
+
+    #[test]
+    fn test_internal_ram_auto_increment() {
+        let mut mapper = Namco163::new(test_rom(19, 2, 1));
+        mapper.cpu_write(0xF800, 0x80 | 0x10);
+        mapper.cpu_write(0x4800, 0x11);
+        mapper.cpu_write(0x4800, 0x22);
+
+        mapper.cpu_write(0xF800, 0x10);
+        assert_eq!(mapper.cpu_read(0x4800), 0x11);
+        assert_eq!(mapper.cpu_read(0x4800), 0x11);
+        mapper.cpu_write(0xF800, 0x11);
+        assert_eq!(mapper.cpu_read(0x4800), 0x22);
+    }
+}
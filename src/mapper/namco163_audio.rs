@@ -0,0 +1,175 @@
+use crate::savestate::Savestate;
+
+const INTERNAL_RAM_SIZE: usize = 0x80;
+// The sound channels take turns, one is updated every 15 CPU cycles
+const CHANNEL_CYCLES: u8 = 15;
+// Channel registers are 8 bytes each at the top of internal RAM, channel 7 first at $78
+const CHANNEL_REGISTERS: usize = 0x40;
+// A single channel playing a full volume square wave comes out at about twice an APU pulse
+const LEVEL_STEP: f32 = 0.3 / 225.0;
+
+// The Namco 163's up to eight wavetable channels. Waves and channel registers both live in 128
+// bytes of internal RAM, accessed through a data port at $4800 and an address port at $F800.
+pub struct Namco163Audio {
+    // Shared between the waves, the channel registers and, on some boards, save data
+    internal_ram: [u8; INTERNAL_RAM_SIZE],
+    // Internal RAM address with auto increment in bit 7
+    address: u8,
+
+    timer: u8,
+    // Which of the enabled channels is updated next, counting down from 7
+    channel: usize,
+    // Each channel's last output, -120 - 105
+    channel_outputs: [i16; 8],
+}
+
+impl Namco163Audio {
+    pub fn new() -> Self {
+        Namco163Audio {
+            internal_ram: [0; INTERNAL_RAM_SIZE],
+            address: 0,
+            timer: CHANNEL_CYCLES,
+            channel: 7,
+            channel_outputs: [0; 8],
+        }
+    }
+
+    // $F800
+    pub fn set_address(&mut self, data: u8) {
+        self.address = data;
+    }
+
+    fn next_address(&mut self) -> usize {
+        let addr = (self.address & 0x7F) as usize;
+        if self.address & 0x80 != 0 {
+            self.address = 0x80 | (self.address.wrapping_add(1) & 0x7F);
+        }
+        addr
+    }
+
+    // $4800
+    pub fn read_data(&mut self) -> u8 {
+        let addr = self.next_address();
+        self.internal_ram[addr]
+    }
+
+    pub fn write_data(&mut self, data: u8) {
+        let addr = self.next_address();
+        self.internal_ram[addr] = data;
+    }
+
+    // $7F bits 4 - 6 hold the number of enabled channels minus one. They are the highest ones,
+    // so with 2 enabled channels 7 and 6 play
+    fn enabled_channels(&self) -> usize {
+        ((self.internal_ram[0x7F] >> 4) & 0b111) as usize + 1
+    }
+
+    // Advances one channel's phase and works out its new output
+    fn update_channel(&mut self, channel: usize) {
+        let base = CHANNEL_REGISTERS + channel * 8;
+        let regs = &self.internal_ram[base..base + 8];
+
+        let frequency = regs[0] as u32 | (regs[2] as u32) << 8 | ((regs[4] & 0b11) as u32) << 16;
+        let phase = regs[1] as u32 | (regs[3] as u32) << 8 | (regs[5] as u32) << 16;
+        // The wave is 256 - 4 * L samples long, the phase wraps at that many 16.16 samples
+        let length = 256 - (regs[4] & 0xFC) as u32;
+        let wave_address = regs[6] as u32;
+        let volume = (regs[7] & 0x0F) as i16;
+
+        let phase = (phase + frequency) % (length << 16);
+        self.internal_ram[base + 1] = phase as u8;
+        self.internal_ram[base + 3] = (phase >> 8) as u8;
+        self.internal_ram[base + 5] = (phase >> 16) as u8;
+
+        // Samples are 4 bits, packed two to a byte with the lower address in the low nibble
+        let sample_address = ((phase >> 16) + wave_address) & 0xFF;
+        let byte = self.internal_ram[(sample_address / 2) as usize];
+        let sample = if sample_address & 1 == 0 { byte & 0x0F } else { byte >> 4 };
+
+        self.channel_outputs[channel] = (sample as i16 - 8) * volume;
+    }
+
+    pub fn clock(&mut self) {
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = CHANNEL_CYCLES;
+
+        let lowest = 8 - self.enabled_channels();
+        if self.channel < lowest {
+            self.channel = 7;
+        }
+        self.update_channel(self.channel);
+        self.channel = if self.channel == lowest { 7 } else { self.channel - 1 };
+    }
+
+    // The real chip outputs one channel at a time, switching every 15 cycles. With all eight
+    // enabled that's an audible whine at ~15 kHz, so like most emulators we average instead.
+    pub fn output(&self) -> f32 {
+        let channels = self.enabled_channels();
+        let sum: i16 = self.channel_outputs[8 - channels..].iter().sum();
+        sum as f32 * LEVEL_STEP / channels as f32
+    }
+}
+
+impl Default for Namco163Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Namco163Audio {
+    crate::savestate_fields!(internal_ram, address, timer, channel, channel_outputs);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wavetable_channel() {
+        let mut audio = Namco163Audio::new();
+        // A 4 sample wave at address 0: 15, 15, 0, 0
+        audio.set_address(0x80);
+        audio.write_data(0xFF);
+        audio.write_data(0x00);
+
+        // Channel 7 stepping one sample per update ($10000 in 16.16), length 4 and full volume
+        audio.set_address(0x80 | 0x78);
+        for data in [0x00, 0x00, 0x00, 0x00, 0xFC | 0x01, 0x00, 0x00, 0x0F] {
+            audio.write_data(data);
+        }
+
+        let mut outputs = Vec::new();
+        for _ in 0..4 {
+            for _ in 0..CHANNEL_CYCLES {
+                audio.clock();
+            }
+            outputs.push(audio.channel_outputs[7]);
+        }
+        assert_eq!(outputs, [7 * 15, -8 * 15, -8 * 15, 7 * 15]);
+        assert!(audio.output() > 0.0);
+    }
+
+    #[test]
+    fn test_internal_ram_auto_increment() {
+        let mut audio = Namco163Audio::new();
+        audio.set_address(0x80 | 0x10);
+        audio.write_data(0x11);
+        audio.write_data(0x22);
+
+        audio.set_address(0x10);
+        assert_eq!(audio.read_data(), 0x11);
+        assert_eq!(audio.read_data(), 0x11);
+        audio.set_address(0x11);
+        assert_eq!(audio.read_data(), 0x22);
+
+        // Wraps within the 128 bytes
+        audio.set_address(0x80 | 0x7F);
+        audio.write_data(0x33);
+        audio.write_data(0x44);
+        audio.set_address(0x00);
+        assert_eq!(audio.read_data(), 0x44);
+    }
+}
@@ -0,0 +1,69 @@
+use super::{chr_memory, Mapper};
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::Savestate;
+use alloc::vec::Vec;
+
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// Mapper 0, no bank switching at all. 16 KiB PRG ROM is mirrored into $C000 - $FFFF.
+// Homebrew often pairs it with CHR RAM instead of ROM.
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_is_ram) = chr_memory(rom.chr_rom, rom.chr_ram_size);
+
+        Nrom {
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            prg_ram: [0; PRG_RAM_SIZE],
+            mirroring: rom.screen_mirroring,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xFFFF => self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            self.prg_ram[(addr - 0x6000) as usize] = data;
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[addr as usize % self.chr.len()]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let offset = addr as usize % self.chr.len();
+            self.chr[offset] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+impl Savestate for Nrom {
+    crate::savestate_fields!(chr if chr_is_ram, prg_ram, mirroring);
+}
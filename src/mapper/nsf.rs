@@ -0,0 +1,212 @@
+use super::fds_audio::FdsAudio;
+use super::mmc5_audio::Mmc5Audio;
+use super::namco163_audio::Namco163Audio;
+use super::sunsoft5b_audio::Sunsoft5bAudio;
+use super::vrc6_audio::Vrc6Audio;
+use super::vrc7_audio::Vrc7Audio;
+use super::Mapper;
+use crate::cartridge::Mirroring;
+use crate::nsf::{ExpansionChips, Nsf};
+use crate::savestate::Savestate;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const PRG_BANK_SIZE: usize = 0x1000;
+const WRAM_SIZE: usize = 0x2000;
+const EXRAM_SIZE: usize = 0x400;
+
+// The hardware an NSF player provides: the music data in 4 KiB banks at $8000 - $FFFF switched
+// through $5FF8 - $5FFF, 8 KiB of RAM at $6000, and whichever sound chips the file asks for at
+// their usual register addresses. FDS tunes also get to write to their data at $8000 - $DFFF,
+// which is RAM on the Disk System. There's no PPU side.
+pub struct NsfMapper {
+    prg: Vec<u8>,
+    banks: [u8; 8],
+    wram: [u8; WRAM_SIZE],
+
+    vrc6: Option<Vrc6Audio>,
+    vrc7: Option<Vrc7Audio>,
+    mmc5: Option<Mmc5Audio>,
+    namco163: Option<Namco163Audio>,
+    sunsoft5b: Option<Sunsoft5bAudio>,
+    fds: Option<FdsAudio>,
+
+    // MMC5 tunes may also use its multiplier and ExRAM
+    exram: [u8; EXRAM_SIZE],
+    multiplicand: u8,
+    multiplier: u8,
+}
+
+impl NsfMapper {
+    pub fn new(nsf: &Nsf) -> Self {
+        // Bankswitched data starts at the load address' offset into its bank. Otherwise it is
+        // loaded at the load address, which works the same as banks 0 - 7 with more padding
+        let (padding, banks) = match nsf.banks {
+            Some(banks) => ((nsf.load_address & 0x0FFF) as usize, banks),
+            None => ((nsf.load_address - 0x8000) as usize, [0, 1, 2, 3, 4, 5, 6, 7]),
+        };
+
+        let mut prg = vec![0; padding];
+        prg.extend_from_slice(&nsf.data);
+        prg.resize(prg.len().div_ceil(PRG_BANK_SIZE).max(1) * PRG_BANK_SIZE, 0);
+
+        let chips = nsf.chips;
+        NsfMapper {
+            prg,
+            banks,
+            wram: [0; WRAM_SIZE],
+            vrc6: chips.contains(ExpansionChips::VRC6).then(Vrc6Audio::new),
+            vrc7: chips.contains(ExpansionChips::VRC7).then(Vrc7Audio::new),
+            mmc5: chips.contains(ExpansionChips::MMC5).then(Mmc5Audio::new),
+            namco163: chips.contains(ExpansionChips::NAMCO163).then(Namco163Audio::new),
+            sunsoft5b: chips.contains(ExpansionChips::SUNSOFT5B).then(Sunsoft5bAudio::new),
+            fds: chips.contains(ExpansionChips::FDS).then(FdsAudio::new),
+            exram: [0; EXRAM_SIZE],
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+        }
+    }
+
+    fn prg_offset(&self, addr: u16) -> usize {
+        let banks = self.prg.len() / PRG_BANK_SIZE;
+        let bank = self.banks[(addr - 0x8000) as usize / PRG_BANK_SIZE] as usize % banks;
+        bank * PRG_BANK_SIZE + addr as usize % PRG_BANK_SIZE
+    }
+}
+
+impl Mapper for NsfMapper {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        let mmc5 = self.mmc5.is_some();
+        match addr {
+            0x4040..=0x4092 => self.fds.as_mut().map_or(0, |audio| audio.read(addr)),
+            0x4800..=0x4FFF => self.namco163.as_mut().map_or(0, |audio| audio.read_data()),
+            0x5015 => self.mmc5.as_ref().map_or(0, |audio| audio.read_status()),
+            0x5205 if mmc5 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+            0x5206 if mmc5 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+            0x5C00..=0x5FF5 if mmc5 => self.exram[(addr - 0x5C00) as usize],
+            0x6000..=0x7FFF => self.wram[(addr - 0x6000) as usize],
+            0x8000..=0xFFFF => {
+                let data = self.prg[self.prg_offset(addr)];
+                if let Some(audio) = self.mmc5.as_mut() {
+                    audio.cpu_read(addr, data);
+                }
+                data
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        let mmc5 = self.mmc5.is_some();
+        match addr {
+            0x5205 if mmc5 => self.multiplicand = data,
+            0x5206 if mmc5 => self.multiplier = data,
+            0x5C00..=0x5FF5 if mmc5 => self.exram[(addr - 0x5C00) as usize] = data,
+            0x5FF8..=0x5FFF => self.banks[(addr - 0x5FF8) as usize] = data,
+            0x6000..=0x7FFF => self.wram[(addr - 0x6000) as usize] = data,
+            0x8000..=0xDFFF if self.fds.is_some() => {
+                let offset = self.prg_offset(addr);
+                self.prg[offset] = data;
+            }
+            _ => {}
+        }
+
+        // Everything else is a sound chip register, ignored unless the file uses that chip
+        if let Some(audio) = self.vrc6.as_mut() {
+            if let 0x9000..=0xB002 = addr {
+                audio.write(addr, data);
+            }
+        }
+        if let Some(audio) = self.vrc7.as_mut() {
+            match addr {
+                0x9010 => audio.select(data),
+                0x9030 => audio.write(data),
+                _ => {}
+            }
+        }
+        if let Some(audio) = self.mmc5.as_mut() {
+            if let 0x5000..=0x5015 = addr {
+                audio.write(addr, data);
+            }
+        }
+        if let Some(audio) = self.namco163.as_mut() {
+            match addr {
+                0x4800..=0x4FFF => audio.write_data(data),
+                0xF800..=0xFFFF => audio.set_address(data),
+                _ => {}
+            }
+        }
+        if let Some(audio) = self.sunsoft5b.as_mut() {
+            match addr {
+                0xC000 => audio.select(data),
+                0xE000 => audio.write(data),
+                _ => {}
+            }
+        }
+        if let Some(audio) = self.fds.as_mut() {
+            if let 0x4040..=0x408A = addr {
+                audio.write(addr, data);
+            }
+        }
+    }
+
+    fn ppu_read(&mut self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn ppu_write(&mut self, _addr: u16, _data: u8) {}
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Vertical
+    }
+
+    fn cpu_clock(&mut self) {
+        if let Some(audio) = self.vrc6.as_mut() {
+            audio.clock();
+        }
+        if let Some(audio) = self.vrc7.as_mut() {
+            audio.clock();
+        }
+        if let Some(audio) = self.mmc5.as_mut() {
+            audio.clock();
+        }
+        if let Some(audio) = self.namco163.as_mut() {
+            audio.clock();
+        }
+        if let Some(audio) = self.sunsoft5b.as_mut() {
+            audio.clock();
+        }
+        if let Some(audio) = self.fds.as_mut() {
+            audio.clock();
+        }
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.vrc6.as_ref().map_or(0.0, |audio| audio.output())
+            + self.vrc7.as_ref().map_or(0.0, |audio| audio.output())
+            + self.mmc5.as_ref().map_or(0.0, |audio| audio.output())
+            + self.namco163.as_ref().map_or(0.0, |audio| audio.output())
+            + self.sunsoft5b.as_ref().map_or(0.0, |audio| audio.output())
+            + self.fds.as_ref().map_or(0.0, |audio| audio.output())
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.wram)
+    }
+}
+
+impl Savestate for NsfMapper {
+    crate::savestate_fields!(
+        banks,
+        wram,
+        vrc6,
+        vrc7,
+        mmc5,
+        namco163,
+        sunsoft5b,
+        fds,
+        exram,
+        multiplicand,
+        multiplier,
+    );
+}
@@ -0,0 +1,300 @@
+use crate::math;
+use crate::savestate::Savestate;
+
+// A tone channel at full volume is a little louder than an APU pulse at full volume
+const CHANNEL_LEVEL: f32 = 0.2;
+// The tone, noise and envelope generators step every 16 CPU cycles
+const PRESCALER: u8 = 16;
+
+// The 5B's DAC is logarithmic, 1.5 dB per step of its 5 bit level
+fn level_to_amplitude(level: u8) -> f32 {
+    if level == 0 {
+        0.0
+    } else {
+        math::powf(10.0, -1.5 * (31 - level) as f32 / 20.0)
+    }
+}
+
+// The FME-7 variant with a YM2149F (a licensed AY-3-8910) inside. Three square wave channels
+// that can each mix in a shared noise generator and use a shared envelope, at $C000 (register
+// select) and $E000 (register write).
+pub struct Sunsoft5bAudio {
+    select: u8,
+
+    tone_periods: [u16; 3],
+    tone_timers: [u16; 3],
+    tone_outputs: [bool; 3],
+
+    noise_period: u8,
+    noise_timer: u8,
+    noise_lfsr: u32,
+
+    // Register 7, active low: bits 0 - 2 disable tone and 3 - 5 noise
+    mixer: u8,
+    // Registers 8 - 10: ---E VVVV, E uses the envelope instead of the fixed volume
+    volumes: [u8; 3],
+
+    envelope_period: u16,
+    envelope_timer: u16,
+    // Register 13: continue, attack, alternate, hold
+    envelope_shape: u8,
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+
+    prescaler: u8,
+    amplitudes: [f32; 32],
+}
+
+impl Sunsoft5bAudio {
+    pub fn new() -> Self {
+        let mut amplitudes = [0.0; 32];
+        for (level, amplitude) in amplitudes.iter_mut().enumerate() {
+            *amplitude = level_to_amplitude(level as u8);
+        }
+
+        Sunsoft5bAudio {
+            select: 0,
+            tone_periods: [0; 3],
+            tone_timers: [0; 3],
+            tone_outputs: [false; 3],
+            noise_period: 0,
+            noise_timer: 0,
+            noise_lfsr: 1,
+            mixer: 0xFF,
+            volumes: [0; 3],
+            envelope_period: 0,
+            envelope_timer: 0,
+            envelope_shape: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: true,
+            prescaler: PRESCALER,
+            amplitudes,
+        }
+    }
+
+    // $C000
+    pub fn select(&mut self, data: u8) {
+        self.select = data & 0x0F;
+    }
+
+    // $E000, writes the selected register
+    pub fn write(&mut self, data: u8) {
+        match self.select {
+            reg @ (0 | 2 | 4) => {
+                let channel = (reg / 2) as usize;
+                self.tone_periods[channel] = (self.tone_periods[channel] & 0xF00) | data as u16;
+            }
+            reg @ (1 | 3 | 5) => {
+                let channel = (reg / 2) as usize;
+                let high = ((data & 0x0F) as u16) << 8;
+                self.tone_periods[channel] = (self.tone_periods[channel] & 0x0FF) | high;
+            }
+            6 => self.noise_period = data & 0x1F,
+            7 => self.mixer = data,
+            reg @ 8..=10 => self.volumes[(reg - 8) as usize] = data & 0x1F,
+            11 => self.envelope_period = (self.envelope_period & 0xFF00) | data as u16,
+            12 => self.envelope_period = (self.envelope_period & 0x00FF) | (data as u16) << 8,
+            13 => {
+                // Writing the shape restarts the envelope
+                self.envelope_shape = data & 0x0F;
+                self.envelope_step = 0;
+                self.envelope_timer = 0;
+                self.envelope_attack = data & 0b0100 != 0;
+                self.envelope_holding = false;
+            }
+            // The I/O ports aren't connected to anything
+            _ => {}
+        }
+    }
+
+    pub fn clock(&mut self) {
+        self.prescaler -= 1;
+        if self.prescaler > 0 {
+            return;
+        }
+        self.prescaler = PRESCALER;
+
+        for channel in 0..3 {
+            self.tone_timers[channel] += 1;
+            if self.tone_timers[channel] >= self.tone_periods[channel] {
+                self.tone_timers[channel] = 0;
+                self.tone_outputs[channel] = !self.tone_outputs[channel];
+            }
+        }
+
+        self.noise_timer += 1;
+        if self.noise_timer >= self.noise_period {
+            self.noise_timer = 0;
+            // 17 bit LFSR tapping bits 0 and 3
+            let feedback = (self.noise_lfsr ^ (self.noise_lfsr >> 3)) & 1;
+            self.noise_lfsr = (self.noise_lfsr >> 1) | (feedback << 16);
+        }
+
+        self.envelope_timer += 1;
+        if self.envelope_timer >= self.envelope_period {
+            self.envelope_timer = 0;
+            self.clock_envelope();
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+
+        self.envelope_step += 1;
+        if self.envelope_step < 32 {
+            return;
+        }
+
+        let continues = self.envelope_shape & 0b1000 != 0;
+        let alternate = self.envelope_shape & 0b0010 != 0;
+        let hold = self.envelope_shape & 0b0001 != 0;
+
+        if !continues {
+            // Drops to silence and stays there
+            self.envelope_holding = true;
+            self.envelope_attack = false;
+            self.envelope_step = 31;
+        } else if hold {
+            self.envelope_holding = true;
+            self.envelope_step = 31;
+            if alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+        } else {
+            self.envelope_step = 0;
+            if alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+        }
+    }
+
+    fn envelope_level(&self) -> u8 {
+        if self.envelope_attack {
+            self.envelope_step
+        } else {
+            31 - self.envelope_step
+        }
+    }
+
+    pub fn output(&self) -> f32 {
+        let noise = self.noise_lfsr & 1 != 0;
+
+        let mut output = 0.0;
+        for channel in 0..3 {
+            let tone_off = self.mixer & (1 << channel) != 0;
+            let noise_off = self.mixer & (8 << channel) != 0;
+            if !((tone_off || self.tone_outputs[channel]) && (noise_off || noise)) {
+                continue;
+            }
+
+            let volume = self.volumes[channel];
+            let level = if volume & 0x10 != 0 {
+                self.envelope_level()
+            } else if volume & 0x0F == 0 {
+                0
+            } else {
+                // Fixed volumes land on the odd steps of the envelope's scale
+                (volume & 0x0F) * 2 + 1
+            };
+            output += self.amplitudes[level as usize];
+        }
+        output * CHANNEL_LEVEL
+    }
+}
+
+impl Default for Sunsoft5bAudio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for Sunsoft5bAudio {
+    crate::savestate_fields!(
+        select,
+        tone_periods,
+        tone_timers,
+        tone_outputs,
+        noise_period,
+        noise_timer,
+        noise_lfsr,
+        mixer,
+        volumes,
+        envelope_period,
+        envelope_timer,
+        envelope_shape,
+        envelope_step,
+        envelope_attack,
+        envelope_holding,
+        prescaler,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(audio: &mut Sunsoft5bAudio, reg: u8, data: u8) {
+        audio.select(reg);
+        audio.write(data);
+    }
+
+    #[test]
+    fn test_tone_frequency() {
+        let mut audio = Sunsoft5bAudio::new();
+        // Channel A only, full volume, period 4: toggles every 4 * 16 CPU cycles
+        write(&mut audio, 7, 0b1111_1110);
+        write(&mut audio, 8, 0x0F);
+        write(&mut audio, 0, 4);
+
+        let mut toggles = 0;
+        let mut last = audio.output();
+        for _ in 0..(64 * 10) {
+            audio.clock();
+            if audio.output() != last {
+                toggles += 1;
+                last = audio.output();
+            }
+        }
+        assert_eq!(toggles, 10);
+        assert!((audio.amplitudes[31] - 1.0).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn test_envelope_decay_holds_at_zero() {
+        let mut audio = Sunsoft5bAudio::new();
+        write(&mut audio, 11, 1);
+        // Shape 0: decay once then stay silent
+        write(&mut audio, 13, 0);
+        assert_eq!(audio.envelope_level(), 31);
+
+        for _ in 0..(16 * 40) {
+            audio.clock();
+        }
+        assert_eq!(audio.envelope_level(), 0);
+    }
+
+    // Runs the envelope well past its first ramp with the given shape
+    fn held_level(shape: u8) -> u8 {
+        let mut audio = Sunsoft5bAudio::new();
+        write(&mut audio, 11, 1);
+        write(&mut audio, 13, shape);
+        for _ in 0..(16 * 100) {
+            audio.clock();
+        }
+        audio.envelope_level()
+    }
+
+    #[test]
+    fn test_envelope_hold_shapes() {
+        // $B: decay then hold at the top
+        assert_eq!(held_level(0x0B), 31);
+        // $D: attack then hold at the top
+        assert_eq!(held_level(0x0D), 31);
+        // $F: attack then hold at zero
+        assert_eq!(held_level(0x0F), 0);
+    }
+}
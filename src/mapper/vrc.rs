@@ -0,0 +1,273 @@
+use super::vrc_irq::VrcIrq;
+use super::{chr_memory, Mapper};
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::Savestate;
+use alloc::vec::Vec;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// The Konami VRCs select their registers with two CPU address lines, but which two depends on
+// the board. Each mask lists the address bits wired to a register select pin. When the submapper
+// doesn't tell us the wiring we OR together every possibility, games only ever touch the
+// addresses that are valid for their own board so this is harmless.
+#[derive(Debug, Clone, Copy)]
+pub struct AddressLines {
+    a0: u16,
+    a1: u16,
+}
+
+impl AddressLines {
+    pub const fn new(a0: u16, a1: u16) -> Self {
+        AddressLines { a0, a1 }
+    }
+
+    // Collapses an address to $x000 - $x003 in the order the chip sees it
+    pub fn translate(&self, addr: u16) -> u16 {
+        let mut reg = addr & 0xF000;
+        if addr & self.a0 != 0 {
+            reg |= 0b01;
+        }
+        if addr & self.a1 != 0 {
+            reg |= 0b10;
+        }
+        reg
+    }
+}
+
+// Mappers 21, 22, 23 and 25. The VRC2 is a cut down VRC4 without the IRQ counter,
+// PRG swap mode or single screen mirroring.
+pub struct Vrc {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    lines: AddressLines,
+    vrc2: bool,
+    // VRC2a (mapper 22) ignores the lowest bit of its CHR bank numbers
+    chr_shift: u8,
+
+    prg_banks: [u8; 2],
+    prg_swap: bool,
+    chr_banks: [u16; 8],
+    mirroring: Mirroring,
+    irq: VrcIrq,
+}
+
+impl Vrc {
+    pub fn new(rom: Rom) -> Self {
+        // (a0 mask, a1 mask, is a VRC2)
+        let (a0, a1, vrc2) = match (rom.mapper, rom.submapper) {
+            (21, 1) => (0x02, 0x04, false), // VRC4a
+            (21, 2) => (0x40, 0x80, false), // VRC4c
+            (21, _) => (0x42, 0x84, false),
+            (22, _) => (0x02, 0x01, true), // VRC2a
+            (23, 1) => (0x01, 0x02, false), // VRC4f
+            (23, 2) => (0x04, 0x08, false), // VRC4e
+            (23, 3) => (0x01, 0x02, true),  // VRC2b
+            (23, _) => (0x05, 0x0A, false),
+            (25, 1) => (0x02, 0x01, false), // VRC4b
+            (25, 2) => (0x08, 0x04, false), // VRC4d
+            (25, 3) => (0x02, 0x01, true),  // VRC2c
+            (_, _) => (0x0A, 0x05, false),
+        };
+
+        let (chr, chr_is_ram) = chr_memory(rom.chr_rom, rom.chr_ram_size);
+
+        Vrc {
+            chr_shift: if rom.mapper == 22 { 1 } else { 0 },
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            prg_ram: [0; PRG_RAM_SIZE],
+            lines: AddressLines::new(a0, a1),
+            vrc2,
+            prg_banks: [0; 2],
+            prg_swap: false,
+            chr_banks: [0; 8],
+            mirroring: rom.screen_mirroring,
+            irq: VrcIrq::new(),
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = (self.chr_banks[(addr as usize / CHR_BANK_SIZE) & 7] >> self.chr_shift) as usize;
+        (bank * CHR_BANK_SIZE + (addr as usize % CHR_BANK_SIZE)) % self.chr.len()
+    }
+
+    fn write_chr_nibble(&mut self, reg: u16, data: u8) {
+        // $B000 - $E003, two registers per 1 KiB bank. The even one holds the low nibble
+        let bank = (((reg >> 12) - 0xB) * 2 + ((reg & 0b10) >> 1)) as usize;
+        let high_mask = if self.vrc2 { 0x0F } else { 0x1F };
+
+        if reg & 0b01 == 0 {
+            self.chr_banks[bank] = (self.chr_banks[bank] & 0x1F0) | (data & 0x0F) as u16;
+        } else {
+            self.chr_banks[bank] = (self.chr_banks[bank] & 0x00F) | ((data & high_mask) as u16) << 4;
+        }
+    }
+}
+
+impl Mapper for Vrc {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xFFFF => {
+                let second_last = self.prg_bank_count() - 2;
+                let bank = match (addr, self.prg_swap) {
+                    (0x8000..=0x9FFF, false) => self.prg_banks[0] as usize,
+                    (0x8000..=0x9FFF, true) => second_last,
+                    (0xA000..=0xBFFF, _) => self.prg_banks[1] as usize,
+                    (0xC000..=0xDFFF, false) => second_last,
+                    (0xC000..=0xDFFF, true) => self.prg_banks[0] as usize,
+                    _ => self.prg_bank_count() - 1,
+                };
+                let offset = (bank % self.prg_bank_count()) * PRG_BANK_SIZE;
+                self.prg_rom[offset + (addr as usize % PRG_BANK_SIZE)]
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            self.prg_ram[(addr - 0x6000) as usize] = data;
+            return;
+        }
+
+        let reg = self.lines.translate(addr);
+        match reg {
+            0x8000..=0x8003 => self.prg_banks[0] = data & 0x1F,
+            0x9000..=0x9003 if self.vrc2 => {
+                self.mirroring = if data & 1 == 0 { Mirroring::Vertical } else { Mirroring::Horizontal };
+            }
+            0x9000 | 0x9001 => {
+                self.mirroring = match data & 0b11 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenLower,
+                    _ => Mirroring::SingleScreenUpper,
+                };
+            }
+            0x9002 | 0x9003 => self.prg_swap = data & 0b10 != 0,
+            0xA000..=0xA003 => self.prg_banks[1] = data & 0x1F,
+            0xB000..=0xE003 => self.write_chr_nibble(reg, data),
+            0xF000 if !self.vrc2 => self.irq.write_latch_low(data),
+            0xF001 if !self.vrc2 => self.irq.write_latch_high(data),
+            0xF002 if !self.vrc2 => self.irq.write_control(data),
+            0xF003 if !self.vrc2 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.cpu_clock();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+impl Savestate for Vrc {
+    crate::savestate_fields!(
+        chr if chr_is_ram,
+        prg_ram,
+        prg_banks,
+        prg_swap,
+        chr_banks,
+        mirroring,
+        irq,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    fn vrc(mapper: u8, submapper: u8) -> Vrc {
+        let mut rom = test_rom(mapper, 8, 8);
+        rom.submapper = submapper;
+        for (i, byte) in rom.prg_rom.iter_mut().enumerate() {
+            *byte = (i / PRG_BANK_SIZE) as u8;
+        }
+        for (i, byte) in rom.chr_rom.iter_mut().enumerate() {
+            *byte = (i / CHR_BANK_SIZE) as u8;
+        }
+        Vrc::new(rom)
+    }
+
+    #[test]
+    fn test_address_lines_by_submapper() {
+        // VRC4a selects with A1/A2, VRC4c with A6/A7. Both write the high nibble of CHR bank 1
+        let mut vrc4a = vrc(21, 1);
+        vrc4a.cpu_write(0xB006, 0x01);
+        assert_eq!(vrc4a.ppu_read(0x0400), 16);
+
+        let mut vrc4c = vrc(21, 2);
+        vrc4c.cpu_write(0xB0C0, 0x01);
+        assert_eq!(vrc4c.ppu_read(0x0400), 16);
+
+        // VRC2a has A0/A1 swapped and drops the low bit of the bank
+        let mut vrc2a = vrc(22, 0);
+        vrc2a.cpu_write(0xB000, 0x06);
+        assert_eq!(vrc2a.ppu_read(0x0000), 3);
+    }
+
+    #[test]
+    fn test_prg_swap_mode() {
+        let mut mapper = vrc(25, 1);
+        mapper.cpu_write(0x8000, 3);
+        mapper.cpu_write(0xA000, 4);
+        assert_eq!(mapper.cpu_read(0x8000), 3);
+        assert_eq!(mapper.cpu_read(0xA000), 4);
+        assert_eq!(mapper.cpu_read(0xC000), 14);
+        assert_eq!(mapper.cpu_read(0xE000), 15);
+
+        // VRC4b has A0/A1 swapped so $9001 is the swap register
+        mapper.cpu_write(0x9001, 0b10);
+        assert_eq!(mapper.cpu_read(0x8000), 14);
+        assert_eq!(mapper.cpu_read(0xC000), 3);
+    }
+
+    #[test]
+    fn test_cycle_mode_irq() {
+        let mut mapper = vrc(23, 1);
+        mapper.cpu_write(0xF000, 0x0E);
+        mapper.cpu_write(0xF001, 0x0F);
+        mapper.cpu_write(0xF002, 0b110);
+
+        mapper.cpu_clock();
+        assert!(!mapper.irq_pending());
+        mapper.cpu_clock();
+        assert!(mapper.irq_pending());
+
+        mapper.cpu_write(0xF003, 0);
+        assert!(!mapper.irq_pending());
+    }
+}
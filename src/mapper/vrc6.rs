@@ -0,0 +1,177 @@
+use super::vrc::AddressLines;
+use super::vrc6_audio::Vrc6Audio;
+use super::vrc_irq::VrcIrq;
+use super::{chr_memory, Mapper};
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::Savestate;
+use alloc::vec::Vec;
+
+const CHR_BANK_SIZE: usize = 0x400;
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// Mappers 24 (VRC6a) and 26 (VRC6b, A0 and A1 swapped)
+pub struct Vrc6 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    lines: AddressLines,
+
+    prg_bank_16k: u8,
+    prg_bank_8k: u8,
+    chr_banks: [u8; 8],
+    // $B003: PPU banking mode, mirroring and PRG RAM enable
+    banking_control: u8,
+    irq: VrcIrq,
+    audio: Vrc6Audio,
+}
+
+impl Vrc6 {
+    pub fn new(rom: Rom) -> Self {
+        let lines = if rom.mapper == 26 {
+            AddressLines::new(0x02, 0x01)
+        } else {
+            AddressLines::new(0x01, 0x02)
+        };
+
+        let (chr, chr_is_ram) = chr_memory(rom.chr_rom, rom.chr_ram_size);
+
+        Vrc6 {
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            prg_ram: [0; PRG_RAM_SIZE],
+            lines,
+            prg_bank_16k: 0,
+            prg_bank_8k: 0,
+            chr_banks: [0; 8],
+            banking_control: 0,
+            irq: VrcIrq::new(),
+            audio: Vrc6Audio::new(),
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.banking_control & 0x80 != 0
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let addr = addr as usize & 0x1FFF;
+        let slot = addr / CHR_BANK_SIZE;
+
+        // With bit 5 set the 2 KiB modes take CHR A10 from the PPU instead of the register
+        let a10 = |reg: u8| {
+            if self.banking_control & 0x20 != 0 {
+                (reg & 0xFE) | ((slot & 1) as u8)
+            } else {
+                reg
+            }
+        };
+
+        let bank = match (self.banking_control & 0b11, slot) {
+            // 1 KiB banks everywhere
+            (0, _) => self.chr_banks[slot],
+            // 2 KiB banks from R0 - R3
+            (1, _) => a10(self.chr_banks[slot / 2]),
+            // 1 KiB banks in the first pattern table, 2 KiB from R4 and R5 in the second
+            (_, 0..=3) => self.chr_banks[slot],
+            (_, _) => a10(self.chr_banks[4 + (slot - 4) / 2]),
+        };
+
+        (bank as usize * CHR_BANK_SIZE + addr % CHR_BANK_SIZE) % self.chr.len()
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        let banks_8k = self.prg_rom.len() / 0x2000;
+
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xBFFF => {
+                let bank = (self.prg_bank_16k as usize * 2) % banks_8k;
+                self.prg_rom[bank * 0x2000 + (addr - 0x8000) as usize]
+            }
+            0xC000..=0xDFFF => {
+                let bank = self.prg_bank_8k as usize % banks_8k;
+                self.prg_rom[bank * 0x2000 + (addr - 0xC000) as usize]
+            }
+            0xE000..=0xFFFF => self.prg_rom[(banks_8k - 1) * 0x2000 + (addr - 0xE000) as usize],
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            if self.prg_ram_enabled() {
+                self.prg_ram[(addr - 0x6000) as usize] = data;
+            }
+            return;
+        }
+
+        match self.lines.translate(addr) {
+            0x8000..=0x8003 => self.prg_bank_16k = data & 0x0F,
+            reg @ 0x9000..=0xB002 => self.audio.write(reg, data),
+            0xB003 => self.banking_control = data,
+            0xC000..=0xC003 => self.prg_bank_8k = data & 0x1F,
+            reg @ 0xD000..=0xE003 => {
+                let bank = (((reg >> 12) - 0xD) * 4 + (reg & 0b11)) as usize;
+                self.chr_banks[bank] = data;
+            }
+            0xF000 => self.irq.write_latch(data),
+            0xF001 => self.irq.write_control(data),
+            0xF002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match (self.banking_control >> 2) & 0b11 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.cpu_clock();
+        self.audio.clock();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+impl Savestate for Vrc6 {
+    crate::savestate_fields!(
+        chr if chr_is_ram,
+        prg_ram,
+        prg_bank_16k,
+        prg_bank_8k,
+        chr_banks,
+        banking_control,
+        irq,
+        audio,
+    );
+}
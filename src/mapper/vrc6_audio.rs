@@ -0,0 +1,211 @@
+use crate::savestate::Savestate;
+
+// The VRC6's DAC steps are about the same size as the APU pulses': a VRC6 pulse at volume 15
+// matches an APU pulse at volume 15, and the sawtooth goes twice as high
+const LEVEL_STEP: f32 = 0.149 / 15.0;
+
+#[derive(Default)]
+struct Pulse {
+    // Ignore duty and output the volume constantly
+    digitized: bool,
+    duty: u8,
+    volume: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    // Counts down from 15, the output is high while it's <= duty
+    step: u8,
+}
+
+impl Pulse {
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            // MDDD VVVV
+            0 => {
+                self.digitized = data & 0x80 != 0;
+                self.duty = (data >> 4) & 0b111;
+                self.volume = data & 0x0F;
+            }
+            1 => self.period = (self.period & 0xF00) | data as u16,
+            // E--- PPPP
+            _ => {
+                self.period = (self.period & 0x0FF) | ((data & 0x0F) as u16) << 8;
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0x0F;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.digitized || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Default)]
+struct Sawtooth {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl Sawtooth {
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            // --AA AAAA
+            0 => self.rate = data & 0x3F,
+            1 => self.period = (self.period & 0xF00) | data as u16,
+            // E--- PPPP
+            _ => {
+                self.period = (self.period & 0x0FF) | ((data & 0x0F) as u16) << 8;
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+
+        // The rate is added on every second step, and the seventh addition resets instead
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step & 1 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+// Two pulse channels with 16 duty settings and a sawtooth, $9000 - $B002
+#[derive(Default)]
+pub struct Vrc6Audio {
+    pulses: [Pulse; 2],
+    sawtooth: Sawtooth,
+    // $9003: halt everything, and shift every period right by 4 or 8
+    halt: bool,
+    period_shift: u8,
+}
+
+impl Vrc6Audio {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // `reg` has already been through the board's address line wiring
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0x9003 => {
+                self.halt = data & 0b001 != 0;
+                self.period_shift = match data & 0b110 {
+                    0 => 0,
+                    0b010 => 4,
+                    _ => 8,
+                };
+            }
+            0x9000..=0x9002 => self.pulses[0].write(reg & 0b11, data),
+            0xA000..=0xA002 => self.pulses[1].write(reg & 0b11, data),
+            0xB000..=0xB002 => self.sawtooth.write(reg & 0b11, data),
+            _ => {}
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if self.halt {
+            return;
+        }
+        for pulse in self.pulses.iter_mut() {
+            pulse.clock(self.period_shift);
+        }
+        self.sawtooth.clock(self.period_shift);
+    }
+
+    pub fn output(&self) -> f32 {
+        let level = self.pulses[0].output() + self.pulses[1].output() + self.sawtooth.output();
+        level as f32 * LEVEL_STEP
+    }
+}
+
+impl Savestate for Pulse {
+    crate::savestate_fields!(digitized, duty, volume, period, enabled, timer, step);
+}
+
+impl Savestate for Sawtooth {
+    crate::savestate_fields!(rate, period, enabled, timer, step, accumulator);
+}
+
+impl Savestate for Vrc6Audio {
+    crate::savestate_fields!(pulses, sawtooth, halt, period_shift);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pulse_duty() {
+        let mut audio = Vrc6Audio::new();
+        // Duty 3 of 16 at volume 10, period 0
+        audio.write(0x9000, 0b0011_1010);
+        audio.write(0x9001, 0);
+        audio.write(0x9002, 0x80);
+
+        let high = (0..16)
+            .filter(|_| {
+                audio.clock();
+                audio.pulses[0].output() == 10
+            })
+            .count();
+        assert_eq!(high, 4);
+    }
+
+    #[test]
+    fn test_sawtooth_ramp() {
+        let mut audio = Vrc6Audio::new();
+        audio.write(0xB000, 42);
+        audio.write(0xB001, 0);
+        audio.write(0xB002, 0x80);
+
+        let mut levels = Vec::new();
+        for _ in 0..14 {
+            audio.clock();
+            levels.push(audio.sawtooth.output());
+        }
+        assert_eq!(levels, [0, 5, 5, 10, 10, 15, 15, 21, 21, 26, 26, 31, 31, 0]);
+    }
+}
@@ -0,0 +1,159 @@
+use super::vrc7_audio::Vrc7Audio;
+use super::vrc_irq::VrcIrq;
+use super::{chr_memory, Mapper};
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::Savestate;
+use alloc::vec::Vec;
+
+const CHR_BANK_SIZE: usize = 0x400;
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// Mapper 85. Registers come in pairs told apart by A4 on the VRC7a (Lagrange Point) and A3 on
+// the VRC7b (Tiny Toon Adventures 2).
+pub struct Vrc7 {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    prg_ram: [u8; PRG_RAM_SIZE],
+    select_line: u16,
+
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    // $E000: mirroring, audio silence (bit 6) and PRG RAM enable
+    control: u8,
+    irq: VrcIrq,
+    audio: Vrc7Audio,
+}
+
+impl Vrc7 {
+    pub fn new(rom: Rom) -> Self {
+        let select_line = match rom.submapper {
+            1 => 0x08,
+            2 => 0x10,
+            _ => 0x18,
+        };
+
+        let (chr, chr_is_ram) = chr_memory(rom.chr_rom, rom.chr_ram_size);
+
+        Vrc7 {
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_is_ram,
+            prg_ram: [0; PRG_RAM_SIZE],
+            select_line,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            control: 0,
+            irq: VrcIrq::new(),
+            audio: Vrc7Audio::new(),
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr as usize / CHR_BANK_SIZE) & 7] as usize;
+        (bank * CHR_BANK_SIZE + addr as usize % CHR_BANK_SIZE) % self.chr.len()
+    }
+}
+
+impl Mapper for Vrc7 {
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        let banks_8k = self.prg_rom.len() / 0x2000;
+
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.prg_ram[(addr - 0x6000) as usize],
+            0x8000..=0xFFFF => {
+                let slot = ((addr - 0x8000) / 0x2000) as usize;
+                let bank = if slot < 3 { self.prg_banks[slot] as usize } else { banks_8k - 1 };
+                self.prg_rom[(bank % banks_8k) * 0x2000 + (addr as usize % 0x2000)]
+            }
+            _ => 0,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF => {
+                if self.prg_ram_enabled() {
+                    self.prg_ram[(addr - 0x6000) as usize] = data;
+                }
+                return;
+            }
+            // The FM synth's register select and data ports don't follow the A3/A4 pairing
+            0x9010 => {
+                self.audio.select(data);
+                return;
+            }
+            0x9030 => {
+                self.audio.write(data);
+                return;
+            }
+            _ => {}
+        }
+
+        // Collapse to $x000 or $x001
+        let reg = (addr & 0xF000) | if addr & self.select_line != 0 { 1 } else { 0 };
+        match reg {
+            0x8000 => self.prg_banks[0] = data & 0x3F,
+            0x8001 => self.prg_banks[1] = data & 0x3F,
+            0x9000 => self.prg_banks[2] = data & 0x3F,
+            0xA000..=0xD001 => {
+                let bank = (((reg >> 12) - 0xA) * 2 + (reg & 1)) as usize;
+                self.chr_banks[bank] = data;
+            }
+            0xE000 => self.control = data,
+            0xE001 => self.irq.write_latch(data),
+            0xF000 => self.irq.write_control(data),
+            0xF001 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr[self.chr_offset(addr)]
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_is_ram {
+            let offset = self.chr_offset(addr);
+            self.chr[offset] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn cpu_clock(&mut self) {
+        self.irq.cpu_clock();
+        self.audio.clock();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending()
+    }
+
+    fn audio_output(&self) -> f32 {
+        if self.control & 0x40 != 0 {
+            0.0
+        } else {
+            self.audio.output()
+        }
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+impl Savestate for Vrc7 {
+    crate::savestate_fields!(chr if chr_is_ram, prg_ram, prg_banks, chr_banks, control, irq, audio);
+}
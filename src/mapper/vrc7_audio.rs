@@ -0,0 +1,506 @@
+use crate::apu::pipeline::CPU_CLOCK_RATE;
+use crate::math;
+use crate::savestate::Savestate;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::f32::consts::PI;
+
+// The VRC7's built in instruments 1 - 15, in the same 8 byte layout as the custom instrument in
+// registers $00 - $07. Dumped from the chip by Nuke.YKT.
+const PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+
+const MULTIPLIERS: [f32; 16] = [
+    0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0,
+];
+
+// Key scale attenuation in dB at 3 dB per octave for block 7, by the top 4 bits of the F-number
+const KSL_TABLE: [f32; 16] = [
+    0.0, 18.0, 24.0, 27.75, 30.0, 32.25, 33.75, 35.25, 36.0, 37.5, 38.25, 39.0, 39.75, 40.5, 41.25,
+    42.0,
+];
+
+// The chip renders one sample every 36 CPU cycles, about 49.7 kHz
+const SAMPLE_CYCLES: u8 = 36;
+const SAMPLE_RATE: f32 = (CPU_CLOCK_RATE / SAMPLE_CYCLES as f64) as f32;
+const CHANNELS: usize = 6;
+
+// The envelope runs from 0 dB down to about 48 dB, past which the operator is silent
+const MAX_ATTENUATION: f32 = 48.0;
+// How long the full attack and the full 48 dB of decay take at an effective rate of 4, every
+// 4 rates above that halves them
+const ATTACK_SECONDS: f32 = 2.826;
+const DECAY_SECONDS: f32 = 19.6;
+// Release rate used on key off while the channel's sustain bit is set
+const SUSTAIN_RELEASE_RATE: u8 = 5;
+
+// Tremolo is 3.7 Hz and 4.8 dB deep, vibrato 6.4 Hz and about 7 cents either way
+const AM_FREQUENCY: f32 = 3.7;
+const AM_DEPTH: f32 = 4.8;
+const VIBRATO_FREQUENCY: f32 = 6.4;
+const VIBRATO_DEPTH: f32 = 0.0041;
+
+// How far in radians a full scale modulator bends the carrier's phase
+const MODULATION_INDEX: f32 = 4.0 * PI;
+// A channel at full volume comes out at about half an APU pulse
+const CHANNEL_LEVEL: f32 = 0.08;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+// One operator's half of an instrument, operator 0 is the modulator and 1 the carrier
+struct OperatorPatch {
+    tremolo: bool,
+    vibrato: bool,
+    // Holds at the sustain level instead of carrying on decaying
+    sustained: bool,
+    key_scale_rate: bool,
+    multiplier: f32,
+    key_scale_level: u8,
+    rectified: bool,
+    attack: u8,
+    decay: u8,
+    sustain_level: f32,
+    release: u8,
+}
+
+impl OperatorPatch {
+    fn decode(patch: &[u8; 8], op: usize) -> Self {
+        let ksl = if op == 0 { patch[2] >> 6 } else { patch[3] >> 6 };
+        let rectified = patch[3] & if op == 0 { 0x08 } else { 0x10 } != 0;
+        OperatorPatch {
+            tremolo: patch[op] & 0x80 != 0,
+            vibrato: patch[op] & 0x40 != 0,
+            sustained: patch[op] & 0x20 != 0,
+            key_scale_rate: patch[op] & 0x10 != 0,
+            multiplier: MULTIPLIERS[(patch[op] & 0x0F) as usize],
+            key_scale_level: ksl,
+            rectified,
+            attack: patch[4 + op] >> 4,
+            decay: patch[4 + op] & 0x0F,
+            sustain_level: (patch[6 + op] >> 4) as f32 * 3.0,
+            release: patch[6 + op] & 0x0F,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Operator {
+    // In cycles, 0.0 - 1.0
+    phase: f32,
+    state: EnvelopeState,
+    attenuation: f32,
+    // The modulator's last two outputs feed back into it
+    outputs: [f32; 2],
+}
+
+impl Operator {
+    fn new() -> Self {
+        Operator {
+            phase: 0.0,
+            state: EnvelopeState::Off,
+            attenuation: MAX_ATTENUATION,
+            outputs: [0.0; 2],
+        }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.state = EnvelopeState::Attack;
+    }
+
+    fn key_off(&mut self) {
+        if self.state != EnvelopeState::Off {
+            self.state = EnvelopeState::Release;
+        }
+    }
+
+    fn clock_envelope(&mut self, patch: &OperatorPatch, key_scale: u8, sustain_on: bool) {
+        // The rate scales up with pitch, a lot more with key scale rate set
+        let rate_offset = if patch.key_scale_rate { key_scale } else { key_scale >> 2 };
+        let effective_rate = |rate: u8| {
+            if rate == 0 {
+                0
+            } else {
+                (4 * rate + rate_offset).min(63)
+            }
+        };
+        let step = |seconds: f32, rate: u8| {
+            if rate == 0 {
+                return 0.0;
+            }
+            let time = seconds / math::powf(2.0, (rate as f32 - 4.0) / 4.0);
+            MAX_ATTENUATION / (time * SAMPLE_RATE)
+        };
+
+        match self.state {
+            EnvelopeState::Attack => {
+                let rate = effective_rate(patch.attack);
+                if rate >= 60 {
+                    self.attenuation = 0.0;
+                } else {
+                    self.attenuation -= step(ATTACK_SECONDS, rate);
+                }
+                if self.attenuation <= 0.0 {
+                    self.attenuation = 0.0;
+                    self.state = EnvelopeState::Decay;
+                }
+            }
+            EnvelopeState::Decay => {
+                self.attenuation += step(DECAY_SECONDS, effective_rate(patch.decay));
+                if self.attenuation >= patch.sustain_level {
+                    self.attenuation = patch.sustain_level;
+                    self.state = EnvelopeState::Sustain;
+                }
+            }
+            // Percussive instruments keep decaying at the release rate while the key is held
+            EnvelopeState::Sustain if !patch.sustained => {
+                self.attenuation += step(DECAY_SECONDS, effective_rate(patch.release));
+            }
+            EnvelopeState::Sustain => {}
+            EnvelopeState::Release => {
+                let rate = if sustain_on { SUSTAIN_RELEASE_RATE } else { patch.release };
+                self.attenuation += step(DECAY_SECONDS, effective_rate(rate));
+            }
+            EnvelopeState::Off => {}
+        }
+
+        if self.attenuation >= MAX_ATTENUATION {
+            self.attenuation = MAX_ATTENUATION;
+            if self.state != EnvelopeState::Attack {
+                self.state = EnvelopeState::Off;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Channel {
+    // 9 bit F-number and 3 bit block (octave)
+    frequency: u16,
+    block: u8,
+    key_on: bool,
+    sustain_on: bool,
+    instrument: u8,
+    // Attenuation in 3 dB steps
+    volume: u8,
+    operators: [Operator; 2],
+}
+
+impl Channel {
+    fn new() -> Self {
+        Channel {
+            frequency: 0,
+            block: 0,
+            key_on: false,
+            sustain_on: false,
+            instrument: 0,
+            volume: 0,
+            operators: [Operator::new(); 2],
+        }
+    }
+
+    // Key scaling looks at the block and the top bit of the F-number
+    fn key_scale(&self) -> u8 {
+        (self.block << 1) | (self.frequency >> 8) as u8
+    }
+
+    fn key_scale_attenuation(&self, ksl: u8) -> f32 {
+        if ksl == 0 {
+            return 0.0;
+        }
+        let base = KSL_TABLE[(self.frequency >> 5) as usize] - 6.0 * (7 - self.block) as f32;
+        // KSL 1, 2, 3 are 1.5, 3 and 6 dB per octave
+        base.max(0.0) * [0.0, 0.5, 1.0, 2.0][ksl as usize]
+    }
+}
+
+fn amplitude(attenuation: f32) -> f32 {
+    if attenuation >= MAX_ATTENUATION {
+        0.0
+    } else {
+        math::powf(10.0, -attenuation / 20.0)
+    }
+}
+
+fn wave(phase: f32, rectified: bool) -> f32 {
+    let sample = math::sinf(phase);
+    if rectified && sample < 0.0 {
+        0.0
+    } else {
+        sample
+    }
+}
+
+// A cut down Yamaha OPLL (YM2413): six two operator FM channels, 15 fixed instruments and one
+// custom one. This follows the chip's structure but works in floating point, so it won't match
+// a recording of the real thing sample for sample.
+pub struct Vrc7Audio {
+    select: u8,
+    custom_patch: [u8; 8],
+    channels: [Channel; CHANNELS],
+
+    timer: u8,
+    am_phase: f32,
+    vibrato_phase: f32,
+    output: f32,
+}
+
+impl Vrc7Audio {
+    pub fn new() -> Self {
+        Vrc7Audio {
+            select: 0,
+            custom_patch: [0; 8],
+            channels: [Channel::new(); CHANNELS],
+            timer: SAMPLE_CYCLES,
+            am_phase: 0.0,
+            vibrato_phase: 0.0,
+            output: 0.0,
+        }
+    }
+
+    // $9010
+    pub fn select(&mut self, data: u8) {
+        self.select = data;
+    }
+
+    // $9030, writes the selected register
+    pub fn write(&mut self, data: u8) {
+        let reg = self.select;
+        let channel = (reg & 0x0F) as usize;
+
+        match reg {
+            0x00..=0x07 => self.custom_patch[reg as usize] = data,
+            0x10..=0x15 => {
+                let channel = &mut self.channels[channel];
+                channel.frequency = (channel.frequency & 0x100) | data as u16;
+            }
+            // --SK BBBF: sustain, key on, block and the F-number's top bit
+            0x20..=0x25 => {
+                let channel = &mut self.channels[channel];
+                channel.frequency = (channel.frequency & 0x0FF) | ((data & 1) as u16) << 8;
+                channel.block = (data >> 1) & 0b111;
+                channel.sustain_on = data & 0x20 != 0;
+
+                let key_on = data & 0x10 != 0;
+                if key_on && !channel.key_on {
+                    channel.operators.iter_mut().for_each(Operator::key_on);
+                } else if !key_on && channel.key_on {
+                    channel.operators.iter_mut().for_each(Operator::key_off);
+                }
+                channel.key_on = key_on;
+            }
+            // IIII VVVV
+            0x30..=0x35 => {
+                let channel = &mut self.channels[channel];
+                channel.instrument = data >> 4;
+                channel.volume = data & 0x0F;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn clock(&mut self) {
+        self.timer -= 1;
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = SAMPLE_CYCLES;
+
+        self.am_phase = (self.am_phase + AM_FREQUENCY / SAMPLE_RATE) % 1.0;
+        self.vibrato_phase = (self.vibrato_phase + VIBRATO_FREQUENCY / SAMPLE_RATE) % 1.0;
+        let tremolo = (1.0 - math::cosf(2.0 * PI * self.am_phase)) / 2.0 * AM_DEPTH;
+        let vibrato = 1.0 + math::sinf(2.0 * PI * self.vibrato_phase) * VIBRATO_DEPTH;
+
+        let mut output = 0.0;
+        for index in 0..CHANNELS {
+            output += self.render_channel(index, tremolo, vibrato);
+        }
+        self.output = output * CHANNEL_LEVEL;
+    }
+
+    fn render_channel(&mut self, index: usize, tremolo: f32, vibrato: f32) -> f32 {
+        let patch = match self.channels[index].instrument {
+            0 => self.custom_patch,
+            instrument => PATCHES[instrument as usize - 1],
+        };
+        let channel = &mut self.channels[index];
+        let ops = [OperatorPatch::decode(&patch, 0), OperatorPatch::decode(&patch, 1)];
+
+        // Phase increment in cycles per sample before the operator's multiplier
+        let base = channel.frequency as f32 * (1 << channel.block) as f32 / (1 << 19) as f32;
+        let key_scale = channel.key_scale();
+
+        let mut attenuations = [0.0; 2];
+        for (op, patch) in ops.iter().enumerate() {
+            let vibrato = if patch.vibrato { vibrato } else { 1.0 };
+            let operator = &mut channel.operators[op];
+            operator.phase = (operator.phase + base * patch.multiplier * vibrato) % 1.0;
+            operator.clock_envelope(patch, key_scale, channel.sustain_on);
+
+            let mut attenuation = operator.attenuation;
+            attenuation += channel.key_scale_attenuation(patch.key_scale_level);
+            if patch.tremolo {
+                attenuation += tremolo;
+            }
+            attenuations[op] = attenuation;
+        }
+
+        // Modulator, with total level in 0.75 dB steps and self feedback
+        let total_level = (patch[2] & 0x3F) as f32 * 0.75;
+        let feedback = match patch[3] & 0b111 {
+            0 => 0.0,
+            level => {
+                let outputs = channel.operators[0].outputs;
+                (outputs[0] + outputs[1]) / 2.0 * PI * math::powi(2.0, level as i32 - 5)
+            }
+        };
+        let modulator = &mut channel.operators[0];
+        let modulator_output = wave(2.0 * PI * modulator.phase + feedback, ops[0].rectified)
+            * amplitude(attenuations[0] + total_level);
+        modulator.outputs = [modulator_output, modulator.outputs[0]];
+
+        // Carrier, with the channel volume in 3 dB steps
+        let carrier = &channel.operators[1];
+        let phase = 2.0 * PI * carrier.phase + modulator_output * MODULATION_INDEX;
+        wave(phase, ops[1].rectified) * amplitude(attenuations[1] + channel.volume as f32 * 3.0)
+    }
+
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+}
+
+impl Default for Vrc7Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Savestate for EnvelopeState {
+    fn save(&self, out: &mut Vec<u8>) {
+        let state: u8 = match self {
+            EnvelopeState::Attack => 0,
+            EnvelopeState::Decay => 1,
+            EnvelopeState::Sustain => 2,
+            EnvelopeState::Release => 3,
+            EnvelopeState::Off => 4,
+        };
+        state.save(out);
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        let mut state = 0u8;
+        state.load(data)?;
+        *self = match state {
+            0 => EnvelopeState::Attack,
+            1 => EnvelopeState::Decay,
+            2 => EnvelopeState::Sustain,
+            3 => EnvelopeState::Release,
+            4 => EnvelopeState::Off,
+            _ => return Err(format!("Save state has an unknown VRC7 envelope state {}", state)),
+        };
+        Ok(())
+    }
+}
+
+impl Savestate for Operator {
+    crate::savestate_fields!(phase, state, attenuation, outputs);
+}
+
+impl Savestate for Channel {
+    crate::savestate_fields!(frequency, block, key_on, sustain_on, instrument, volume, operators);
+}
+
+// Instruments are decoded from the patches as they play, so only the registers are saved
+impl Savestate for Vrc7Audio {
+    crate::savestate_fields!(
+        select,
+        custom_patch,
+        channels,
+        timer,
+        am_phase,
+        vibrato_phase,
+        output,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(audio: &mut Vrc7Audio, reg: u8, data: u8) {
+        audio.select(reg);
+        audio.write(data);
+    }
+
+    // A plain sine: modulator turned all the way down, instant attack, no decay
+    fn sine_patch(audio: &mut Vrc7Audio) {
+        for (reg, data) in [0x01, 0x21, 0x3F, 0x00, 0xF0, 0xF0, 0x0F, 0x0F].into_iter().enumerate() {
+            write(audio, reg as u8, data);
+        }
+    }
+
+    #[test]
+    fn test_frequency() {
+        let mut audio = Vrc7Audio::new();
+        sine_patch(&mut audio);
+        // F-number 256 in block 4 is 256 * 16 * 49716 / 2^19 = 388 Hz
+        write(&mut audio, 0x10, 0x00);
+        write(&mut audio, 0x30, 0x00);
+        write(&mut audio, 0x20, 0x10 | (4 << 1) | 1);
+
+        let mut crossings = 0;
+        let mut last = audio.output();
+        for _ in 0..(CPU_CLOCK_RATE as usize / 2) {
+            audio.clock();
+            let output = audio.output();
+            if (last < 0.0) != (output < 0.0) {
+                crossings += 1;
+            }
+            last = output;
+        }
+        assert!((386..=390).contains(&crossings), "{} crossings", crossings);
+    }
+
+    #[test]
+    fn test_release() {
+        let mut audio = Vrc7Audio::new();
+        sine_patch(&mut audio);
+        write(&mut audio, 0x20, 0x10 | (4 << 1) | 1);
+        for _ in 0..(SAMPLE_CYCLES as usize * 100) {
+            audio.clock();
+        }
+        assert_eq!(audio.channels[0].operators[1].state, EnvelopeState::Sustain);
+
+        // Release rate 15 dies away almost immediately
+        write(&mut audio, 0x20, (4 << 1) | 1);
+        for _ in 0..(SAMPLE_CYCLES as usize * 1000) {
+            audio.clock();
+        }
+        assert_eq!(audio.channels[0].operators[1].state, EnvelopeState::Off);
+        assert_eq!(audio.output(), 0.0);
+    }
+}
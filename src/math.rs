@@ -0,0 +1,47 @@
+// The float functions that come from the standard library, or from libm without it. Going
+// through std when we have it keeps the output exactly as it was
+#[cfg(feature = "std")]
+mod imp {
+    pub fn sinf(x: f32) -> f32 {
+        x.sin()
+    }
+
+    pub fn cosf(x: f32) -> f32 {
+        x.cos()
+    }
+
+    pub fn powf(x: f32, y: f32) -> f32 {
+        x.powf(y)
+    }
+
+    pub fn powi(x: f32, n: i32) -> f32 {
+        x.powi(n)
+    }
+
+    pub fn sin(x: f64) -> f64 {
+        x.sin()
+    }
+
+    pub fn cos(x: f64) -> f64 {
+        x.cos()
+    }
+
+    pub fn ceil(x: f64) -> f64 {
+        x.ceil()
+    }
+
+    pub fn fabs(x: f64) -> f64 {
+        x.abs()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+mod imp {
+    pub use libm::{ceil, cos, cosf, fabs, powf, sin, sinf};
+
+    pub fn powi(x: f32, n: i32) -> f32 {
+        libm::powf(x, n as f32)
+    }
+}
+
+pub use imp::*;
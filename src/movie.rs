@@ -0,0 +1,485 @@
+#[cfg(feature = "std")]
+use crate::cpu::CPU;
+use crate::input::Buttons;
+#[cfg(feature = "std")]
+use crate::input::{InputState, Peripherals};
+use crate::math;
+use crate::savestate::Savestate;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+
+// The FM2 version we read and write, and the emulator version we claim to FCEUX
+const FM2_VERSION: u32 = 3;
+const EMU_VERSION: u32 = 20604;
+
+// FM2 joypad columns, left to right
+const BUTTON_COLUMNS: [(char, Buttons); 8] = [
+    ('R', Buttons::RIGHT),
+    ('L', Buttons::LEFT),
+    ('D', Buttons::DOWN),
+    ('U', Buttons::UP),
+    ('T', Buttons::START),
+    ('S', Buttons::SELECT),
+    ('B', Buttons::B),
+    ('A', Buttons::A),
+];
+
+// Commands a frame can start with
+pub const SOFT_RESET: u8 = 0b01;
+pub const POWER: u8 = 0b10;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MovieFrame {
+    pub commands: u8,
+    pub buttons: [Buttons; 4],
+}
+
+// Controller input for every frame from power on, or from a save state, in FCEUX's FM2 text
+// format: `key value` header lines, then one `|commands|RLDUTSBA|RLDUTSBA||` line per frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_filename: String,
+    // MD5 of the PRG and CHR ROM
+    pub rom_checksum: [u8; 16],
+    pub guid: String,
+    pub four_score: bool,
+    pub rerecord_count: u32,
+    pub comments: Vec<String>,
+    // Where the frontend's own randomness starts, for the snake game. Not an FCEUX key, which
+    // it ignores
+    pub rng_seed: u64,
+    // Movies that don't start from power on start from this, see `CPU::save_state`. Kept under
+    // our own key, FCEUX's `savestate` is one of its own states
+    pub savestate: Option<Vec<u8>>,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn new(rom_filename: &str, rom_checksum: [u8; 16], rng_seed: u64) -> Self {
+        // Only needs to tell movies apart
+        let mut rng = MovieRng::new(rng_seed ^ md5(rom_filename.as_bytes())[0] as u64);
+        let hex: String = (0..16).map(|_| format!("{:02X}", rng.next_u8())).collect();
+        let guid = format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]);
+
+        Movie {
+            rom_filename: rom_filename.to_string(),
+            rom_checksum,
+            guid,
+            four_score: false,
+            rerecord_count: 0,
+            comments: Vec::new(),
+            rng_seed,
+            savestate: None,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut movie = Movie::new("", [0; 16], 0);
+        movie.guid.clear();
+        let mut players = 2;
+
+        for (number, line) in text.lines().enumerate() {
+            let error = |message: &str| format!("Line {}: {}", number + 1, message);
+            let line = line.trim_end_matches('\r');
+
+            if let Some(fields) = line.strip_prefix('|') {
+                let frame = parse_frame(fields, players).ok_or_else(|| error("can't read the input"))?;
+                movie.frames.push(frame);
+                continue;
+            }
+
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "" => {}
+                "version" if value != FM2_VERSION.to_string() => {
+                    return Err(error(&format!("FM2 version {} isn't supported", value)));
+                }
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "romChecksum" => {
+                    let md5 = value.strip_prefix("base64:").and_then(base64_decode);
+                    movie.rom_checksum = md5
+                        .and_then(|md5| md5.try_into().ok())
+                        .ok_or_else(|| error("romChecksum should be a base64 MD5"))?;
+                }
+                "guid" => movie.guid = value.to_string(),
+                "fourscore" => {
+                    movie.four_score = value == "1";
+                    players = if movie.four_score { 4 } else { 2 };
+                }
+                "rerecordCount" => movie.rerecord_count = value.parse().map_err(|_| error("bad rerecordCount"))?,
+                "comment" => movie.comments.push(value.to_string()),
+                "rngSeed" => movie.rng_seed = value.parse().map_err(|_| error("bad rngSeed"))?,
+                "nessSavestate" => {
+                    let state = value.strip_prefix("base64:").and_then(base64_decode);
+                    movie.savestate = Some(state.ok_or_else(|| error("nessSavestate should be base64"))?);
+                }
+                "savestate" => return Err(error("movies starting from an FCEUX save state aren't supported")),
+                // Everything else is for FCEUX: ports, palFlag, FDS and so on
+                _ => {}
+            }
+        }
+
+        Ok(movie)
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut fm2 = format!(
+            "version {}\nemuVersion {}\nrerecordCount {}\npalFlag 0\nromFilename {}\nromChecksum base64:{}\n",
+            FM2_VERSION,
+            EMU_VERSION,
+            self.rerecord_count,
+            self.rom_filename,
+            base64_encode(&self.rom_checksum),
+        );
+        fm2 += &format!("guid {}\nfourscore {}\nmicrophone 0\n", self.guid, self.four_score as u8);
+        // Both ports have joypads, or the Four Score's pairs of them
+        let port = if self.four_score { 0 } else { 1 };
+        fm2 += &format!("port0 {}\nport1 {}\nport2 0\nFDS 0\nNewPPU 0\n", port, port);
+        fm2 += &format!("rngSeed {}\n", self.rng_seed);
+        for comment in &self.comments {
+            fm2 += &format!("comment {}\n", comment);
+        }
+        if let Some(state) = &self.savestate {
+            fm2 += &format!("nessSavestate base64:{}\n", base64_encode(state));
+        }
+
+        let players = if self.four_score { 4 } else { 2 };
+        for frame in &self.frames {
+            fm2 += &format!("|{}|", frame.commands);
+            for buttons in &frame.buttons[..players] {
+                let columns = BUTTON_COLUMNS.iter().map(|&(c, button)| if buttons.contains(button) { c } else { '.' });
+                fm2.extend(columns);
+                fm2.push('|');
+            }
+            fm2 += "|\n";
+        }
+        fm2
+    }
+
+    #[cfg(feature = "std")]
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+        Movie::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+fn parse_frame(fields: &str, players: usize) -> Option<MovieFrame> {
+    let mut fields = fields.split('|');
+    let mut frame = MovieFrame {
+        commands: fields.next()?.trim().parse().ok()?,
+        ..Default::default()
+    };
+    for buttons in frame.buttons.iter_mut().take(players) {
+        let field = fields.next()?;
+        for (c, &(_, button)) in field.chars().zip(BUTTON_COLUMNS.iter()) {
+            if c != '.' && c != ' ' {
+                buttons.insert(button);
+            }
+        }
+    }
+    Some(frame)
+}
+
+// Records or replays a movie a frame at a time
+#[cfg(feature = "std")]
+pub struct MovieSession {
+    movie: Movie,
+    path: PathBuf,
+    playing: bool,
+    frame: usize,
+}
+
+#[cfg(feature = "std")]
+impl MovieSession {
+    pub fn record(movie: Movie, path: &Path) -> Self {
+        MovieSession { movie, path: path.to_path_buf(), playing: false, frame: 0 }
+    }
+
+    pub fn play(movie: Movie, path: &Path) -> Self {
+        MovieSession { movie, path: path.to_path_buf(), playing: true, frame: 0 }
+    }
+
+    // Records what's played on `cpu` from here on, noting whether it has a Four Score
+    pub fn start_recording(cpu: &mut CPU, mut movie: Movie, path: &Path) -> Result<Self, String> {
+        let peripherals = cpu.bus.controllers().peripherals();
+        check_peripherals(peripherals)?;
+        movie.four_score = peripherals == Peripherals::FourScore;
+        Ok(MovieSession::record(movie, path))
+    }
+
+    // Plays `movie` on `cpu`, first putting in the devices it was recorded with and loading the
+    // state it starts from
+    pub fn start_playback(cpu: &mut CPU, movie: Movie, path: &Path) -> Result<Self, String> {
+        if movie.four_score {
+            cpu.bus.controllers().connect(Peripherals::FourScore);
+        }
+        check_peripherals(cpu.bus.controllers().peripherals())?;
+        if let Some(state) = &movie.savestate {
+            cpu.load_state(state).map_err(|e| format!("Couldn't load {}'s save state: {}", path.display(), e))?;
+        }
+        Ok(MovieSession::play(movie, path))
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    // Called as each frame starts with what the players are holding. Recording logs it, playing
+    // back replaces the buttons with the movie's. None once playback has run out
+    pub fn next_frame(&mut self, live: &InputState) -> Option<(InputState, u8)> {
+        let frame = if self.playing {
+            *self.movie.frames.get(self.frame)?
+        } else {
+            let frame = MovieFrame { commands: 0, buttons: live.buttons };
+            self.movie.frames.push(frame);
+            frame
+        };
+        self.frame += 1;
+
+        let input = InputState { buttons: frame.buttons, ..*live };
+        Some((input, frame.commands))
+    }
+
+    // Writes out a recording, playback has nothing to save
+    pub fn finish(self) -> io::Result<()> {
+        if self.playing {
+            return Ok(());
+        }
+        fs::write(&self.path, self.movie.to_fm2())
+    }
+}
+
+// Movies only hold the joypads' buttons. Anything else would be read from the player as the movie
+// runs, so it wouldn't replay
+#[cfg(feature = "std")]
+fn check_peripherals(peripherals: Peripherals) -> Result<(), String> {
+    match peripherals {
+        Peripherals::Joypads | Peripherals::FourScore => Ok(()),
+        _ => Err(format!("Movies only work with joypads or a Four Score, not {:?}", peripherals)),
+    }
+}
+
+// The randomness the frontend gives the snake game. Seeded so that movies replay exactly, and
+// simple enough that the sequence never changes under them
+pub struct MovieRng {
+    state: u64,
+}
+
+impl MovieRng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck on 0
+        MovieRng { state: seed.max(1) }
+    }
+
+    // Where the sequence has got to, to start a recording from
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    // xorshift64*
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    // In `low..high`
+    pub fn gen_range(&mut self, low: u8, high: u8) -> u8 {
+        low + (self.next_u64() % (high - low) as u64) as u8
+    }
+}
+
+impl Savestate for MovieRng {
+    crate::savestate_fields!(state);
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim().trim_end_matches('=');
+    let mut out = Vec::new();
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.bytes() {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        bits = bits << 6 | value;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+// MD5, which FM2 files identify their ROM by
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    // floor(2^32 * |sin(i + 1)|)
+    let constants: Vec<u32> = (0..64)
+        .map(|i| (math::fabs(math::sin(i as f64 + 1.0)) * 4_294_967_296.0) as u32)
+        .collect();
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend(((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut hash: [u32; 4] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476];
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block.chunks(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect();
+        let [mut a, mut b, mut c, mut d] = hash;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(constants[i])
+                .wrapping_add(words[g])
+                .rotate_left(SHIFTS[i / 16 * 4 + i % 4]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+        for (h, v) in hash.iter_mut().zip([a, b, c, d]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 16];
+    for (bytes, h) in digest.chunks_mut(4).zip(hash) {
+        bytes.copy_from_slice(&h.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        let hex = |digest: [u8; 16]| digest.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(hex(md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        let fox = md5(b"The quick brown fox jumps over the lazy dog");
+        assert_eq!(hex(fox), "9e107d9d372bb6826bd81d3542a419d6");
+
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foobar"] {
+            assert_eq!(base64_decode(&base64_encode(data)).unwrap(), data);
+        }
+        assert_eq!(base64_encode(b"foob"), "Zm9vYg==");
+    }
+
+    #[test]
+    fn test_fm2_round_trip() {
+        let fm2 = "version 3\nemuVersion 20604\nrerecordCount 2\npalFlag 0\nromFilename smb\n\
+                   romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==\nguid 01234567-89AB-CDEF-0123-456789ABCDEF\n\
+                   fourscore 0\nport0 1\nport1 1\nport2 0\ncomment author me\n\
+                   |0|........|........||\n|1|R..UT..A|.L....B.||\n";
+        let movie = Movie::parse(fm2).unwrap();
+        assert_eq!(movie.rom_filename, "smb");
+        assert_eq!(movie.rerecord_count, 2);
+        assert_eq!(movie.comments, ["author me"]);
+        assert_eq!(movie.frames.len(), 2);
+        assert_eq!(movie.frames[1].commands, SOFT_RESET);
+        assert_eq!(movie.frames[1].buttons[0], Buttons::RIGHT | Buttons::UP | Buttons::START | Buttons::A);
+        assert_eq!(movie.frames[1].buttons[1], Buttons::LEFT | Buttons::B);
+
+        assert_eq!(Movie::parse(&movie.to_fm2()).unwrap(), movie);
+
+        // Our states are written where FCEUX won't try to load them, and its own are refused
+        let mut resumed = movie.clone();
+        resumed.savestate = Some(b"NESS".to_vec());
+        let fm2 = resumed.to_fm2();
+        assert!(fm2.contains("\nnessSavestate base64:TkVTUw==\n") && !fm2.contains("\nsavestate"));
+        assert_eq!(Movie::parse(&fm2).unwrap(), resumed);
+        assert!(Movie::parse("savestate base64:AAAA\n").is_err());
+
+        assert!(Movie::parse("version 2\n").is_err());
+        assert!(Movie::parse("|x|........|........||\n").is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_session_records_then_replays() {
+        let path = Path::new("snake.fm2");
+        let mut recording = MovieSession::record(Movie::new("snake", md5(b"snake"), 42), path);
+        for buttons in [Buttons::empty(), Buttons::UP, Buttons::UP | Buttons::A] {
+            let mut live = InputState::default();
+            live.buttons[0] = buttons;
+            recording.next_frame(&live);
+        }
+
+        let movie = Movie::parse(&recording.movie().to_fm2()).unwrap();
+        assert_eq!(movie.rng_seed, 42);
+        let mut playback = MovieSession::play(movie, path);
+        let live = InputState::default();
+        let replayed: Vec<Buttons> = std::iter::from_fn(|| playback.next_frame(&live))
+            .map(|(input, _)| input.buttons[0])
+            .collect();
+        assert_eq!(replayed, [Buttons::empty(), Buttons::UP, Buttons::UP | Buttons::A]);
+
+        // Carrying on from a recorded state gives the same numbers
+        let mut rng = MovieRng::new(7);
+        assert!((0..100).all(|_| (2..255).contains(&rng.gen_range(2, 255))));
+        let mut resumed = MovieRng::new(rng.state());
+        assert_eq!(rng.next_u64(), resumed.next_u64());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_sessions_need_joypads() {
+        let path = Path::new("zapper.fm2");
+        let mut cpu = CPU::new();
+        cpu.bus.controllers().connect(Peripherals::Zapper);
+        let movie = Movie::new("zapper", md5(b"zapper"), 1);
+        assert!(MovieSession::start_recording(&mut cpu, movie.clone(), path).is_err());
+        assert!(MovieSession::start_playback(&mut cpu, movie.clone(), path).is_err());
+
+        cpu.bus.controllers().connect(Peripherals::FourScore);
+        let session = MovieSession::start_recording(&mut cpu, movie, path).unwrap();
+        assert!(session.movie().four_score);
+    }
+}
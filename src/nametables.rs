@@ -0,0 +1,122 @@
+use crate::cartridge::Mirroring;
+use crate::mapper::Mapper;
+use crate::savestate::Savestate;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const CIRAM_SIZE: usize = 0x800;
+const FOUR_SCREEN_SIZE: usize = 0x1000;
+
+// The memory the PPU sees at $2000 - $2FFF (mirrored up to $3EFF). That is the 2 KiB of VRAM
+// inside the console, plus another 2 KiB on the cartridge for four screen boards. Every access
+// goes through the cartridge since it wires up the nametables, either by picking a mirroring
+// mode, which it may change at any time, or by handling the access itself.
+// There's no PPU yet, so nothing reads or writes through here apart from tests and save states.
+pub struct Nametables {
+    vram: Vec<u8>,
+}
+
+impl Nametables {
+    pub fn new(mirroring: Mirroring) -> Self {
+        let size = match mirroring {
+            Mirroring::FourScreen => FOUR_SCREEN_SIZE,
+            _ => CIRAM_SIZE,
+        };
+
+        Nametables { vram: vec![0; size] }
+    }
+
+    pub fn read(&mut self, mapper: Option<&mut Box<dyn Mapper>>, addr: u16) -> u8 {
+        match mapper {
+            Some(mapper) => mapper.nametable_read(addr, &self.vram),
+            // Without a cartridge A10 isn't connected to anything
+            None => self.vram[Mirroring::Horizontal.vram_offset(addr)],
+        }
+    }
+
+    pub fn write(&mut self, mapper: Option<&mut Box<dyn Mapper>>, addr: u16, data: u8) {
+        match mapper {
+            Some(mapper) => mapper.nametable_write(addr, data, &mut self.vram),
+            None => self.vram[Mirroring::Horizontal.vram_offset(addr)] = data,
+        }
+    }
+
+    // $0000 - $1FFF, which is only the cartridge's unless it maps VRAM there
+    pub fn pattern_read(&mut self, mapper: Option<&mut Box<dyn Mapper>>, addr: u16) -> u8 {
+        mapper.map_or(0, |mapper| mapper.pattern_read(addr, &self.vram))
+    }
+
+    pub fn pattern_write(&mut self, mapper: Option<&mut Box<dyn Mapper>>, addr: u16, data: u8) {
+        if let Some(mapper) = mapper {
+            mapper.pattern_write(addr, data, &mut self.vram);
+        }
+    }
+}
+
+impl Default for Nametables {
+    fn default() -> Self {
+        Self::new(Mirroring::Horizontal)
+    }
+}
+
+impl Savestate for Nametables {
+    crate::savestate_fields!(vram);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::mapper;
+
+    #[test]
+    fn test_header_mirroring() {
+        // test_rom sets vertical mirroring
+        let rom = test_rom(0, 1, 1);
+        let mut nametables = Nametables::new(rom.screen_mirroring);
+        let mut mapper = Some(mapper::from_rom(rom).unwrap());
+
+        nametables.write(mapper.as_mut(), 0x2005, 0x11);
+        assert_eq!(nametables.read(mapper.as_mut(), 0x2805), 0x11);
+        assert_eq!(nametables.read(mapper.as_mut(), 0x2405), 0x00);
+        // $3000 - $3EFF mirrors $2000 - $2EFF
+        assert_eq!(nametables.read(mapper.as_mut(), 0x3005), 0x11);
+    }
+
+    #[test]
+    fn test_four_screen() {
+        let mut rom = test_rom(0, 1, 1);
+        rom.screen_mirroring = Mirroring::FourScreen;
+        let mut nametables = Nametables::new(rom.screen_mirroring);
+        let mut mapper = Some(mapper::from_rom(rom).unwrap());
+
+        for (i, addr) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+            nametables.write(mapper.as_mut(), *addr, i as u8 + 1);
+        }
+        for (i, addr) in [0x2000, 0x2400, 0x2800, 0x2C00].iter().enumerate() {
+            assert_eq!(nametables.read(mapper.as_mut(), *addr), i as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn test_mapper_switches_mirroring() {
+        // FME-7 command $C sets the mirroring
+        let rom = test_rom(69, 2, 1);
+        let mut nametables = Nametables::new(rom.screen_mirroring);
+        let mut mapper = Some(mapper::from_rom(rom).unwrap());
+        nametables.write(mapper.as_mut(), 0x2000, 0x11);
+        nametables.write(mapper.as_mut(), 0x2C00, 0x22);
+
+        let m = mapper.as_mut().unwrap();
+        m.cpu_write(0x8000, 0xC);
+        m.cpu_write(0xA000, 1);
+        assert_eq!(nametables.read(mapper.as_mut(), 0x2400), 0x11);
+        assert_eq!(nametables.read(mapper.as_mut(), 0x2800), 0x22);
+
+        let m = mapper.as_mut().unwrap();
+        m.cpu_write(0xA000, 3);
+        assert_eq!(nametables.read(mapper.as_mut(), 0x2000), 0x22);
+        assert_eq!(nametables.read(mapper.as_mut(), 0x2400), 0x22);
+    }
+}
@@ -0,0 +1,379 @@
+use crate::cartridge::Rom;
+use crate::cpu::memory::Memory;
+use crate::cpu::CPU;
+use crate::input::{Buttons, InputState};
+use crate::movie::MovieRng;
+#[cfg(feature = "std")]
+use crate::movie::{self, MovieSession};
+use crate::savestate;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// CPU cycles in an NTSC frame
+pub const CYCLES_PER_FRAME: u64 = 29_781;
+pub const AUDIO_SAMPLE_RATE: u32 = 44_100;
+// The snake game was written for a much slower machine and only waits a couple of thousand cycles
+// between moves, so its frames are shorter to keep it at about 15 moves a second
+pub const SNAKE_CYCLES_PER_FRAME: u64 = 650;
+
+// Full range 16 bit mono, the same as the sound card gets
+pub type AudioSample = i16;
+
+// The snake game's 32 x 32 screen, one byte per pixel at $0200 - $05FF
+pub const SNAKE_WIDTH: usize = 32;
+pub const SNAKE_HEIGHT: usize = 32;
+const SNAKE_SCREEN: u16 = 0x0200;
+
+// What a PPU would draw into. Cartridges stay black for now since there is no PPU yet
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+// Snake Machine code
+pub const SNAKE_GAME: [u8; 309] = [
+    0x20, 0x06, 0x06, 0x20, 0x38, 0x06, 0x20, 0x0d, 0x06, 0x20, 0x2a, 0x06, 0x60, 0xa9, 0x02, 0x85,
+    0x02, 0xa9, 0x04, 0x85, 0x03, 0xa9, 0x11, 0x85, 0x10, 0xa9, 0x10, 0x85, 0x12, 0xa9, 0x0f, 0x85,
+    0x14, 0xa9, 0x04, 0x85, 0x11, 0x85, 0x13, 0x85, 0x15, 0x60, 0xa5, 0xfe, 0x85, 0x00, 0xa5, 0xfe,
+    0x29, 0x03, 0x18, 0x69, 0x02, 0x85, 0x01, 0x60, 0x20, 0x4d, 0x06, 0x20, 0x8d, 0x06, 0x20, 0xc3,
+    0x06, 0x20, 0x19, 0x07, 0x20, 0x20, 0x07, 0x20, 0x2d, 0x07, 0x4c, 0x38, 0x06, 0xa5, 0xff, 0xc9,
+    0x77, 0xf0, 0x0d, 0xc9, 0x64, 0xf0, 0x14, 0xc9, 0x73, 0xf0, 0x1b, 0xc9, 0x61, 0xf0, 0x22, 0x60,
+    0xa9, 0x04, 0x24, 0x02, 0xd0, 0x26, 0xa9, 0x01, 0x85, 0x02, 0x60, 0xa9, 0x08, 0x24, 0x02, 0xd0,
+    0x1b, 0xa9, 0x02, 0x85, 0x02, 0x60, 0xa9, 0x01, 0x24, 0x02, 0xd0, 0x10, 0xa9, 0x04, 0x85, 0x02,
+    0x60, 0xa9, 0x02, 0x24, 0x02, 0xd0, 0x05, 0xa9, 0x08, 0x85, 0x02, 0x60, 0x60, 0x20, 0x94, 0x06,
+    0x20, 0xa8, 0x06, 0x60, 0xa5, 0x00, 0xc5, 0x10, 0xd0, 0x0d, 0xa5, 0x01, 0xc5, 0x11, 0xd0, 0x07,
+    0xe6, 0x03, 0xe6, 0x03, 0x20, 0x2a, 0x06, 0x60, 0xa2, 0x02, 0xb5, 0x10, 0xc5, 0x10, 0xd0, 0x06,
+    0xb5, 0x11, 0xc5, 0x11, 0xf0, 0x09, 0xe8, 0xe8, 0xe4, 0x03, 0xf0, 0x06, 0x4c, 0xaa, 0x06, 0x4c,
+    0x35, 0x07, 0x60, 0xa6, 0x03, 0xca, 0x8a, 0xb5, 0x10, 0x95, 0x12, 0xca, 0x10, 0xf9, 0xa5, 0x02,
+    0x4a, 0xb0, 0x09, 0x4a, 0xb0, 0x19, 0x4a, 0xb0, 0x1f, 0x4a, 0xb0, 0x2f, 0xa5, 0x10, 0x38, 0xe9,
+    0x20, 0x85, 0x10, 0x90, 0x01, 0x60, 0xc6, 0x11, 0xa9, 0x01, 0xc5, 0x11, 0xf0, 0x28, 0x60, 0xe6,
+    0x10, 0xa9, 0x1f, 0x24, 0x10, 0xf0, 0x1f, 0x60, 0xa5, 0x10, 0x18, 0x69, 0x20, 0x85, 0x10, 0xb0,
+    0x01, 0x60, 0xe6, 0x11, 0xa9, 0x06, 0xc5, 0x11, 0xf0, 0x0c, 0x60, 0xc6, 0x10, 0xa5, 0x10, 0x29,
+    0x1f, 0xc9, 0x1f, 0xf0, 0x01, 0x60, 0x4c, 0x35, 0x07, 0xa0, 0x00, 0xa5, 0xfe, 0x91, 0x00, 0x60,
+    0xa6, 0x03, 0xa9, 0x00, 0x81, 0x10, 0xa2, 0x00, 0xa9, 0x01, 0x81, 0x10, 0x60, 0xa2, 0x00, 0xea,
+    0xea, 0xca, 0xd0, 0xfb, 0x60,
+];
+
+pub fn snake_color(byte: u8) -> [u8; 3] {
+    // Only bytes 0 and 1 matter. Must be black and white.
+    // The values are in the range of 0 to 255
+    // Because that is what we write to 0xfe which is a random num generator
+    // Apple will always be green due to range of rands written to 0xfe
+    match byte {
+        0 => [0, 0, 0],
+        1 => [255, 255, 255],
+        _ => [0, 255, 0],
+    }
+}
+
+// The snake game predates the joypads, it reads the ASCII code of the last W/A/S/D key from $FF
+pub fn write_snake_direction(cpu: &mut CPU, buttons: Buttons) {
+    let key = if buttons.contains(Buttons::UP) {
+        b'w'
+    } else if buttons.contains(Buttons::LEFT) {
+        b'a'
+    } else if buttons.contains(Buttons::DOWN) {
+        b's'
+    } else if buttons.contains(Buttons::RIGHT) {
+        b'd'
+    } else {
+        return;
+    };
+    cpu.mem_write(0xff, key);
+}
+
+// A picture as RGB24, 3 bytes per pixel, row by row
+pub struct FrameBuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        FrameBuffer { width, height, pixels: vec![0; width * height * 3] }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    // CRC32 of the pixels, to compare frames without keeping them
+    pub fn hash(&self) -> u32 {
+        savestate::crc32(&[&self.pixels])
+    }
+
+    // Binary PPM, about the simplest image file there is
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut out = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        out.extend_from_slice(&self.pixels);
+        out
+    }
+
+    // An uncompressed PNG. The image data is zlib wrapped but only in stored deflate blocks, which
+    // is plenty for screenshots this size
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // 8 bits per channel, RGB, then the only compression, filtering and interlacing there are
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        // Every row starts with its filter type, 0 for none
+        let mut raw = Vec::with_capacity((self.width * 3 + 1) * self.height);
+        for row in self.pixels.chunks(self.width * 3) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+
+        let mut zlib = vec![0x78, 0x01];
+        let blocks = raw.chunks(0xFFFF);
+        let count = blocks.len();
+        for (i, block) in blocks.enumerate() {
+            zlib.push((i + 1 == count) as u8);
+            zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+        if count == 0 {
+            zlib.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+        }
+        zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+        let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut out, b"IHDR", &header);
+        png_chunk(&mut out, b"IDAT", &zlib);
+        png_chunk(&mut out, b"IEND", &[]);
+        out
+    }
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&savestate::crc32(&[kind, data]).to_be_bytes());
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+// The console without a frontend, run a frame at a time. Nothing here needs a window or a sound
+// device, so it can run anywhere
+pub struct Nes {
+    cpu: CPU,
+    frame: FrameBuffer,
+    audio: Vec<AudioSample>,
+    cycles_per_frame: u64,
+    next_frame: u64,
+}
+
+impl Nes {
+    pub fn new(rom: Rom) -> Result<Self, String> {
+        let mut cpu = CPU::new();
+        cpu.bus.insert_cartridge(rom)?;
+        Ok(Nes::start(cpu, FrameBuffer::new(SCREEN_WIDTH, SCREEN_HEIGHT), CYCLES_PER_FRAME))
+    }
+
+    // The built in snake game, with its randomness seeded so that runs repeat
+    pub fn snake(seed: u64) -> Self {
+        let mut cpu = CPU::new();
+        cpu.load(SNAKE_GAME.to_vec());
+        // Only the snake game needs the frontend to supply randomness
+        cpu.bus.set_snake_rng(Some(MovieRng::new(seed)));
+        Nes::start(cpu, FrameBuffer::new(SNAKE_WIDTH, SNAKE_HEIGHT), SNAKE_CYCLES_PER_FRAME)
+    }
+
+    fn start(mut cpu: CPU, frame: FrameBuffer, cycles_per_frame: u64) -> Self {
+        cpu.bus.apu().set_sample_rate(AUDIO_SAMPLE_RATE);
+        cpu.reset();
+        let next_frame = (cpu.bus.cycles() / cycles_per_frame + 1) * cycles_per_frame;
+        Nes { cpu, frame, audio: Vec::new(), cycles_per_frame, next_frame }
+    }
+
+    // For anything the façade doesn't cover, eg. connecting devices or saving state
+    pub fn cpu(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    // The last frame run. Only the snake game draws anything, cartridges' frames stay black until
+    // there's a PPU so there's no point comparing them
+    pub fn frame(&self) -> &FrameBuffer {
+        &self.frame
+    }
+
+    pub fn is_snake(&self) -> bool {
+        self.cpu.bus.snake_rng().is_some()
+    }
+
+    // Where the snake game's randomness has got to, for a movie recorded from here to carry on
+    // with. Cartridges don't use it
+    pub fn rng_state(&self) -> u64 {
+        self.cpu.bus.snake_rng().map_or(1, MovieRng::state)
+    }
+
+    // What to run the next frame with: the movie's input when one is playing, otherwise `live`,
+    // which a recording logs. Also does any reset the movie asks for. None once playback has run
+    // out
+    #[cfg(feature = "std")]
+    pub fn movie_input(&mut self, session: &mut MovieSession, live: &InputState) -> Option<InputState> {
+        let (input, commands) = session.next_frame(live)?;
+        if commands & (movie::SOFT_RESET | movie::POWER) != 0 {
+            self.cpu.reset();
+        }
+        Some(input)
+    }
+
+    // Runs until the next frame starts with `input` held throughout. Returns the frame and the
+    // sound made during it
+    pub fn run_frame(&mut self, input: &InputState) -> (&FrameBuffer, &[AudioSample]) {
+        // Resets and loaded states move the clock. Frames stay on the same boundaries counted from
+        // power on, so a state saved as a frame started runs the rest of it the same as it did
+        let now = self.cpu.bus.cycles();
+        if now >= self.next_frame || self.next_frame - now > self.cycles_per_frame {
+            self.next_frame = (now / self.cycles_per_frame + 1) * self.cycles_per_frame;
+        }
+
+        self.cpu.bus.controllers().update(input);
+        if self.is_snake() {
+            write_snake_direction(&mut self.cpu, input.buttons[0]);
+        }
+
+        // A BRK or an opcode we don't know ends the program, the machine then sits where it stopped
+        while self.cpu.bus.cycles() < self.next_frame {
+            if let Some(rng) = self.cpu.bus.snake_rng_mut() {
+                // Exclude 0 and 1 so we don't have a black or white
+                let random = rng.gen_range(2, 255);
+                self.cpu.mem_write(0xfe, random);
+            }
+            if self.cpu.step() != Ok(true) {
+                break;
+            }
+        }
+        self.next_frame += self.cycles_per_frame;
+
+        if self.is_snake() {
+            self.draw_snake();
+        }
+        self.audio.clear();
+        if let Some(pipeline) = self.cpu.bus.apu().audio() {
+            self.audio.extend_from_slice(pipeline.end_frame_i16());
+        }
+        (&self.frame, &self.audio)
+    }
+
+    fn draw_snake(&mut self) {
+        for (i, pixel) in self.frame.pixels.chunks_mut(3).enumerate() {
+            let byte = self.cpu.mem_read(SNAKE_SCREEN + i as u16);
+            pixel.copy_from_slice(&snake_color(byte));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_snake_repeats_with_the_same_seed() {
+        let run = |seed| {
+            let mut nes = Nes::snake(seed);
+            let mut input = InputState::default();
+            input.buttons[0] = Buttons::DOWN;
+            let (frame, audio) = nes.run_frame(&input);
+            // 44100 Hz for 650 of the CPU's 1.79 MHz
+            assert!((15..18).contains(&audio.len()));
+            frame.hash()
+        };
+
+        assert_eq!(run(42), run(42));
+        // The apple is somewhere else
+        assert_ne!(run(42), run(43));
+        // And the snake is drawn, so the screen isn't all black
+        assert_ne!(run(42), FrameBuffer::new(SNAKE_WIDTH, SNAKE_HEIGHT).hash());
+    }
+
+    #[test]
+    fn test_snake_carries_on_from_a_save_state() {
+        let mut nes = Nes::snake(42);
+        let idle = InputState::default();
+        for _ in 0..10 {
+            nes.run_frame(&idle);
+        }
+        let state = nes.cpu().save_state();
+        let run = |nes: &mut Nes| {
+            for _ in 0..10 {
+                nes.run_frame(&idle);
+            }
+            nes.cpu().save_state()
+        };
+        let first = run(&mut nes);
+
+        // The same random numbers come out again
+        nes.cpu().load_state(&state).unwrap();
+        assert_eq!(run(&mut nes), first);
+    }
+
+    // The way both frontends run movies: recording what the player holds, then playing it back on
+    // a fresh machine without them
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_movie_replays_to_the_same_state() {
+        let path = std::path::Path::new("snake.fm2");
+        let mut nes = Nes::snake(42);
+        let mut live = InputState::default();
+        // Started part way through, like F10 does
+        for _ in 0..10 {
+            nes.run_frame(&live);
+        }
+        let mut recording = movie::Movie::new("snake", movie::md5(&SNAKE_GAME), nes.rng_state());
+        recording.savestate = Some(nes.cpu().save_state());
+        let mut session = MovieSession::start_recording(nes.cpu(), recording, path).unwrap();
+        for buttons in [Buttons::DOWN, Buttons::RIGHT, Buttons::UP, Buttons::RIGHT] {
+            live.buttons[0] = buttons;
+            for _ in 0..10 {
+                let input = nes.movie_input(&mut session, &live).unwrap();
+                nes.run_frame(&input);
+            }
+        }
+        let recorded = savestate::crc32(&[&nes.cpu().save_state()]);
+
+        let movie = movie::Movie::parse(&session.movie().to_fm2()).unwrap();
+        let mut replay = Nes::snake(movie.rng_seed);
+        let mut playback = MovieSession::start_playback(replay.cpu(), movie, path).unwrap();
+        let idle = InputState::default();
+        while let Some(input) = replay.movie_input(&mut playback, &idle) {
+            replay.run_frame(&input);
+        }
+        assert_eq!(savestate::crc32(&[&replay.cpu().save_state()]), recorded);
+    }
+
+    #[test]
+    fn test_image_files() {
+        let mut frame = FrameBuffer::new(3, 2);
+        frame.pixels[0..3].copy_from_slice(&[255, 0, 0]);
+
+        let ppm = frame.to_ppm();
+        assert!(ppm.starts_with(b"P6\n3 2\n255\n"));
+        assert_eq!(ppm.len(), 11 + 18);
+
+        let png = frame.to_png();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR\x00\x00\x00\x03\x00\x00\x00\x02"));
+        assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+}
@@ -0,0 +1,430 @@
+use crate::apu::pipeline::CPU_CLOCK_RATE;
+use crate::cpu::memory::Memory;
+use crate::cpu::stack::Stack;
+use crate::cpu::CPU;
+use crate::mapper::nsf::NsfMapper;
+use crate::math;
+use bitflags::bitflags;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+// NSF header magic: "NESM" followed by MS-DOS end of file
+const NSF_TAG: [u8; 5] = [0x4E, 0x45, 0x53, 0x4D, 0x1A];
+const NSFE_TAG: [u8; 4] = *b"NSFE";
+const NSF_HEADER_SIZE: usize = 0x80;
+
+// Play rates in microseconds per call, for files that leave them out
+const DEFAULT_NTSC_SPEED: u16 = 16_639;
+const DEFAULT_PAL_SPEED: u16 = 19_997;
+
+// INIT and PLAY are called with this pushed as their return address minus one, so their RTS
+// lands here. Nothing is mapped at $4100, so no tune will have code there
+const RETURN_ADDRESS: u16 = 0x4100;
+// How long INIT or PLAY get to return before we give up on them, a second
+const MAX_CALL_CYCLES: u64 = 1_789_773;
+
+bitflags! {
+    // Header byte $7B
+    pub struct ExpansionChips: u8 {
+        const VRC6      = 0b00000001;
+        const VRC7      = 0b00000010;
+        const FDS       = 0b00000100;
+        const MMC5      = 0b00001000;
+        const NAMCO163  = 0b00010000;
+        const SUNSOFT5B = 0b00100000;
+    }
+}
+
+// A music rip: the game's sound driver and data, with the addresses to call to start a song
+// and to run it for a frame
+pub struct Nsf {
+    pub name: String,
+    pub artist: String,
+    pub copyright: String,
+    // Only NSFe files name their tracks, this is empty otherwise
+    pub track_labels: Vec<String>,
+
+    pub songs: u8,
+    // Counting from 0, unlike NSF headers which count from 1
+    pub start_song: u8,
+
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    // Set for tunes that only run on PAL consoles
+    pub pal: bool,
+    // Initial 4 KiB bank at each of $8000, $9000... $F000, if the tune is bankswitched
+    pub banks: Option<[u8; 8]>,
+    pub chips: ExpansionChips,
+    pub data: Vec<u8>,
+}
+
+impl Nsf {
+    pub fn parse(raw: &[u8]) -> Result<Nsf, String> {
+        let nsf = if raw.starts_with(&NSF_TAG) {
+            Self::parse_nsf(raw)?
+        } else if raw.starts_with(&NSFE_TAG) {
+            Self::parse_nsfe(raw)?
+        } else {
+            return Err("File is not in NSF or NSFe format".to_string());
+        };
+
+        if nsf.banks.is_none() && nsf.load_address < 0x8000 {
+            return Err(format!("Load address ${:04X} is below $8000", nsf.load_address));
+        }
+        Ok(nsf)
+    }
+
+    fn parse_nsf(raw: &[u8]) -> Result<Nsf, String> {
+        if raw.len() < NSF_HEADER_SIZE {
+            return Err("File is shorter than an NSF header".to_string());
+        }
+
+        let mut banks = [0; 8];
+        banks.copy_from_slice(&raw[0x70..0x78]);
+        // Any non-zero bank means the tune is bankswitched
+        let banks = if banks.iter().any(|&bank| bank != 0) { Some(banks) } else { None };
+
+        // Byte $7A bit 0 is PAL, bit 1 is a tune that runs on both
+        let pal = raw[0x7A] & 0b11 == 0b01;
+
+        Ok(Nsf {
+            name: fixed_string(&raw[0x0E..0x2E]),
+            artist: fixed_string(&raw[0x2E..0x4E]),
+            copyright: fixed_string(&raw[0x4E..0x6E]),
+            track_labels: Vec::new(),
+            songs: raw[0x06],
+            start_song: raw[0x07].saturating_sub(1),
+            load_address: read_u16(raw, 0x08),
+            init_address: read_u16(raw, 0x0A),
+            play_address: read_u16(raw, 0x0C),
+            ntsc_speed: speed_or(read_u16(raw, 0x6E), DEFAULT_NTSC_SPEED),
+            pal_speed: speed_or(read_u16(raw, 0x78), DEFAULT_PAL_SPEED),
+            pal,
+            banks,
+            chips: ExpansionChips::from_bits_truncate(raw[0x7B]),
+            data: raw[NSF_HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    // NSFe is a list of chunks after the tag: a 32 bit length, a four character ID and the data.
+    // Chunks we don't know can be skipped unless their ID starts with a capital letter
+    fn parse_nsfe(raw: &[u8]) -> Result<Nsf, String> {
+        let mut nsf = Nsf {
+            name: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            track_labels: Vec::new(),
+            songs: 1,
+            start_song: 0,
+            load_address: 0,
+            init_address: 0,
+            play_address: 0,
+            ntsc_speed: DEFAULT_NTSC_SPEED,
+            pal_speed: DEFAULT_PAL_SPEED,
+            pal: false,
+            banks: None,
+            chips: ExpansionChips::empty(),
+            data: Vec::new(),
+        };
+        let mut info = false;
+
+        let mut pos = NSFE_TAG.len();
+        while pos + 8 <= raw.len() {
+            let len = u32::from_le_bytes([raw[pos], raw[pos + 1], raw[pos + 2], raw[pos + 3]]) as usize;
+            let id = &raw[pos + 4..pos + 8];
+            let start = pos + 8;
+            let chunk = raw
+                .get(start..start.saturating_add(len))
+                .ok_or_else(|| format!("{} chunk runs past the end of the file", fourcc(id)))?;
+            pos = start + len;
+
+            match id {
+                b"INFO" => {
+                    if chunk.len() < 9 {
+                        return Err("INFO chunk is too short".to_string());
+                    }
+                    nsf.load_address = read_u16(chunk, 0);
+                    nsf.init_address = read_u16(chunk, 2);
+                    nsf.play_address = read_u16(chunk, 4);
+                    nsf.pal = chunk[6] & 0b11 == 0b01;
+                    nsf.chips = ExpansionChips::from_bits_truncate(chunk[7]);
+                    nsf.songs = chunk.get(8).copied().unwrap_or(1);
+                    nsf.start_song = chunk.get(9).copied().unwrap_or(0);
+                    info = true;
+                }
+                b"DATA" => nsf.data = chunk.to_vec(),
+                b"BANK" => {
+                    let mut banks = [0; 8];
+                    let count = chunk.len().min(8);
+                    banks[..count].copy_from_slice(&chunk[..count]);
+                    nsf.banks = Some(banks);
+                }
+                b"RATE" if chunk.len() >= 2 => {
+                    nsf.ntsc_speed = speed_or(read_u16(chunk, 0), DEFAULT_NTSC_SPEED);
+                    if chunk.len() >= 4 {
+                        nsf.pal_speed = speed_or(read_u16(chunk, 2), DEFAULT_PAL_SPEED);
+                    }
+                }
+                b"auth" => {
+                    let mut strings = chunk.split(|&byte| byte == 0).map(fixed_string);
+                    nsf.name = strings.next().unwrap_or_default();
+                    nsf.artist = strings.next().unwrap_or_default();
+                    nsf.copyright = strings.next().unwrap_or_default();
+                }
+                b"tlbl" => {
+                    nsf.track_labels = chunk
+                        .split(|&byte| byte == 0)
+                        .take(nsf.songs as usize)
+                        .map(fixed_string)
+                        .collect();
+                }
+                b"NEND" => break,
+                _ if id[0].is_ascii_uppercase() => {
+                    return Err(format!("Unsupported NSFe chunk {}", fourcc(id)));
+                }
+                _ => {}
+            }
+        }
+
+        if !info || nsf.data.is_empty() {
+            return Err("NSFe file is missing its INFO or DATA chunk".to_string());
+        }
+        Ok(nsf)
+    }
+}
+
+fn read_u16(raw: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([raw[pos], raw[pos + 1]])
+}
+
+// NSF strings are padded with zeroes to a fixed size, and aren't always terminated
+fn fixed_string(raw: &[u8]) -> String {
+    let len = raw.iter().position(|&byte| byte == 0).unwrap_or(raw.len());
+    String::from_utf8_lossy(&raw[..len]).into_owned()
+}
+
+fn fourcc(id: &[u8]) -> String {
+    String::from_utf8_lossy(id).into_owned()
+}
+
+fn speed_or(speed: u16, default: u16) -> u16 {
+    if speed == 0 { default } else { speed }
+}
+
+// Plays an NSF the way a hardware player does: INIT once with the song number, then PLAY at the
+// rate the file asks for. Between calls the CPU would sit in a loop, so only the bus runs.
+pub struct NsfPlayer {
+    cpu: CPU,
+    nsf: Nsf,
+    song: u8,
+    // In CPU cycles
+    play_period: f64,
+    next_play: f64,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: Nsf) -> Self {
+        // The APU always runs at the NTSC rate, PAL tunes just get called at their own speed
+        let speed = if nsf.pal { nsf.pal_speed } else { nsf.ntsc_speed };
+        NsfPlayer {
+            cpu: CPU::new(),
+            song: nsf.start_song,
+            play_period: speed as f64 * CPU_CLOCK_RATE / 1_000_000.0,
+            next_play: 0.0,
+            nsf,
+        }
+    }
+
+    pub fn cpu(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn nsf(&self) -> &Nsf {
+        &self.nsf
+    }
+
+    pub fn song(&self) -> u8 {
+        self.song
+    }
+
+    // Sets the console up as the NSF spec describes and calls INIT for `song`, counting from 0
+    pub fn start_song(&mut self, song: u8) -> Result<(), String> {
+        if song >= self.nsf.songs {
+            return Err(format!("There is no track {}, the file has {}", song + 1, self.nsf.songs));
+        }
+        self.song = song;
+
+        // A fresh mapper brings back the initial banks and clears the RAM at $6000
+        self.cpu.bus.insert_mapper(Box::new(NsfMapper::new(&self.nsf)));
+        self.cpu.bus.reset();
+        for addr in 0x0000..0x0800 {
+            self.cpu.mem_write(addr, 0);
+        }
+        for addr in 0x4000..=0x4013 {
+            self.cpu.mem_write(addr, 0);
+        }
+        self.cpu.mem_write(0x4015, 0x0F);
+        self.cpu.mem_write(0x4017, 0x40);
+
+        self.cpu.register_a = song;
+        self.cpu.register_x = self.nsf.pal as u8;
+        self.cpu.register_y = 0;
+        self.cpu.stack_pointer = 0xFF;
+        self.call(self.nsf.init_address)?;
+
+        self.next_play = self.cpu.bus.cycles() as f64 + self.play_period;
+        Ok(())
+    }
+
+    // Calls PLAY, then runs the bus until the next call is due
+    pub fn play(&mut self) -> Result<(), String> {
+        self.call(self.nsf.play_address)?;
+
+        while (self.cpu.bus.cycles() as f64) < self.next_play {
+            let remaining = self.next_play - self.cpu.bus.cycles() as f64;
+            self.cpu.bus.tick(math::ceil(remaining).min(u8::MAX as f64) as u8);
+        }
+
+        // If PLAY ran long, the next call comes straight away rather than trying to catch up
+        self.next_play = self.next_play.max(self.cpu.bus.cycles() as f64) + self.play_period;
+        Ok(())
+    }
+
+    // Runs the subroutine at `addr` until it returns, like a JSR from the player's own code
+    fn call(&mut self, addr: u16) -> Result<(), String> {
+        self.cpu.stack_push_u16(RETURN_ADDRESS - 1);
+        self.cpu.program_counter = addr;
+
+        let deadline = self.cpu.bus.cycles() + MAX_CALL_CYCLES;
+        while self.cpu.program_counter != RETURN_ADDRESS {
+            if !self.cpu.step()? {
+                return Err(format!("Hit a BRK in the routine at ${:04X}", addr));
+            }
+            if self.cpu.bus.cycles() > deadline {
+                return Err(format!("The routine at ${:04X} didn't return", addr));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // INIT at $8000 stores the song number at $00, PLAY at $8003 counts its calls at $01
+    const DRIVER: [u8; 6] = [0x85, 0x00, 0x60, 0xE6, 0x01, 0x60];
+
+    fn nsf_file(banks: [u8; 8], data: &[u8]) -> Vec<u8> {
+        let mut raw = vec![0; NSF_HEADER_SIZE];
+        raw[0..5].copy_from_slice(&NSF_TAG);
+        raw[0x05] = 1;
+        raw[0x06] = 3;
+        raw[0x07] = 2;
+        raw[0x08..0x0A].copy_from_slice(&0x8000u16.to_le_bytes());
+        raw[0x0A..0x0C].copy_from_slice(&0x8000u16.to_le_bytes());
+        raw[0x0C..0x0E].copy_from_slice(&0x8003u16.to_le_bytes());
+        raw[0x0E..0x13].copy_from_slice(b"Title");
+        raw[0x6E..0x70].copy_from_slice(&16_639u16.to_le_bytes());
+        raw[0x70..0x78].copy_from_slice(&banks);
+        raw[0x7B] = 0b0000_0001;
+        raw.extend_from_slice(data);
+        raw
+    }
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut raw = (data.len() as u32).to_le_bytes().to_vec();
+        raw.extend_from_slice(id);
+        raw.extend_from_slice(data);
+        raw
+    }
+
+    #[test]
+    fn test_parse_nsf_header() {
+        let nsf = Nsf::parse(&nsf_file([0; 8], &DRIVER)).unwrap();
+        assert_eq!(nsf.name, "Title");
+        assert_eq!(nsf.songs, 3);
+        assert_eq!(nsf.start_song, 1);
+        assert_eq!(nsf.play_address, 0x8003);
+        assert_eq!(nsf.banks, None);
+        assert_eq!(nsf.chips, ExpansionChips::VRC6);
+        assert_eq!(nsf.data, DRIVER);
+    }
+
+    #[test]
+    fn test_parse_nsfe_chunks() {
+        let mut info = Vec::new();
+        for addr in [0x8000u16, 0x8000, 0x8003] {
+            info.extend_from_slice(&addr.to_le_bytes());
+        }
+        info.extend_from_slice(&[0, 0b0010_0000, 2, 1]);
+
+        let mut raw = NSFE_TAG.to_vec();
+        raw.extend(chunk(b"INFO", &info));
+        raw.extend(chunk(b"DATA", &DRIVER));
+        raw.extend(chunk(b"auth", b"Game\0Composer\0Company\0Ripper\0"));
+        raw.extend(chunk(b"tlbl", b"Intro\0Boss\0"));
+        raw.extend(chunk(b"xtra", &[1, 2, 3]));
+        raw.extend(chunk(b"NEND", &[]));
+
+        let nsf = Nsf::parse(&raw).unwrap();
+        assert_eq!(nsf.artist, "Composer");
+        assert_eq!(nsf.track_labels, ["Intro", "Boss"]);
+        assert_eq!(nsf.start_song, 1);
+        assert_eq!(nsf.ntsc_speed, DEFAULT_NTSC_SPEED);
+        assert_eq!(nsf.chips, ExpansionChips::SUNSOFT5B);
+        assert_eq!(nsf.data, DRIVER);
+
+        // Chunks we don't understand but must are an error
+        let mut raw = raw[..raw.len() - 8].to_vec();
+        raw.extend(chunk(b"XTRA", &[]));
+        assert!(Nsf::parse(&raw).is_err());
+    }
+
+    #[test]
+    fn test_init_and_play() {
+        let mut player = NsfPlayer::new(Nsf::parse(&nsf_file([0; 8], &DRIVER)).unwrap());
+        player.start_song(2).unwrap();
+        assert_eq!(player.cpu().mem_read(0x00), 2);
+
+        player.play().unwrap();
+        player.play().unwrap();
+        assert_eq!(player.cpu().mem_read(0x01), 2);
+
+        // Two calls at ~60 Hz
+        let cycles = player.cpu().bus.cycles();
+        assert!((59_500..59_600).contains(&cycles), "{}", cycles);
+
+        assert!(player.start_song(3).is_err());
+    }
+
+    #[test]
+    fn test_unknown_opcode_is_an_error() {
+        let mut player = NsfPlayer::new(Nsf::parse(&nsf_file([0; 8], &[0x02])).unwrap());
+        assert_eq!(player.start_song(0).unwrap_err(), "Unknown opcode $02 at $8000");
+    }
+
+    #[test]
+    fn test_bankswitching() {
+        // Three banks, each filled with its number, loaded at $8000 with the driver in bank 0
+        let mut data = vec![0; 3 * 0x1000];
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = (i / 0x1000) as u8;
+        }
+        data[..DRIVER.len()].copy_from_slice(&DRIVER);
+
+        let mut player = NsfPlayer::new(Nsf::parse(&nsf_file([0, 2, 1, 0, 0, 0, 0, 0], &data)).unwrap());
+        player.start_song(0).unwrap();
+        let cpu = player.cpu();
+        assert_eq!(cpu.mem_read(0x9000), 2);
+        assert_eq!(cpu.mem_read(0xA000), 1);
+
+        cpu.mem_write(0x5FF9, 1);
+        assert_eq!(cpu.mem_read(0x9000), 1);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::cpu::CPU;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+// Recent save states to step back through. The newest is kept whole, every older one as the
+// difference from the state after it, which is mostly zeros since little changes in a frame.
+// The oldest states are dropped to stay under the memory limit
+pub struct Rewind {
+    // Frames between snapshots
+    interval: u32,
+    max_bytes: usize,
+    frames: u32,
+    newest: Option<Vec<u8>>,
+    // Oldest first, each turns the state after it back into itself
+    deltas: VecDeque<Vec<u8>>,
+    delta_bytes: usize,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, max_bytes: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            max_bytes,
+            frames: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+        }
+    }
+
+    // Called at the end of every frame, takes a snapshot every `interval` of them
+    pub fn end_frame(&mut self, cpu: &CPU) {
+        self.frames += 1;
+        if self.frames >= self.interval {
+            self.frames = 0;
+            self.push(cpu.save_state());
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(newest) = self.newest.take() {
+            let delta = encode_delta(&state, &newest);
+            self.delta_bytes += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.newest = Some(state);
+
+        while self.memory_used() > self.max_bytes {
+            match self.deltas.pop_front() {
+                Some(oldest) => self.delta_bytes -= oldest.len(),
+                None => break,
+            }
+        }
+    }
+
+    // Takes the newest state off, the one before it becomes the newest
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest.take()?;
+        if let Some(delta) = self.deltas.pop_back() {
+            self.delta_bytes -= delta.len();
+            self.newest = Some(decode_delta(&newest, &delta));
+        }
+        self.frames = 0;
+        Some(newest)
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.newest.as_ref().map_or(0, |newest| newest.len()) + self.delta_bytes
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &mut &[u8]) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    while let Some((&byte, rest)) = data.split_first() {
+        *data = rest;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    value
+}
+
+// `target` XORed with `base`, as the target's length then runs of (unchanged bytes, changed
+// bytes, the changed bytes XORed). States can differ slightly in length, past the end of the
+// base counts as zeros
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| target[i] ^ base.get(i).copied().unwrap_or(0);
+
+    let mut out = Vec::new();
+    write_varint(&mut out, target.len());
+    let mut i = 0;
+    while i < target.len() {
+        let start = i;
+        while i < target.len() && xor(i) == 0 {
+            i += 1;
+        }
+        let same = i - start;
+
+        let start = i;
+        while i < target.len() && xor(i) != 0 {
+            i += 1;
+        }
+        write_varint(&mut out, same);
+        write_varint(&mut out, i - start);
+        out.extend((start..i).map(xor));
+    }
+    out
+}
+
+fn decode_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut data = delta;
+    let len = read_varint(&mut data);
+    let mut target: Vec<u8> = (0..len).map(|i| base.get(i).copied().unwrap_or(0)).collect();
+
+    let mut i = 0;
+    while !data.is_empty() {
+        i += read_varint(&mut data);
+        let changed = read_varint(&mut data);
+        for (byte, xor) in target[i..i + changed].iter_mut().zip(data) {
+            *byte ^= xor;
+        }
+        data = &data[changed..];
+        i += changed;
+    }
+    target
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_delta_round_trip() {
+        let base: Vec<u8> = (0..=255).collect();
+        let mut target = base.clone();
+        target[3] = 0;
+        target[200..210].fill(0xAA);
+        target.extend([1, 2, 3]);
+
+        let delta = encode_delta(&base, &target);
+        assert!(delta.len() < 30);
+        assert_eq!(decode_delta(&base, &delta), target);
+        assert_eq!(decode_delta(&target, &encode_delta(&target, &base)), base);
+    }
+
+    #[test]
+    fn test_steps_back_newest_first_within_memory() {
+        let state = |frame: u8| {
+            let mut state = vec![0; 1000];
+            state[0] = frame;
+            state
+        };
+
+        let mut rewind = Rewind::new(1, 1100);
+        for frame in 0..20 {
+            rewind.push(state(frame));
+        }
+        assert!(rewind.memory_used() <= 1100);
+        assert!(rewind.len() > 2 && rewind.len() < 20);
+
+        let popped: Vec<u8> = std::iter::from_fn(|| rewind.pop()).map(|state| state[0]).collect();
+        assert_eq!(popped[0], 19);
+        assert!(popped.windows(2).all(|pair| pair[0] == pair[1] + 1));
+        assert!(rewind.is_empty());
+    }
+}
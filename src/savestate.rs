@@ -0,0 +1,322 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+
+// Save state files start with this, then the format version and the CRC32 of the ROM they are
+// from. Bump the version whenever what any component saves changes
+const MAGIC: [u8; 4] = *b"NESS";
+pub const VERSION: u32 = 2;
+pub const HEADER_SIZE: usize = 12;
+pub const SLOTS: u8 = 9;
+
+// Something that is part of the machine's state. Components save their fields in a fixed order
+// and load them back in the same order, leaving out anything that comes from the ROM or the
+// frontend's setup, which has to already match.
+pub trait Savestate {
+    fn save(&self, out: &mut Vec<u8>);
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String>;
+}
+
+// Implements `Savestate` by saving each of the listed fields in turn. `field if flag` only saves
+// the field while the bool field `flag` is set, eg. CHR memory that is only worth saving as RAM
+#[macro_export]
+macro_rules! savestate_fields {
+    ($($field:ident $(if $flag:ident)?),* $(,)?) => {
+        fn save(&self, out: &mut $crate::alloc::vec::Vec<u8>) {
+            $($(if self.$flag)? { $crate::savestate::Savestate::save(&self.$field, out); })*
+        }
+
+        fn load(&mut self, data: &mut &[u8]) -> Result<(), $crate::alloc::string::String> {
+            $($(if self.$flag)? { $crate::savestate::Savestate::load(&mut self.$field, data)?; })*
+            Ok(())
+        }
+    };
+}
+
+// Splits the next `len` bytes off the front of `data`
+pub fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    if data.len() < len {
+        return Err("Save state is truncated".to_string());
+    }
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+    Ok(taken)
+}
+
+macro_rules! savestate_int {
+    ($($int:ty),*) => {
+        $(impl Savestate for $int {
+            fn save(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+                let bytes = take(data, core::mem::size_of::<$int>())?;
+                *self = <$int>::from_le_bytes(bytes.try_into().unwrap());
+                Ok(())
+            }
+        })*
+    };
+}
+
+savestate_int!(u8, u16, u32, u64, i8, i16, i32);
+
+impl Savestate for usize {
+    fn save(&self, out: &mut Vec<u8>) {
+        (*self as u64).save(out);
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        let mut value = 0u64;
+        value.load(data)?;
+        *self = usize::try_from(value).map_err(|_| "Save state has a value out of range".to_string())?;
+        Ok(())
+    }
+}
+
+impl Savestate for bool {
+    fn save(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        *self = take(data, 1)?[0] != 0;
+        Ok(())
+    }
+}
+
+impl Savestate for f32 {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.to_bits().save(out);
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        let mut bits = 0u32;
+        bits.load(data)?;
+        *self = f32::from_bits(bits);
+        Ok(())
+    }
+}
+
+impl<T: Savestate, const N: usize> Savestate for [T; N] {
+    fn save(&self, out: &mut Vec<u8>) {
+        for item in self {
+            item.save(out);
+        }
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        for item in self {
+            item.load(data)?;
+        }
+        Ok(())
+    }
+}
+
+// Vecs are sized by the cartridge, so a state with a different length is for other hardware
+impl<T: Savestate> Savestate for Vec<T> {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.len().save(out);
+        for item in self {
+            item.save(out);
+        }
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        let mut len = 0usize;
+        len.load(data)?;
+        if len != self.len() {
+            return Err(format!("Save state has {} bytes of memory where {} were expected", len, self.len()));
+        }
+        for item in self {
+            item.load(data)?;
+        }
+        Ok(())
+    }
+}
+
+// Values that may be empty are filled in by loading, so need a default to start from
+impl<T: Savestate + Default> Savestate for Option<T> {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.is_some().save(out);
+        if let Some(value) = self {
+            value.save(out);
+        }
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        let mut present = false;
+        present.load(data)?;
+        *self = if present {
+            let mut value = T::default();
+            value.load(data)?;
+            Some(value)
+        } else {
+            None
+        };
+        Ok(())
+    }
+}
+
+impl<A: Savestate, B: Savestate> Savestate for (A, B) {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.0.save(out);
+        self.1.save(out);
+    }
+
+    fn load(&mut self, data: &mut &[u8]) -> Result<(), String> {
+        self.0.load(data)?;
+        self.1.load(data)
+    }
+}
+
+pub fn crc32(data: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data.iter().flat_map(|chunk| chunk.iter()) {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+pub fn write_header(out: &mut Vec<u8>, rom_hash: u32) {
+    out.extend_from_slice(&MAGIC);
+    VERSION.save(out);
+    rom_hash.save(out);
+}
+
+// Refuses states that aren't ours, are from another version or were made with another game
+pub fn read_header(data: &mut &[u8], rom_hash: u32) -> Result<(), String> {
+    if take(data, MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err("Not a save state".to_string());
+    }
+
+    let mut version = 0u32;
+    version.load(data)?;
+    if version != VERSION {
+        return Err(format!("Save state is version {}, only version {} can be loaded", version, VERSION));
+    }
+
+    let mut hash = 0u32;
+    hash.load(data)?;
+    if hash != rom_hash {
+        return Err(format!("Save state is for another game (ROM CRC32 {:08X}, not {:08X})", hash, rom_hash));
+    }
+    Ok(())
+}
+
+// Numbered save state files for a ROM, kept next to it unless a save directory is given:
+// zelda.nes saves to zelda.ss1 - zelda.ss9
+#[cfg(feature = "std")]
+pub struct SaveSlots {
+    base: PathBuf,
+    slot: u8,
+}
+
+#[cfg(feature = "std")]
+impl SaveSlots {
+    pub fn new(rom_path: &Path, save_dir: Option<&Path>) -> Self {
+        let base = match (save_dir, rom_path.file_name()) {
+            (Some(dir), Some(name)) => dir.join(name),
+            _ => rom_path.to_path_buf(),
+        };
+
+        SaveSlots { base, slot: 1 }
+    }
+
+    pub fn slot(&self) -> u8 {
+        self.slot
+    }
+
+    // Goes round 1 - SLOTS
+    pub fn next_slot(&mut self) -> u8 {
+        self.slot = self.slot % SLOTS + 1;
+        self.slot
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.base.with_extension(format!("ss{}", self.slot))
+    }
+
+    pub fn save(&self, state: &[u8]) -> io::Result<()> {
+        let path = self.path();
+        if let Some(dir) = path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+
+        // Like battery saves, don't lose the old state if writing the new one fails
+        let tmp = path.with_extension(format!("ss{}.tmp", self.slot));
+        fs::write(&tmp, state)?;
+        fs::rename(&tmp, &path)
+    }
+
+    pub fn load(&self) -> io::Result<Vec<u8>> {
+        fs::read(self.path())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_values_round_trip() {
+        let values = ([1u8, 2, 3], vec![0x1234u16, 0x5678], Some(-2i16), 1.5f32, (true, 7usize));
+        let mut out = Vec::new();
+        values.0.save(&mut out);
+        values.1.save(&mut out);
+        values.2.save(&mut out);
+        values.3.save(&mut out);
+        values.4.save(&mut out);
+
+        let mut loaded = ([0u8; 3], vec![0u16; 2], None, 0f32, (false, 0usize));
+        let mut data = &out[..];
+        loaded.0.load(&mut data).unwrap();
+        loaded.1.load(&mut data).unwrap();
+        loaded.2.load(&mut data).unwrap();
+        loaded.3.load(&mut data).unwrap();
+        loaded.4.load(&mut data).unwrap();
+        assert_eq!(loaded, values);
+        assert!(data.is_empty());
+
+        // Running out, and memory of the wrong size
+        assert!(0u32.load(&mut &out[..2]).is_err());
+        assert!(vec![0u16; 3].load(&mut &out[3..]).is_err());
+    }
+
+    #[test]
+    fn test_header_checks() {
+        assert_eq!(crc32(&[b"1234", b"56789"]), 0xCBF4_3926);
+
+        let mut out = Vec::new();
+        write_header(&mut out, 0x1234);
+        assert!(read_header(&mut &out[..], 0x1234).is_ok());
+        assert!(read_header(&mut &out[..], 0x4321).unwrap_err().contains("another game"));
+
+        out[4] = 99;
+        assert!(read_header(&mut &out[..], 0x1234).unwrap_err().contains("version 99"));
+        assert!(read_header(&mut &b"NES\x1A"[..], 0x1234).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_slot_paths() {
+        let mut slots = SaveSlots::new(Path::new("roms/zelda.nes"), Some(Path::new("saves")));
+        assert_eq!(slots.path(), Path::new("saves/zelda.ss1"));
+        for _ in 0..8 {
+            slots.next_slot();
+        }
+        assert_eq!(slots.path(), Path::new("saves/zelda.ss9"));
+        assert_eq!(slots.next_slot(), 1);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::apu::{Apu, Channel};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Used when recording starts without anything else having set up the APU's audio
+const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+const HEADER_SIZE: u32 = 44;
+
+// Writes 16 bit mono PCM. The sizes in the header aren't known until the end, so they are
+// written as zero and patched in by `finish`.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    data_len: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<Self> {
+        out.write_all(&header(sample_rate, 0))?;
+        Ok(WavWriter { out, data_len: 0 })
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len += (samples.len() * 2) as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(HEADER_SIZE - 8 + self.data_len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&self.data_len.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn header(sample_rate: u32, data_len: u32) -> [u8; HEADER_SIZE as usize] {
+    let mut header = [0; HEADER_SIZE as usize];
+    header[0..4].copy_from_slice(b"RIFF");
+    header[4..8].copy_from_slice(&(HEADER_SIZE - 8 + data_len).to_le_bytes());
+    header[8..12].copy_from_slice(b"WAVE");
+
+    header[12..16].copy_from_slice(b"fmt ");
+    header[16..20].copy_from_slice(&16u32.to_le_bytes());
+    // PCM, one channel
+    header[20..22].copy_from_slice(&1u16.to_le_bytes());
+    header[22..24].copy_from_slice(&1u16.to_le_bytes());
+    header[24..28].copy_from_slice(&sample_rate.to_le_bytes());
+    // Byte rate, block align and bits per sample
+    header[28..32].copy_from_slice(&(sample_rate * 2).to_le_bytes());
+    header[32..34].copy_from_slice(&2u16.to_le_bytes());
+    header[34..36].copy_from_slice(&16u16.to_le_bytes());
+
+    header[36..40].copy_from_slice(b"data");
+    header[40..44].copy_from_slice(&data_len.to_le_bytes());
+    header
+}
+
+type FileWriter = WavWriter<BufWriter<File>>;
+
+fn create(path: &Path, sample_rate: u32) -> io::Result<FileWriter> {
+    WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)
+}
+
+// Records the mixed output to a WAV file, and optionally each APU channel on its own to files
+// next to it, e.g. capture.wav, capture-pulse1.wav, capture-noise.wav...
+pub struct WavRecorder {
+    path: PathBuf,
+    mixed: FileWriter,
+    channels: Vec<FileWriter>,
+}
+
+impl WavRecorder {
+    pub fn start(apu: &mut Apu, path: &Path, per_channel: bool) -> io::Result<Self> {
+        let sample_rate = match apu.audio() {
+            Some(pipeline) => pipeline.sample_rate(),
+            None => {
+                apu.set_sample_rate(DEFAULT_SAMPLE_RATE);
+                DEFAULT_SAMPLE_RATE
+            }
+        };
+
+        // Every file is made before the APU starts capturing, so failing leaves it as it was
+        let mixed = create(path, sample_rate)?;
+        let mut channels = Vec::new();
+        if per_channel {
+            for channel in Channel::ALL {
+                channels.push(create(&channel_path(path, channel), sample_rate)?);
+            }
+            apu.capture_channels(sample_rate);
+        }
+
+        Ok(WavRecorder { path: path.to_path_buf(), mixed, channels })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // `mixed` is the frame's output, which the caller has already taken from the APU
+    pub fn write_frame(&mut self, mixed: &[i16], apu: &mut Apu) -> io::Result<()> {
+        self.mixed.write_samples(mixed)?;
+        for (writer, pipeline) in self.channels.iter_mut().zip(apu.channel_audio()) {
+            writer.write_samples(pipeline.end_frame_i16())?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self, apu: &mut Apu) -> io::Result<()> {
+        apu.stop_channel_capture();
+        self.mixed.finish()?;
+        for writer in self.channels {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+fn channel_path(path: &Path, channel: Channel) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}-{}.wav", stem, channel.name()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_header_sizes_are_patched() {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 48_000).unwrap();
+        writer.write_samples(&[0, 1, -1]).unwrap();
+        writer.write_samples(&[i16::MAX]).unwrap();
+        let wav = writer.finish().unwrap().into_inner();
+
+        assert_eq!(wav.len(), 44 + 8);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()), 36 + 8);
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), 48_000);
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 8);
+        assert_eq!(&wav[48..50], &(-1i16).to_le_bytes());
+    }
+
+    #[test]
+    fn test_channel_path() {
+        let path = channel_path(Path::new("out/capture.wav"), Channel::Pulse1);
+        assert_eq!(path, Path::new("out/capture-pulse1.wav"));
+    }
+
+    #[test]
+    fn test_failed_start_leaves_capture_off() {
+        // A directory where the mixed file should go, the channel files can still be made
+        let dir = std::env::temp_dir().join(format!("nes-wav-test-{}", std::process::id()));
+        let path = dir.join("capture.wav");
+        std::fs::create_dir_all(&path).unwrap();
+
+        let mut apu = Apu::new();
+        assert!(WavRecorder::start(&mut apu, &path, true).is_err());
+        assert!(apu.channel_audio().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}